@@ -0,0 +1,829 @@
+use crate::models::measurements::{Distribution, HistogramBucket, MinMaxAvg, Percentile};
+use crate::models::{EventType, KafkaBrokerCfg, KafkaTimestampType, MessageEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Histogram values are exact up to 2^11 ms. Larger values keep that many significant bits
+const HISTOGRAM_SIGNIFICANT_BITS: u32 = 11;
+
+/// Percentiles of the value distributions
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// Timeline buckets kept at most. Buckets are twice as long once there are more of them
+const MAX_TIMELINE_BUCKETS: usize = 3600;
+
+/// Counts of the values. Memory depends on the value range only
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Histogram {
+    counts: BTreeMap<u64, u64>,
+}
+
+impl FromIterator<u64> for Histogram {
+    fn from_iter<T: IntoIterator<Item = u64>>(values: T) -> Self {
+        let mut histogram = Self::default();
+        for value in values {
+            histogram.record(value);
+        }
+        histogram
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, value: u64) {
+        let shift = (u64::BITS - value.leading_zeros()).saturating_sub(HISTOGRAM_SIGNIFICANT_BITS);
+        *self.counts.entry((value >> shift) << shift).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (value, count) in &other.counts {
+            *self.counts.entry(*value).or_default() += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Lowest recorded value such that at least `percentile` percent of the values are not
+    /// greater, rounded to the histogram precision
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        let rank = ((percentile / 100.0 * self.count() as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        self.counts.iter().find_map(|(value, count)| {
+            seen += count;
+            (seen >= rank).then_some(*value)
+        })
+    }
+
+    pub fn distribution(&self) -> Distribution {
+        Distribution {
+            count: self.count(),
+            min_max_avg: self.min_max_avg(),
+            percentiles: PERCENTILES
+                .iter()
+                .filter_map(|percentile| {
+                    Some(Percentile {
+                        percentile: *percentile,
+                        value: self.percentile(*percentile)? as u128,
+                    })
+                })
+                .collect(),
+            buckets: self
+                .counts
+                .iter()
+                .map(|(value, count)| HistogramBucket {
+                    value: *value as u128,
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn min_max_avg(&self) -> Option<MinMaxAvg> {
+        let count = self.count();
+        let sum: u128 = self
+            .counts
+            .iter()
+            .map(|(value, count)| *value as u128 * *count as u128)
+            .sum();
+
+        Some(MinMaxAvg {
+            min: *self.counts.keys().next()? as u128,
+            max: *self.counts.keys().next_back()? as u128,
+            avg: sum / count as u128,
+        })
+    }
+}
+
+/// Receipts of the route within a timeline bucket
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BucketStats {
+    pub received: u64,
+    pub duplicates: u64,
+    pub latency_sum_ms: u128,
+    pub latency_min_ms: u64,
+    pub latency_max_ms: u64,
+}
+
+impl BucketStats {
+    fn record(&mut self, latency_ms: u64, duplicate: bool) {
+        self.latency_min_ms = match self.received {
+            0 => latency_ms,
+            _ => self.latency_min_ms.min(latency_ms),
+        };
+        self.latency_max_ms = self.latency_max_ms.max(latency_ms);
+        self.latency_sum_ms += latency_ms as u128;
+        self.received += 1;
+        self.duplicates += duplicate as u64;
+    }
+
+    pub fn min_max_avg(&self) -> Option<MinMaxAvg> {
+        (self.received > 0).then(|| MinMaxAvg {
+            min: self.latency_min_ms as u128,
+            max: self.latency_max_ms as u128,
+            avg: self.latency_sum_ms / self.received as u128,
+        })
+    }
+
+    pub fn merge(&mut self, other: &BucketStats) {
+        if other.received == 0 {
+            return;
+        }
+
+        self.latency_min_ms = match self.received {
+            0 => other.latency_min_ms,
+            _ => self.latency_min_ms.min(other.latency_min_ms),
+        };
+        self.latency_max_ms = self.latency_max_ms.max(other.latency_max_ms);
+        self.latency_sum_ms += other.latency_sum_ms;
+        self.received += other.received;
+        self.duplicates += other.duplicates;
+    }
+}
+
+/// Receipts by the receive time. Buckets start one second long and double in length whenever
+/// there are too many of them, so long experiments take bounded memory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timeline {
+    bucket_secs: u64,
+    buckets: BTreeMap<u64, BucketStats>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            bucket_secs: 1,
+            buckets: Default::default(),
+        }
+    }
+}
+
+impl Timeline {
+    fn record(&mut self, received_millis: u64, latency_ms: u64, duplicate: bool) {
+        let second = received_millis / 1000;
+        self.buckets
+            .entry(second - second % self.bucket_secs)
+            .or_default()
+            .record(latency_ms, duplicate);
+        self.bound();
+    }
+
+    pub fn merge(&mut self, other: &Timeline) {
+        self.coarsen(self.bucket_secs.max(other.bucket_secs));
+
+        for (start, stats) in &other.buckets {
+            self.buckets
+                .entry(start - start % self.bucket_secs)
+                .or_default()
+                .merge(stats);
+        }
+        self.bound();
+    }
+
+    /// Buckets with their start (unix seconds) in the ascending order
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, &BucketStats)> {
+        self.buckets.iter().map(|(start, stats)| (*start, stats))
+    }
+
+    fn bound(&mut self) {
+        while self.buckets.len() > MAX_TIMELINE_BUCKETS {
+            self.coarsen(self.bucket_secs * 2);
+        }
+    }
+
+    fn coarsen(&mut self, bucket_secs: u64) {
+        if bucket_secs == self.bucket_secs {
+            return;
+        }
+
+        self.bucket_secs = bucket_secs;
+        for (start, stats) in std::mem::take(&mut self.buckets) {
+            self.buckets
+                .entry(start - start % bucket_secs)
+                .or_default()
+                .merge(&stats);
+        }
+    }
+}
+
+/// Messages sent to the source endpoint and received by the dest listener
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RouteStats {
+    /// Receipts, including the duplicated ones
+    pub received: u64,
+    pub duplicates: u64,
+    /// Send/receive latencies of the receipts
+    pub latencies: Histogram,
+    /// Receipts by the receive time
+    pub timeline: Timeline,
+}
+
+impl RouteStats {
+    pub fn merge(&mut self, other: &RouteStats) {
+        self.received += other.received;
+        self.duplicates += other.duplicates;
+        self.latencies.merge(&other.latencies);
+        self.timeline.merge(&other.timeline);
+    }
+}
+
+/// Kafka timestamps or receive times of the same message seen by two listeners
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KafkaRouteStats {
+    /// Differences of the dest and source times, if not negative
+    pub latencies: Histogram,
+    pub compared: u64,
+    /// Messages which timestamps are equal for both listeners
+    pub copied: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransactionReceipts {
+    pub committed: u64,
+    pub aborted: u64,
+}
+
+/// Endpoint the events are aggregated by. Listeners have the consumer group set and the
+/// kafka timestamps also the timestamp type
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EndpointKey {
+    pub brokers: String,
+    pub topic: String,
+    pub consumer_group: Option<String>,
+    pub timestamp_type: Option<KafkaTimestampType>,
+}
+
+/// Statistics of the aggregated experiment. Endpoints are referred to by their index
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AggregateStats {
+    pub endpoints: Vec<EndpointKey>,
+    /// Sent messages by the source endpoint
+    pub sent: HashMap<u16, u64>,
+    pub send_failed: u64,
+    /// Messages awaiting receipt by the source endpoint
+    pub pending: HashMap<u16, u64>,
+    /// Send/receive statistics by the source and dest endpoints
+    #[serde(with = "entries")]
+    pub routes: HashMap<(u16, u16), RouteStats>,
+    /// Kafka timestamps statistics by the source and dest endpoints with the timestamp type.
+    /// Endpoints without the timestamp type compare the first receive times of the listeners
+    #[serde(with = "entries")]
+    pub kafka: HashMap<(u16, u16), KafkaRouteStats>,
+    /// Messages that have left the pending window without being received by the experiment
+    /// listener, by the source endpoint, the listener brokers and the consumer group
+    #[serde(with = "entries")]
+    pub lost: HashMap<(u16, String, String), u64>,
+    /// Receipts of the messages which send is unknown, by the dest endpoint
+    pub unmatched: HashMap<u16, u64>,
+    pub committed: u64,
+    pub aborted: u64,
+    /// Received transactional messages by the dest endpoint
+    pub transactions_received: HashMap<u16, TransactionReceipts>,
+    /// Payload sizes of the sent messages in bytes
+    pub sizes: Histogram,
+}
+
+impl AggregateStats {
+    pub fn endpoint(&self, id: u16) -> &EndpointKey {
+        &self.endpoints[id as usize]
+    }
+}
+
+/// Events of the message within the pending window
+#[derive(Debug, Clone, Default)]
+struct PendingMessage {
+    sent: Option<(u16, u64)>,
+    transaction: Option<bool>,
+    received: Vec<(u16, u64)>,
+    timestamps: Vec<(u16, u64)>,
+}
+
+/// Online statistics of the experiment recorded instead of the raw events
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    pending_window: usize,
+    ids: HashMap<EndpointKey, u16>,
+    pending: HashMap<Uuid, PendingMessage>,
+    /// Pending messages in the order they have been seen first
+    order: VecDeque<Uuid>,
+    stats: AggregateStats,
+    /// Number of the recorded events and messages, so the changed statistics are persisted
+    version: u64,
+    /// Messages that have left the pending window since they have been taken last
+    evicted: Vec<Uuid>,
+}
+
+impl Aggregator {
+    pub fn new(pending_window: usize) -> Self {
+        Self {
+            pending_window: pending_window.max(1),
+            ids: Default::default(),
+            pending: Default::default(),
+            order: Default::default(),
+            stats: Default::default(),
+            version: 0,
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Continues from the persisted statistics. Messages that were awaiting receipt are
+    /// forgotten, so they are counted neither as received nor as lost
+    pub fn restore(&mut self, mut stats: AggregateStats) {
+        stats.pending.clear();
+        self.ids = stats.endpoints.iter().cloned().zip(0..=u16::MAX).collect();
+        self.pending.clear();
+        self.order.clear();
+        self.stats = stats;
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn is_pending(&self, message_uuid: &Uuid) -> bool {
+        self.pending.contains_key(message_uuid)
+    }
+
+    pub fn take_evicted(&mut self) -> Vec<Uuid> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    pub fn message_size(&mut self, bytes: u64) {
+        self.version += 1;
+        self.stats.sizes.record(bytes);
+    }
+
+    pub fn stats(&self) -> &AggregateStats {
+        &self.stats
+    }
+
+    /// Records the event of the experiment with the provided listeners. Messages leaving the
+    /// pending window are counted lost for every listener which has not received them
+    pub fn record(&mut self, event: MessageEvent, listeners: &[KafkaBrokerCfg]) {
+        self.version += 1;
+        let timestamp_millis = event.timestamp_millis as u64;
+        let (consumer_group, timestamp_type) = match &event.event_type {
+            EventType::KafkaTimestampSet {
+                consumer_group,
+                timestamp_type,
+            } => (Some(consumer_group), Some(*timestamp_type)),
+            EventType::Received { consumer_group } => (Some(consumer_group), None),
+            _ => (None, None),
+        };
+
+        let key = EndpointKey {
+            brokers: event.brokers,
+            topic: event.topic,
+            consumer_group: consumer_group.cloned(),
+            timestamp_type,
+        };
+
+        match event.event_type {
+            EventType::Sent => {
+                let source = self.id(key);
+                self.sent(event.message_uuid, source, timestamp_millis);
+            }
+            EventType::SendFailed { .. } => self.stats.send_failed += 1,
+            EventType::TransactionCommitted => self.transaction(event.message_uuid, true),
+            EventType::TransactionAborted => self.transaction(event.message_uuid, false),
+            EventType::KafkaTimestampSet { .. } => {
+                let endpoint = self.id(key);
+                self.kafka_timestamp(event.message_uuid, endpoint, timestamp_millis);
+            }
+            EventType::Received { .. } => {
+                let dest = self.id(key);
+                self.received(event.message_uuid, dest, timestamp_millis);
+            }
+            EventType::Processed { .. } => {}
+        }
+
+        while self.order.len() > self.pending_window {
+            let Some(evicted) = self.order.pop_front() else {
+                break;
+            };
+            self.evict(evicted, listeners);
+        }
+    }
+
+    fn id(&mut self, key: EndpointKey) -> u16 {
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+
+        // Experiments have only a few distinct endpoints. The ones past the limit share the
+        // last id, which endpoint has no brokers, so it is never measured
+        let id = self.stats.endpoints.len();
+        if id >= u16::MAX as usize {
+            if id == u16::MAX as usize {
+                tracing::warn!("Too many distinct endpoints in the aggregated experiment");
+                self.stats.endpoints.push(EndpointKey {
+                    brokers: String::new(),
+                    topic: String::new(),
+                    consumer_group: None,
+                    timestamp_type: None,
+                });
+            }
+            return u16::MAX;
+        }
+
+        let id = id as u16;
+        self.stats.endpoints.push(key.clone());
+        self.ids.insert(key, id);
+
+        id
+    }
+
+    fn message(&mut self, message_uuid: Uuid) -> &mut PendingMessage {
+        if !self.pending.contains_key(&message_uuid) {
+            self.order.push_back(message_uuid);
+        }
+
+        self.pending.entry(message_uuid).or_default()
+    }
+
+    fn evict(&mut self, message_uuid: Uuid, listeners: &[KafkaBrokerCfg]) {
+        let Some(message) = self.pending.remove(&message_uuid) else {
+            return;
+        };
+        self.evicted.push(message_uuid);
+
+        let Some((source, _)) = message.sent else {
+            for dest in distinct_endpoints(&message.received) {
+                *self.stats.unmatched.entry(dest).or_default() += 1;
+            }
+            return;
+        };
+
+        if let Some(pending) = self.stats.pending.get_mut(&source) {
+            *pending = pending.saturating_sub(1);
+        }
+
+        for listener in listeners {
+            let received = message.received.iter().any(|(dest, _)| {
+                let key = self.stats.endpoint(*dest);
+                key.brokers == listener.brokers
+                    && key.consumer_group.as_ref() == Some(&listener.consumer_group_id)
+            });
+
+            if !received {
+                let key = (
+                    source,
+                    listener.brokers.clone(),
+                    listener.consumer_group_id.clone(),
+                );
+                *self.stats.lost.entry(key).or_default() += 1;
+            }
+        }
+    }
+
+    fn sent(&mut self, message_uuid: Uuid, source: u16, timestamp_millis: u64) {
+        let message = self.message(message_uuid);
+        if message.sent.is_some() {
+            return;
+        }
+        message.sent = Some((source, timestamp_millis));
+        let received = message.received.clone();
+
+        *self.stats.sent.entry(source).or_default() += 1;
+        *self.stats.pending.entry(source).or_default() += 1;
+
+        // Messages may be received before the delivery is reported
+        for (idx, (dest, received_millis)) in received.iter().enumerate() {
+            let duplicate = received[..idx].iter().any(|(x, _)| x == dest);
+            self.route_receipt(source, *dest, timestamp_millis, *received_millis, duplicate);
+        }
+    }
+
+    fn received(&mut self, message_uuid: Uuid, dest: u16, timestamp_millis: u64) {
+        let message = self.message(message_uuid);
+        let duplicate = message.received.iter().any(|(x, _)| *x == dest);
+        let seen = match duplicate {
+            true => Vec::new(),
+            false => first_receipts(&message.received),
+        };
+        message.received.push((dest, timestamp_millis));
+        let (sent, transaction) = (message.sent, message.transaction);
+
+        // Receive times of the listeners are compared like their kafka timestamps
+        for (other, other_millis) in seen {
+            self.kafka_pair(other, dest, other_millis, timestamp_millis);
+            self.kafka_pair(dest, other, timestamp_millis, other_millis);
+        }
+
+        if let Some((source, sent_millis)) = sent {
+            self.route_receipt(source, dest, sent_millis, timestamp_millis, duplicate);
+        }
+
+        if let Some(committed) = transaction
+            && !duplicate
+        {
+            self.transaction_receipt(dest, committed);
+        }
+    }
+
+    fn route_receipt(
+        &mut self,
+        source: u16,
+        dest: u16,
+        sent_millis: u64,
+        received_millis: u64,
+        duplicate: bool,
+    ) {
+        let Some(latency_ms) = received_millis.checked_sub(sent_millis) else {
+            tracing::warn!("Destination timestamp is lower than source timestamp");
+            return;
+        };
+
+        let route = self.stats.routes.entry((source, dest)).or_default();
+        route.received += 1;
+        route.duplicates += duplicate as u64;
+        route.latencies.record(latency_ms);
+        route
+            .timeline
+            .record(received_millis, latency_ms, duplicate);
+    }
+
+    fn transaction(&mut self, message_uuid: Uuid, committed: bool) {
+        let message = self.message(message_uuid);
+        if message.transaction.is_some() {
+            return;
+        }
+        message.transaction = Some(committed);
+        let dests = distinct_endpoints(&message.received);
+
+        match committed {
+            true => self.stats.committed += 1,
+            false => self.stats.aborted += 1,
+        }
+
+        for dest in dests {
+            self.transaction_receipt(dest, committed);
+        }
+    }
+
+    fn transaction_receipt(&mut self, dest: u16, committed: bool) {
+        let receipts = self.stats.transactions_received.entry(dest).or_default();
+        match committed {
+            true => receipts.committed += 1,
+            false => receipts.aborted += 1,
+        }
+    }
+
+    fn kafka_timestamp(&mut self, message_uuid: Uuid, endpoint: u16, timestamp_millis: u64) {
+        let message = self.message(message_uuid);
+        let seen = message.timestamps.clone();
+        message.timestamps.push((endpoint, timestamp_millis));
+
+        // Either of the listeners may see the message first
+        for (other, other_millis) in seen {
+            self.kafka_pair(other, endpoint, other_millis, timestamp_millis);
+            self.kafka_pair(endpoint, other, timestamp_millis, other_millis);
+        }
+    }
+
+    fn kafka_pair(&mut self, source: u16, dest: u16, source_millis: u64, dest_millis: u64) {
+        let stats = self.stats.kafka.entry((source, dest)).or_default();
+        stats.compared += 1;
+        stats.copied += (source_millis == dest_millis) as u64;
+
+        if let Some(latency_ms) = dest_millis.checked_sub(source_millis) {
+            stats.latencies.record(latency_ms);
+        }
+    }
+}
+
+/// First receipt of every endpoint
+fn first_receipts(receipts: &[(u16, u64)]) -> Vec<(u16, u64)> {
+    let mut first: Vec<(u16, u64)> = Vec::new();
+    for (endpoint, millis) in receipts {
+        if !first.iter().any(|(x, _)| x == endpoint) {
+            first.push((*endpoint, *millis));
+        }
+    }
+    first
+}
+
+fn distinct_endpoints(endpoints: &[(u16, u64)]) -> Vec<u16> {
+    let mut distinct: Vec<u16> = endpoints.iter().map(|(x, _)| *x).collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    distinct
+}
+
+/// Maps with the tuple keys are (de)serialized as the lists of the entries, since JSON object
+/// keys have to be strings
+mod entries {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    pub fn serialize<K: Serialize, V: Serialize, S: Serializer>(
+        map: &HashMap<K, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BROKERS: &str = "localhost:9092";
+
+    fn listener(consumer_group: &str) -> KafkaBrokerCfg {
+        serde_json::from_value(serde_json::json!({
+            "brokers": BROKERS,
+            "topic": "topic",
+            "ssl": false,
+            "message_timeout": "1s",
+            "consumer_group_id": consumer_group,
+        }))
+        .unwrap()
+    }
+
+    fn event(message: u64, timestamp_millis: u128, event_type: EventType) -> MessageEvent {
+        MessageEvent {
+            message_uuid: Uuid::from_u64_pair(0, message),
+            timestamp_millis,
+            brokers: BROKERS.into(),
+            topic: "topic".into(),
+            event_type,
+        }
+    }
+
+    fn received(consumer_group: &str) -> EventType {
+        EventType::Received {
+            consumer_group: consumer_group.into(),
+        }
+    }
+
+    fn endpoint_id(stats: &AggregateStats, consumer_group: Option<&str>) -> u16 {
+        stats
+            .endpoints
+            .iter()
+            .position(|x| x.consumer_group.as_deref() == consumer_group)
+            .unwrap() as u16
+    }
+
+    #[test]
+    fn histogram_is_exact_for_small_values() {
+        let histogram: Histogram = [0, 1, 1, 2047].into_iter().collect();
+
+        assert_eq!(
+            histogram.counts.into_iter().collect::<Vec<_>>(),
+            vec![(0, 1), (1, 2), (2047, 1)]
+        );
+    }
+
+    #[test]
+    fn histogram_keeps_significant_bits_of_large_values() {
+        let histogram: Histogram = [2048, 2049, 4095, 1_000_000].into_iter().collect();
+
+        assert_eq!(
+            histogram.counts.into_iter().collect::<Vec<_>>(),
+            vec![(2048, 2), (4094, 1), (999_936, 1)]
+        );
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let histogram: Histogram = (1..=100).collect();
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0.0), Some(1));
+        assert_eq!(histogram.percentile(50.0), Some(50));
+        assert_eq!(histogram.percentile(99.9), Some(100));
+        assert_eq!(Histogram::default().percentile(50.0), None);
+
+        let distribution = histogram.distribution();
+        let min_max_avg = distribution.min_max_avg.unwrap();
+        assert_eq!(
+            (min_max_avg.min, min_max_avg.max, min_max_avg.avg),
+            (1, 100, 50)
+        );
+        assert_eq!(distribution.percentiles.len(), PERCENTILES.len());
+        assert_eq!(distribution.buckets.len(), 100);
+    }
+
+    #[test]
+    fn histogram_merge_adds_counts() {
+        let mut histogram: Histogram = [1, 2].into_iter().collect();
+        histogram.merge(&[2, 3].into_iter().collect());
+
+        assert_eq!(
+            histogram.counts.into_iter().collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2), (3, 1)]
+        );
+    }
+
+    #[test]
+    fn message_leaving_pending_window_is_lost_for_listeners_without_receipt() {
+        let listeners = [listener("received"), listener("missed")];
+        let mut aggregator = Aggregator::new(2);
+
+        aggregator.record(event(0, 100, EventType::Sent), &listeners);
+        aggregator.record(event(0, 150, received("received")), &listeners);
+        aggregator.record(event(1, 200, EventType::Sent), &listeners);
+        assert!(aggregator.take_evicted().is_empty());
+
+        aggregator.record(event(2, 300, EventType::Sent), &listeners);
+
+        assert!(!aggregator.is_pending(&Uuid::from_u64_pair(0, 0)));
+        assert!(aggregator.is_pending(&Uuid::from_u64_pair(0, 2)));
+        assert_eq!(aggregator.take_evicted(), vec![Uuid::from_u64_pair(0, 0)]);
+
+        let stats = aggregator.stats();
+        let source = endpoint_id(stats, None);
+        assert_eq!(stats.sent[&source], 3);
+        assert_eq!(stats.pending[&source], 2);
+        assert_eq!(
+            stats.lost,
+            HashMap::from([((source, BROKERS.to_string(), "missed".to_string()), 1)])
+        );
+    }
+
+    #[test]
+    fn duplicates_are_counted_per_listener() {
+        let listeners = [listener("group")];
+        let mut aggregator = Aggregator::new(10);
+
+        aggregator.record(event(0, 100, EventType::Sent), &listeners);
+        aggregator.record(event(0, 110, received("group")), &listeners);
+        aggregator.record(event(0, 130, received("group")), &listeners);
+
+        let stats = aggregator.stats();
+        let route = &stats.routes[&(endpoint_id(stats, None), endpoint_id(stats, Some("group")))];
+        assert_eq!(route.received, 2);
+        assert_eq!(route.duplicates, 1);
+        assert_eq!(route.latencies.count(), 2);
+        assert_eq!(route.latencies.percentile(100.0), Some(30));
+    }
+
+    #[test]
+    fn receipt_before_delivery_report_is_matched() {
+        let listeners = [listener("group")];
+        let mut aggregator = Aggregator::new(10);
+
+        aggregator.record(event(0, 120, received("group")), &listeners);
+        aggregator.record(event(0, 100, EventType::Sent), &listeners);
+
+        let stats = aggregator.stats();
+        let route = &stats.routes[&(endpoint_id(stats, None), endpoint_id(stats, Some("group")))];
+        assert_eq!(route.received, 1);
+        assert_eq!(route.duplicates, 0);
+        assert_eq!(route.latencies.percentile(50.0), Some(20));
+    }
+
+    #[test]
+    fn receipt_without_send_is_unmatched_once_evicted() {
+        let listeners = [listener("group")];
+        let mut aggregator = Aggregator::new(1);
+
+        aggregator.record(event(0, 100, received("group")), &listeners);
+        aggregator.record(event(1, 200, EventType::Sent), &listeners);
+
+        let stats = aggregator.stats();
+        assert_eq!(stats.unmatched[&endpoint_id(stats, Some("group"))], 1);
+        assert!(stats.lost.is_empty());
+    }
+
+    #[test]
+    fn endpoints_past_the_limit_share_the_unmeasured_id() {
+        let mut aggregator = Aggregator::new(1);
+        let key = |topic: usize| EndpointKey {
+            brokers: BROKERS.into(),
+            topic: topic.to_string(),
+            consumer_group: None,
+            timestamp_type: None,
+        };
+
+        for topic in 0..u16::MAX as usize {
+            assert_eq!(aggregator.id(key(topic)) as usize, topic);
+        }
+        assert_eq!(aggregator.id(key(0)), 0);
+        assert_eq!(aggregator.id(key(70_000)), u16::MAX);
+        assert_eq!(aggregator.id(key(70_001)), u16::MAX);
+
+        let stats = aggregator.stats().clone();
+        assert_eq!(stats.endpoints.len(), u16::MAX as usize + 1);
+        assert!(stats.endpoint(u16::MAX).brokers.is_empty());
+
+        aggregator.restore(stats);
+        assert_eq!(aggregator.id(key(1)), 1);
+        assert_eq!(aggregator.id(key(70_002)), u16::MAX);
+    }
+}
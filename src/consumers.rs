@@ -1,73 +1,812 @@
-use crate::models::KafkaBrokerCfg;
-use crate::models::{EventType, Message, MessageEvent};
-use crate::state::MessagesState;
-use crate::{EXPERIMENT_UUID_HEADER, MESSAGE_UUID_HEADER, get_now_millis};
-use rdkafka::consumer::Consumer as _;
-use rdkafka::message::{Headers, Message as _};
-use std::collections::HashMap;
+use crate::correlation::Correlator;
+use crate::get_now_millis;
+use crate::models::{
+    AssignedPartition, ChurnAction, ChurnEvent, ChurnType, CommitStrategy, EventType,
+    KafkaTimestampType, ListenerLag, ListenerState, ListenerStatus, Message, MessageEvent,
+    PartitionLag, PartitionOffset, ProcessingDelay, RebalanceEvent, RebalanceType,
+};
+use crate::models::{CorrelationHeaders, KafkaBrokerCfg};
+use crate::state::{ExperimentError, MessagesState, Record};
+use rdkafka::client::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use rdkafka::consumer::{
+    BaseConsumer, CommitMode, Consumer as _, ConsumerContext, Rebalance, RebalanceProtocol,
+};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::{BorrowedMessage, Message as _, Timestamp};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+use rdkafka::types::RDKafkaRespErr;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tracing::warn;
 use uuid::Uuid;
 
+/// Listeners with the same key share one kafka consumer. Otherwise they would split partitions
+/// of the topic between each other
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListenerKey {
+    brokers: String,
+    topic: String,
+    topic_pattern: bool,
+    consumer_group_id: String,
+    ssl: bool,
+}
+
+impl ListenerKey {
+    /// Consumer group is the configured one, except for the ephemeral restores, which are never
+    /// shared
+    fn new(cfg: &KafkaBrokerCfg, consumer_group_id: &str) -> Self {
+        Self {
+            brokers: cfg.brokers.clone(),
+            topic: cfg.topic.clone(),
+            topic_pattern: cfg.topic_pattern,
+            consumer_group_id: consumer_group_id.into(),
+            ssl: cfg.ssl,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct LoopHandler {
-    handles: HashMap<Uuid, Vec<tokio::task::AbortHandle>>,
+    /// Experiment uuid to its listeners
+    handles: HashMap<Uuid, Vec<ListenerHandle>>,
+    /// Running listeners available for sharing
+    shared: HashMap<ListenerKey, ListenerHandle>,
 }
 
 impl LoopHandler {
     pub fn new() -> Self {
         Self {
             handles: Default::default(),
+            shared: Default::default(),
         }
     }
 
-    pub fn register_experiment(
-        &mut self,
-        uuid: Uuid,
-        handles: Vec<tokio::task::AbortHandle>,
-    ) -> &mut Self {
-        self.handles.insert(uuid, handles);
+    pub fn register_listener(&mut self, uuid: Uuid, handle: ListenerHandle) -> &mut Self {
+        let handles = self.handles.entry(uuid).or_default();
+        if handles
+            .iter()
+            .any(|x| Arc::ptr_eq(&x.status, &handle.status))
+        {
+            return self;
+        }
+
+        handle.status.lock().experiments.insert(uuid);
+
+        self.shared
+            .entry(handle.key.clone())
+            .or_insert(handle.clone());
+
+        handles.push(handle);
         self
     }
 
     pub fn deregister_experiment(&mut self, uuid: &Uuid) -> &mut Self {
         if let Some(handles) = self.handles.remove(uuid) {
             for handle in handles {
-                handle.abort();
+                self.release(uuid, &handle);
             }
         }
 
         self
     }
+
+    /// Detaches the first experiment listener matching the predicate
+    pub fn deregister_listener(
+        &mut self,
+        uuid: &Uuid,
+        predicate: impl Fn(&KafkaBrokerCfg) -> bool,
+    ) -> Option<ListenerHandle> {
+        let handles = self.handles.get_mut(uuid)?;
+        let idx = handles
+            .iter()
+            .position(|handle| predicate(&handle.status.lock().listener))?;
+
+        let handle = handles.remove(idx);
+        self.release(uuid, &handle);
+
+        Some(handle)
+    }
+
+    /// Stops routing messages to the experiment. The consumer is stopped once it has no
+    /// experiments left
+    fn release(&mut self, uuid: &Uuid, handle: &ListenerHandle) {
+        let unused = {
+            let mut status = handle.status.lock();
+            status.experiments.remove(uuid);
+            status.experiments.is_empty()
+        };
+
+        if unused {
+            handle.task.abort();
+
+            if self
+                .shared
+                .get(&handle.key)
+                .is_some_and(|shared| Arc::ptr_eq(&shared.status, &handle.status))
+            {
+                self.shared.remove(&handle.key);
+            }
+        }
+    }
+}
+
+/// Status of the experiment listener shared with its consumer loop
+pub type ListenerStatusHandle = Arc<parking_lot::Mutex<ListenerStatus>>;
+
+/// Timeout of the kafka queries resolving the restore timestamp to the partition offsets
+pub const RESTORE_QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Offsets the restored listener starts consuming from
+#[derive(Debug, Clone)]
+pub enum RestorePosition {
+    /// Relies on `auto.offset.reset=earliest`. Committed offsets of the group take precedence
+    Earliest,
+    /// Offsets by the topic and partition. Partitions not listed start like with `Earliest`
+    Offsets(HashMap<(String, i32), Offset>),
+}
+
+impl RestorePosition {
+    pub fn offsets(offsets: &[PartitionOffset]) -> Self {
+        Self::Offsets(
+            offsets
+                .iter()
+                .map(|x| ((x.topic.clone(), x.partition), Offset::Offset(x.offset)))
+                .collect(),
+        )
+    }
+
+    /// Offsets of the first messages with the kafka timestamp (unix millis) equal or greater
+    /// than the provided one in the partitions of the listener topics. Resolved before the
+    /// listener is created, so the rebalance callback never waits for kafka
+    pub async fn timestamp(
+        cfg: &KafkaBrokerCfg,
+        timestamp_millis: i64,
+    ) -> Result<Self, ExperimentError> {
+        let subscription = subscription(cfg)?;
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", cfg.brokers.clone());
+        if cfg.ssl {
+            config.set("security.protocol", "ssl");
+            config.set("enable.ssl.certificate.verification", "false");
+        }
+
+        let offsets = tokio::task::spawn_blocking(move || {
+            offsets_for_timestamp(&config, &subscription, timestamp_millis)
+        })
+        .await
+        .unwrap_or(Err(KafkaError::Canceled))?;
+
+        tracing::info!("Restoring partitions from {:?}", offsets);
+        Ok(Self::Offsets(offsets))
+    }
+}
+
+/// Partitions without the message at or after the timestamp start from their end
+fn offsets_for_timestamp(
+    config: &ClientConfig,
+    subscription: &str,
+    timestamp_millis: i64,
+) -> KafkaResult<HashMap<(String, i32), Offset>> {
+    let consumer: BaseConsumer = config.create()?;
+
+    let pattern = match subscription.starts_with('^') {
+        true => Some(
+            regex::Regex::new(subscription)
+                .map_err(|e| KafkaError::ClientCreation(format!("Invalid topic pattern: {e}")))?,
+        ),
+        false => None,
+    };
+    let metadata = consumer.fetch_metadata(
+        pattern.is_none().then_some(subscription),
+        RESTORE_QUERY_TIMEOUT,
+    )?;
+
+    let mut timestamps = TopicPartitionList::new();
+    for topic in metadata.topics() {
+        let matches = match &pattern {
+            Some(pattern) => pattern.is_match(topic.name()),
+            None => topic.name() == subscription,
+        };
+
+        if matches {
+            for partition in topic.partitions() {
+                timestamps.add_partition_offset(
+                    topic.name(),
+                    partition.id(),
+                    Offset::Offset(timestamp_millis),
+                )?;
+            }
+        }
+    }
+
+    Ok(consumer
+        .offsets_for_times(timestamps, RESTORE_QUERY_TIMEOUT)?
+        .elements()
+        .iter()
+        .map(|x| ((x.topic().to_string(), x.partition()), x.offset()))
+        .collect())
+}
+
+/// Options of the listener started for the restored experiment
+#[derive(Debug, Clone)]
+pub struct ListenerRestore {
+    /// Restored experiment. Its own running listeners are stopped before the restored ones start
+    pub experiment_uuid: Uuid,
+    pub position: RestorePosition,
+    /// Consume with a fresh consumer group, so the committed offsets are not used
+    pub ephemeral_group: bool,
+}
+
+/// Partitions of the listener that have been already moved to the restore position
+type RestoredPartitions = Arc<parking_lot::Mutex<HashSet<(String, i32)>>>;
+
+/// Consumer context updating the listener status on rebalances and client errors
+pub struct ListenerContext {
+    status: ListenerStatusHandle,
+    /// Consumer instance of the listener
+    instance: usize,
+    /// Rebalances are recorded into the experiments by the consumer loop
+    rebalances: UnboundedSender<RebalanceEvent>,
+    restore: Option<RestorePosition>,
+    /// Shared by the instances, so partitions moving between them are restored only once
+    restored: RestoredPartitions,
+}
+
+impl ListenerContext {
+    /// Sets the restore offsets of the newly assigned partitions, so they are assigned at these
+    /// offsets. Every partition is restored only once, so later rebalances do not rewind the
+    /// listener
+    fn restore_offsets(&self, partitions: &mut TopicPartitionList) {
+        let Some(RestorePosition::Offsets(offsets)) = &self.restore else {
+            return;
+        };
+
+        let mut restored = self.restored.lock();
+
+        for ((topic, partition), offset) in offsets {
+            if partitions.find_partition(topic, *partition).is_none()
+                || !restored.insert((topic.clone(), *partition))
+            {
+                continue;
+            }
+
+            if let Err(e) = partitions.set_partition_offset(topic, *partition, *offset) {
+                warn!("Could not restore {} [{}]: {}", topic, partition, e);
+            }
+        }
+    }
+}
+
+impl ClientContext for ListenerContext {
+    fn error(&self, error: KafkaError, reason: &str) {
+        warn!("Listener error: {}: {}", error, reason);
+        self.status.lock().set_error(format!("{error}: {reason}"));
+    }
+}
+
+impl ConsumerContext for ListenerContext {
+    /// Same as the default rebalance, except that the restored partitions are assigned at
+    /// their restore offsets
+    fn rebalance(
+        &self,
+        base_consumer: &BaseConsumer<Self>,
+        err: RDKafkaRespErr,
+        tpl: &mut TopicPartitionList,
+    ) {
+        if err == RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS {
+            self.restore_offsets(tpl);
+        }
+
+        let cooperative = matches!(
+            base_consumer.rebalance_protocol(),
+            RebalanceProtocol::Cooperative
+        );
+        let (rebalance, result) = match err {
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS => (
+                Rebalance::Assign(tpl),
+                match cooperative {
+                    true => base_consumer.incremental_assign(tpl),
+                    false => base_consumer.assign(tpl),
+                },
+            ),
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__REVOKE_PARTITIONS => (
+                Rebalance::Revoke(tpl),
+                match cooperative {
+                    true => base_consumer.incremental_unassign(tpl),
+                    false => base_consumer.unassign(),
+                },
+            ),
+            _ => {
+                let error = KafkaError::Rebalance(err.into());
+                // Partitions are unassigned like by the default rebalance
+                let result = match cooperative {
+                    true => base_consumer.incremental_unassign(tpl),
+                    false => base_consumer.unassign(),
+                };
+                (Rebalance::Error(error), result)
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("Rebalance failed: {}", e);
+            self.status.lock().set_error(e.to_string());
+        }
+
+        self.post_rebalance(base_consumer, &rebalance);
+    }
+
+    fn post_rebalance(&self, _base_consumer: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        let mut status = self.status.lock();
+
+        let (rebalance_type, partitions) = match rebalance {
+            Rebalance::Assign(partitions) => {
+                let partitions = assigned_partitions(partitions);
+                status.instance_assigned(self.instance, partitions.clone());
+                status.state = ListenerState::Assigned;
+                (RebalanceType::Assigned, partitions)
+            }
+            Rebalance::Revoke(partitions) => {
+                status.instance_assigned(self.instance, Vec::new());
+                (RebalanceType::Revoked, assigned_partitions(partitions))
+            }
+            Rebalance::Error(e) => {
+                status.set_error(e.to_string());
+                (
+                    RebalanceType::Failed {
+                        reason: e.to_string(),
+                    },
+                    Vec::new(),
+                )
+            }
+        };
+
+        // Fails only if the consumer loop has been stopped
+        let _ = self.rebalances.send(RebalanceEvent {
+            timestamp_millis: get_now_millis(),
+            brokers: status.listener.brokers.clone(),
+            topic: status.listener.topic.clone(),
+            consumer_group: status.listener.consumer_group_id.clone(),
+            instance: self.instance,
+            rebalance_type,
+            partitions,
+        });
+    }
+
+    fn commit_callback(&self, result: KafkaResult<()>, offsets: &TopicPartitionList) {
+        let mut status = self.status.lock();
+
+        match result {
+            Ok(_) => status.offsets_committed(offsets.elements().iter().filter_map(|x| {
+                offset_value(x.offset()).map(|offset| PartitionOffset {
+                    topic: x.topic().into(),
+                    partition: x.partition(),
+                    offset,
+                })
+            })),
+            // Nothing has been consumed since the last commit
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {}
+            Err(e) => status.set_error(format!("Commit failed: {e}")),
+        }
+    }
+}
+
+fn assigned_partitions(partitions: &TopicPartitionList) -> Vec<AssignedPartition> {
+    partitions
+        .elements()
+        .iter()
+        .map(|x| AssignedPartition {
+            topic: x.topic().into(),
+            partition: x.partition(),
+        })
+        .collect()
+}
+
+/// Timeout of the kafka queries made while calculating the listener lag
+pub const LAG_QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Parts of the listener shared by its consumer instances
+struct ListenerShared {
+    cfg: KafkaBrokerCfg,
+    status: ListenerStatusHandle,
+    correlator: Correlator,
+    config: ClientConfig,
+    subscription: String,
+    restore: Option<RestorePosition>,
+    restored: RestoredPartitions,
+}
+
+/// Kafka consumer of the listener with the receiver of its rebalances
+struct ListenerInstance {
+    index: usize,
+    consumer: Arc<StreamConsumer<ListenerContext>>,
+    rebalances: UnboundedReceiver<RebalanceEvent>,
+}
+
+impl ListenerShared {
+    /// Creates and subscribes a new consumer in the listener group
+    fn instance(&self, index: usize) -> KafkaResult<ListenerInstance> {
+        let (rebalances_sender, rebalances) = tokio::sync::mpsc::unbounded_channel();
+
+        let consumer: Arc<StreamConsumer<ListenerContext>> =
+            Arc::new(self.config.create_with_context(ListenerContext {
+                status: self.status.clone(),
+                instance: index,
+                rebalances: rebalances_sender,
+                restore: self.restore.clone(),
+                restored: self.restored.clone(),
+            })?);
+
+        consumer.subscribe(&[&self.subscription])?;
+
+        Ok(ListenerInstance {
+            index,
+            consumer,
+            rebalances,
+        })
+    }
+}
+
+/// Kafka consumers created and subscribed for the experiment listener
+pub struct Listener {
+    shared: Arc<ListenerShared>,
+    instances: Vec<ListenerInstance>,
+    key: ListenerKey,
+    headers: CorrelationHeaders,
+}
+
+/// Running consumers of the listener by the instance, used for the lag queries
+pub type ListenerConsumers =
+    Arc<parking_lot::Mutex<BTreeMap<usize, Arc<StreamConsumer<ListenerContext>>>>>;
+
+/// Listener to start for the experiment
+pub enum ListenerAttachment {
+    /// Already running listener with the same key
+    Shared(ListenerHandle),
+    New(Listener),
+}
+
+/// Handle to the running experiment listener
+#[derive(Clone)]
+pub struct ListenerHandle {
+    pub status: ListenerStatusHandle,
+    consumers: ListenerConsumers,
+    task: tokio::task::AbortHandle,
+    key: ListenerKey,
+    /// Experiment headers the listener correlates the messages with
+    headers: CorrelationHeaders,
+}
+
+impl std::fmt::Debug for ListenerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ListenerHandle")
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+fn offset_value(offset: Offset) -> Option<i64> {
+    match offset {
+        Offset::Offset(x) => Some(x),
+        _ => None,
+    }
+}
+
+impl ListenerHandle {
+    /// Listener can be shared only by the experiments with the same config. Listeners with churn
+    /// are never shared
+    fn accepts(&self, cfg: &KafkaBrokerCfg, headers: &CorrelationHeaders) -> bool {
+        let listener = &self.status.lock().listener;
+
+        listener.churn.is_none()
+            && cfg.churn.is_none()
+            && *listener == *cfg
+            && self.headers.message_uuid == headers.message_uuid
+            && self.headers.experiment_uuid == headers.experiment_uuid
+    }
+
+    /// Positions, committed offsets and watermarks of the assigned partitions. Blocks for the
+    /// time of kafka queries
+    pub fn lag(&self, timeout: std::time::Duration) -> ListenerLag {
+        let listener = self.status.lock().listener.clone();
+
+        match self.partitions_lag(timeout) {
+            Ok(partitions) => ListenerLag {
+                listener,
+                total_lag: partitions.iter().filter_map(|x| x.lag).sum(),
+                partitions,
+                error: None,
+            },
+            Err(e) => ListenerLag {
+                listener,
+                partitions: Vec::new(),
+                total_lag: 0,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+
+    fn partitions_lag(&self, timeout: std::time::Duration) -> KafkaResult<Vec<PartitionLag>> {
+        let consumers: Vec<_> = self.consumers.lock().values().cloned().collect();
+
+        let mut partitions = Vec::new();
+        for consumer in consumers {
+            partitions.extend(consumer_partitions_lag(&consumer, timeout)?);
+        }
+
+        Ok(partitions)
+    }
+}
+
+fn consumer_partitions_lag(
+    consumer: &StreamConsumer<ListenerContext>,
+    timeout: std::time::Duration,
+) -> KafkaResult<Vec<PartitionLag>> {
+    let assignment = consumer.assignment()?;
+    let positions = consumer.position()?;
+    let committed = consumer.committed_offsets(assignment.clone(), timeout)?;
+
+    assignment
+        .elements()
+        .iter()
+        .map(|element| {
+            let (topic, partition) = (element.topic(), element.partition());
+            let (low_watermark, high_watermark) =
+                consumer.fetch_watermarks(topic, partition, timeout)?;
+
+            let position = positions
+                .find_partition(topic, partition)
+                .and_then(|x| offset_value(x.offset()));
+            let committed_offset = committed
+                .find_partition(topic, partition)
+                .and_then(|x| offset_value(x.offset()));
+
+            Ok(PartitionLag {
+                topic: topic.into(),
+                partition,
+                committed_offset,
+                position,
+                low_watermark,
+                high_watermark,
+                lag: position
+                    .or(committed_offset)
+                    .map(|offset| (high_watermark - offset).max(0)),
+            })
+        })
+        .collect()
+}
+
+impl Listener {
+    pub fn new(
+        cfg: KafkaBrokerCfg,
+        restore: Option<&ListenerRestore>,
+        headers: &CorrelationHeaders,
+    ) -> Result<Self, ExperimentError> {
+        let correlator = Correlator::new(&cfg.correlation, headers)?;
+        let mut config = ClientConfig::new();
+
+        let restored_ephemeral = restore.is_some_and(|x| x.ephemeral_group);
+        // Listeners never committing join a group of their own, so they take no partitions
+        // from the other consumers of the configured group. Unlike the ephemeral restores, they
+        // are still shared under the configured group
+        let group_id = if restored_ephemeral || cfg.commit == CommitStrategy::None {
+            format!("{}-{}", cfg.consumer_group_id, Uuid::new_v4())
+        } else {
+            cfg.consumer_group_id.clone()
+        };
+        let key = if restored_ephemeral {
+            ListenerKey::new(&cfg, &group_id)
+        } else {
+            ListenerKey::new(&cfg, &cfg.consumer_group_id)
+        };
+
+        config
+            .set("group.id", group_id.clone())
+            .set("bootstrap.servers", cfg.brokers.clone())
+            .set(
+                "max.poll.interval.ms",
+                (cfg.message_timeout.0.as_millis() * 2).to_string(),
+            )
+            .set(
+                "session.timeout.ms",
+                cfg.message_timeout.0.as_millis().to_string(),
+            );
+
+        match &cfg.commit {
+            CommitStrategy::None | CommitStrategy::Manual { .. } => {
+                config.set("enable.auto.commit", "false");
+            }
+            CommitStrategy::Auto { interval } => {
+                config.set("enable.auto.commit", "true").set(
+                    "auto.commit.interval.ms",
+                    interval.0.as_millis().to_string(),
+                );
+            }
+        }
+
+        if cfg.ssl {
+            config.set("security.protocol", "ssl");
+            config.set("enable.ssl.certificate.verification", "false");
+        }
+
+        if let Some(isolation_level) = cfg.isolation_level {
+            config.set("isolation.level", isolation_level.as_kafka_config());
+        }
+
+        if let Some(restore) = restore {
+            config.set("auto.offset.reset", "earliest");
+            tracing::info!(
+                "Consuming messages from {:?} with group {}",
+                restore.position,
+                group_id
+            );
+        }
+
+        let status = Arc::new(parking_lot::Mutex::new(ListenerStatus::new(
+            cfg.clone(),
+            group_id,
+        )));
+
+        let shared = Arc::new(ListenerShared {
+            subscription: subscription(&cfg)?,
+            cfg,
+            status,
+            correlator,
+            config,
+            restore: restore.map(|x| x.position.clone()),
+            restored: Default::default(),
+        });
+
+        let instances = (0..shared.cfg.parallelism.max(1))
+            .map(|index| shared.instance(index))
+            .collect::<KafkaResult<_>>()?;
+        shared.status.lock().state = ListenerState::Subscribed;
+
+        Ok(Self {
+            key,
+            headers: headers.clone(),
+            shared,
+            instances,
+        })
+    }
+}
+
+/// Topic the listener subscribes to. Kafka client treats topics starting with `^` as patterns
+fn subscription(cfg: &KafkaBrokerCfg) -> Result<String, ExperimentError> {
+    if !cfg.topic_pattern {
+        return Ok(cfg.topic.clone());
+    }
+
+    regex::Regex::new(&cfg.topic).map_err(ExperimentError::InvalidTopicPattern)?;
+    posix_pattern(&cfg.topic)?;
+
+    Ok(match cfg.topic.starts_with('^') {
+        true => cfg.topic.clone(),
+        false => format!("^{}", cfg.topic),
+    })
+}
+
+/// Kafka client matches the patterns with POSIX regex. Perl classes (e.g. `\d`) and groups with
+/// flags (e.g. `(?i)`) would pass the validation and then never match
+fn posix_pattern(pattern: &str) -> Result<(), ExperimentError> {
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        let unsupported = match c {
+            '\\' => chars.next().filter(|x| x.is_ascii_alphanumeric()),
+            '(' if chars.as_str().starts_with('?') => Some('?'),
+            _ => None,
+        };
+
+        if let Some(unsupported) = unsupported {
+            return Err(ExperimentError::UnsupportedTopicPattern(format!(
+                "{c}{unsupported}"
+            )));
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct Consumers {
     loops: LoopHandler,
-    state_container: Arc<Mutex<MessagesState>>,
+    state_container: MessagesState,
 }
 
 impl Consumers {
-    pub fn new(events_container: Arc<Mutex<MessagesState>>) -> Self {
+    pub fn new(events_container: MessagesState) -> Self {
         Self {
             loops: Default::default(),
             state_container: events_container,
         }
     }
 
-    pub async fn start(
-        &mut self,
-        uuid: Uuid,
-        cfg: KafkaBrokerCfg,
-        offset_reset: bool,
-    ) -> &mut Self {
+    /// Returns running listener with the same brokers, topic, consumer group and security or
+    /// creates a new one. Running listener configured differently is not shared, as a second
+    /// consumer would split the partitions with it. Restored listener starts from its own
+    /// position, so it conflicts with the running listener of its group
+    pub fn attachment(
+        &self,
+        cfg: &KafkaBrokerCfg,
+        restore: Option<&ListenerRestore>,
+        headers: &CorrelationHeaders,
+    ) -> Result<ListenerAttachment, ExperimentError> {
+        if let Some(churn) = &cfg.churn {
+            churn.validate().map_err(ExperimentError::InvalidChurn)?;
+        }
+
+        let ephemeral = restore.is_some_and(|x| x.ephemeral_group);
+        if !ephemeral
+            && let Some(handle) = self
+                .loops
+                .shared
+                .get(&ListenerKey::new(cfg, &cfg.consumer_group_id))
+        {
+            match restore {
+                // Listener used only by the restored experiment is stopped before the restore
+                Some(restore) => {
+                    let experiments = &handle.status.lock().experiments;
+                    if experiments.iter().any(|x| *x != restore.experiment_uuid) {
+                        return Err(ExperimentError::ListenerConfigConflict);
+                    }
+                }
+                None => {
+                    if !handle.accepts(cfg, headers) {
+                        return Err(ExperimentError::ListenerConfigConflict);
+                    }
+
+                    tracing::info!("Sharing listener for {} with the experiment", cfg.topic);
+                    return Ok(ListenerAttachment::Shared(handle.clone()));
+                }
+            }
+        }
+
+        let listener = Listener::new(cfg.clone(), restore, headers)?;
+        Ok(ListenerAttachment::New(listener))
+    }
+
+    pub async fn start(&mut self, uuid: Uuid, attachment: ListenerAttachment) -> &mut Self {
+        let listener = match attachment {
+            ListenerAttachment::Shared(handle) => {
+                self.loops.register_listener(uuid, handle);
+                return self;
+            }
+            // Listener with the same key may have been started in the meantime
+            ListenerAttachment::New(listener) => match self.loops.shared.get(&listener.key) {
+                Some(handle) => {
+                    let handle = handle.clone();
+                    self.loops.register_listener(uuid, handle);
+                    return self;
+                }
+                None => listener,
+            },
+        };
+
         let events = self.state_container.clone();
-        let handle = tokio::spawn(async move { consumer_loop(cfg, events, offset_reset).await })
-            .abort_handle();
+        let status = listener.shared.status.clone();
+        let consumers = ListenerConsumers::default();
+        let key = listener.key.clone();
+        let headers = listener.headers.clone();
 
-        let vec_of_handles = self.loops.handles.entry(uuid).or_default();
-        vec_of_handles.push(handle);
+        let task = {
+            let consumers = consumers.clone();
+            tokio::spawn(async move { listener_loop(listener, events, consumers).await })
+                .abort_handle()
+        };
+
+        self.loops.register_listener(
+            uuid,
+            ListenerHandle {
+                status,
+                consumers,
+                task,
+                key,
+                headers,
+            },
+        );
 
         self
     }
@@ -76,133 +815,784 @@ impl Consumers {
         self.loops.deregister_experiment(uuid);
         self
     }
+
+    /// Detaches a single experiment listener. Returns its last status
+    pub fn stop_listener(
+        &mut self,
+        uuid: &Uuid,
+        brokers: &str,
+        topic: &str,
+        consumer_group_id: &str,
+    ) -> Option<ListenerStatus> {
+        self.loops
+            .deregister_listener(uuid, |cfg| {
+                cfg.brokers == brokers
+                    && cfg.topic == topic
+                    && cfg.consumer_group_id == consumer_group_id
+            })
+            .map(|handle| handle.status.lock().clone())
+    }
+
+    /// Current statuses of the experiment listeners
+    pub fn statuses(&self, uuid: &Uuid) -> Vec<ListenerStatus> {
+        self.listeners(uuid)
+            .iter()
+            .map(|x| x.status.lock().clone())
+            .collect()
+    }
+
+    pub fn listeners(&self, uuid: &Uuid) -> Vec<ListenerHandle> {
+        self.loops.handles.get(uuid).cloned().unwrap_or_default()
+    }
 }
 
-pub async fn consumer_loop(
-    cfg: KafkaBrokerCfg,
-    state: Arc<Mutex<MessagesState>>,
-    offset_reset: bool,
+/// Completes on the commit interval tick. Never completes for listeners not committing manually
+async fn commit_tick(commit_interval: &mut Option<tokio::time::Interval>) {
+    match commit_interval {
+        Some(commit_interval) => {
+            commit_interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Offsets committed by the manual strategy. Message is finished once its events are queued for
+/// recording and its processing is over
+#[derive(Debug, Default)]
+struct FinishedOffsets(HashMap<(String, i32), i64>);
+
+impl FinishedOffsets {
+    fn finish(&mut self, topic: &str, partition: i32, offset: i64) {
+        // Committed offset is the one of the next message to consume
+        let next = self.0.entry((topic.into(), partition)).or_default();
+        *next = (*next).max(offset + 1);
+    }
+
+    /// Offsets finished since the previous call
+    fn take(&mut self) -> Option<TopicPartitionList> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let mut offsets = TopicPartitionList::new();
+        for ((topic, partition), next) in self.0.drain() {
+            if let Err(e) = offsets.add_partition_offset(&topic, partition, Offset::Offset(next)) {
+                tracing::debug!("Could not commit offset of {}/{}: {}", topic, partition, e);
+            }
+        }
+
+        Some(offsets)
+    }
+}
+
+/// Simulated processing of the consumed messages
+struct Processing {
+    delay: ProcessingDelay,
+    last_stall: tokio::time::Instant,
+}
+
+impl Processing {
+    fn new(delay: ProcessingDelay) -> Self {
+        Self {
+            delay,
+            last_stall: tokio::time::Instant::now(),
+        }
+    }
+
+    async fn process(&mut self) {
+        let delay = match &self.delay {
+            ProcessingDelay::Fixed { delay } => delay.0,
+            ProcessingDelay::Random { min, max } if max.0 > min.0 => {
+                rand::Rng::random_range(&mut rand::rng(), min.0..=max.0)
+            }
+            ProcessingDelay::Random { min, .. } => min.0,
+            ProcessingDelay::Stalls { interval, stall } => {
+                if self.last_stall.elapsed() < interval.0 {
+                    return;
+                }
+                self.last_stall = tokio::time::Instant::now() + stall.0;
+                stall.0
+            }
+        };
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Records the listener event into every experiment served by the listener
+fn record_listener_event<T: Clone>(
+    status: &ListenerStatusHandle,
+    state: &MessagesState,
+    event: T,
+    record: fn(T) -> Record,
 ) {
-    use rdkafka::config::ClientConfig;
-    use rdkafka::consumer::DefaultConsumerContext;
-    use rdkafka::consumer::stream_consumer::StreamConsumer;
-
-    let mut config = ClientConfig::new();
-
-    config
-        .set("group.id", cfg.consumer_group_id.clone())
-        .set("bootstrap.servers", cfg.brokers.clone())
-        .set("enable.auto.commit", "true")
-        .set(
-            "max.poll.interval.ms",
-            (cfg.message_timeout.0.as_millis() * 2).to_string(),
-        )
-        .set(
-            "session.timeout.ms",
-            cfg.message_timeout.0.as_millis().to_string(),
-        )
-        .set("enable.auto.commit", "false");
+    let experiments = status.lock().experiments.clone();
 
-    if cfg.ssl {
-        config.set("security.protocol", "ssl");
-        config.set("enable.ssl.certificate.verification", "false");
+    for experiment_uuid in experiments {
+        if let Some(experiment) = state.get(&experiment_uuid) {
+            experiment.record(record(event.clone()));
+        }
     }
+}
+
+fn record_churn(shared: &ListenerShared, state: &MessagesState, churn_type: ChurnType) {
+    tracing::info!(
+        "Listener churn for {} ({}): {:?}",
+        shared.cfg.topic,
+        shared.cfg.consumer_group_id,
+        churn_type
+    );
+
+    let event = ChurnEvent {
+        timestamp_millis: get_now_millis(),
+        brokers: shared.cfg.brokers.clone(),
+        topic: shared.cfg.topic.clone(),
+        consumer_group: shared.cfg.consumer_group_id.clone(),
+        churn_type,
+    };
+
+    record_listener_event(&shared.status, state, event, Record::Churn);
+}
+
+/// Consumer instances of the running listener
+struct ListenerInstances {
+    shared: Arc<ListenerShared>,
+    state: MessagesState,
+    consumers: ListenerConsumers,
+    /// Instances are aborted once the set is dropped
+    tasks: tokio::task::JoinSet<()>,
+    aborts: BTreeMap<usize, tokio::task::AbortHandle>,
+}
+
+impl ListenerInstances {
+    fn spawn(&mut self, instance: ListenerInstance) {
+        let index = instance.index;
 
-    if offset_reset {
-        config.set("auto.offset.reset", "earliest");
-        tracing::info!("Consuming messages from beginning");
+        self.stop(index);
+        self.shared.status.lock().instance_started(index);
+        self.consumers
+            .lock()
+            .insert(index, instance.consumer.clone());
+
+        let abort = self.tasks.spawn(consumer_loop(
+            instance,
+            self.shared.clone(),
+            self.state.clone(),
+        ));
+        self.aborts.insert(index, abort);
+    }
+
+    fn stop(&mut self, index: usize) {
+        if let Some(abort) = self.aborts.remove(&index) {
+            abort.abort();
+            self.consumers.lock().remove(&index);
+            self.shared.status.lock().instance_stopped(index);
+        }
+    }
+
+    /// Creates the consumer for the instance and starts it
+    fn start(&mut self, index: usize) -> bool {
+        match self.shared.instance(index) {
+            Ok(instance) => {
+                self.spawn(instance);
+                true
+            }
+            Err(e) => {
+                warn!("Could not create listener instance: {}", e);
+                self.shared.status.lock().set_error(e.to_string());
+                false
+            }
+        }
     }
+}
+
+/// Runs the consumer instances of the listener and injects the configured churn
+async fn listener_loop(listener: Listener, state: MessagesState, consumers: ListenerConsumers) {
+    let Listener {
+        shared,
+        instances: created,
+        ..
+    } = listener;
 
-    let consumer: StreamConsumer<DefaultConsumerContext> =
-        config.create().expect("Consumer creation failed");
+    let mut instances = ListenerInstances {
+        shared: shared.clone(),
+        state: state.clone(),
+        consumers,
+        tasks: tokio::task::JoinSet::new(),
+        aborts: BTreeMap::new(),
+    };
 
-    consumer
-        .subscribe(&[&cfg.topic])
-        .expect("Could not subscribe fucker");
+    for instance in created {
+        instances.spawn(instance);
+    }
+
+    let Some(churn) = shared.cfg.churn.clone() else {
+        while instances.tasks.join_next().await.is_some() {}
+        return;
+    };
+
+    // Instance added by the churn gets the index following the regular ones
+    let extra = shared.cfg.parallelism.max(1);
 
     loop {
-        match consumer.recv().await {
-            Err(e) => warn!("Kafka error: {}", e),
+        tokio::time::sleep(churn.schedule.next_delay()).await;
+
+        while instances.tasks.try_join_next().is_some() {}
+
+        match &churn.action {
+            ChurnAction::Restart => {
+                if instances.start(0) {
+                    record_churn(&shared, &state, ChurnType::InstanceRestarted);
+                }
+            }
+            ChurnAction::AddRemove { hold } => {
+                if !instances.start(extra) {
+                    continue;
+                }
+                record_churn(&shared, &state, ChurnType::InstanceAdded);
+
+                tokio::time::sleep(hold.0).await;
+
+                instances.stop(extra);
+                record_churn(&shared, &state, ChurnType::InstanceRemoved);
+            }
+        }
+    }
+}
+
+async fn consumer_loop(
+    instance: ListenerInstance,
+    shared: Arc<ListenerShared>,
+    state: MessagesState,
+) {
+    let ListenerInstance {
+        index,
+        consumer,
+        mut rebalances,
+    } = instance;
+    let ListenerShared {
+        cfg,
+        status,
+        correlator,
+        ..
+    } = shared.as_ref();
+
+    let mut processing = cfg.processing.clone().map(Processing::new);
+
+    let mut commit_interval = match &cfg.commit {
+        CommitStrategy::Manual { interval } => Some(tokio::time::interval(interval.0)),
+        CommitStrategy::None | CommitStrategy::Auto { .. } => None,
+    };
+    let mut finished = FinishedOffsets::default();
+
+    loop {
+        let received = tokio::select! {
+            received = consumer.recv() => received,
+            Some(rebalance) = rebalances.recv() => {
+                record_listener_event(status, &state, rebalance, Record::Rebalance);
+                continue;
+            }
+            _ = commit_tick(&mut commit_interval) => {
+                if let Some(offsets) = finished.take()
+                    && let Err(e) = consumer.commit(&offsets, CommitMode::Async)
+                {
+                    tracing::debug!("Could not commit listener offsets: {}", e);
+                }
+                continue;
+            }
+        };
+
+        match received {
+            Err(KafkaError::MessageConsumptionFatal(code)) => {
+                warn!("Fatal kafka error: {}", code);
+                let mut status = status.lock();
+                status.set_error(code.to_string());
+                status.state = ListenerState::Dead;
+                break;
+            }
+            Err(e) => {
+                warn!("Kafka error: {}", e);
+                status.lock().set_error(e.to_string());
+            }
             Ok(m) => {
                 let now = get_now_millis();
-                let headers: HashMap<String, String> = if let Some(headers) = m.headers() {
-                    headers
-                        .iter()
-                        .filter_map(|header| {
-                            let value = header.value.map(std::str::from_utf8).and_then(|x| x.ok());
-
-                            value.map(|x| (header.key.to_string(), x.to_string()))
-                        })
-                        .collect()
-                } else {
-                    HashMap::new()
-                };
+                status.lock().message_seen(index, now);
 
-                if let Some(message_uuid_str) = headers.get(MESSAGE_UUID_HEADER)
-                    && let Ok(message_uuid) = uuid::Uuid::try_parse(message_uuid_str)
-                    && let Some(experiment_uuid_str) = headers.get(EXPERIMENT_UUID_HEADER)
-                    && let Ok(experiment_uuid) = uuid::Uuid::try_parse(experiment_uuid_str)
-                {
-                    let mut state = state.lock().await;
-
-                    if (!state.messages.contains_key(&message_uuid))
-                        && state.experiments.contains_key(&experiment_uuid)
-                    {
-                        state.insert_message(
-                            Message {
-                                uuid: message_uuid,
-                                bytes_size: bytesize::ByteSize::b(m.payload_len() as u64).into(),
-                            },
-                            experiment_uuid,
-                            false,
-                        );
-                    }
+                let recorded = record_received(&m, now, cfg, status, correlator, &state);
+
+                if let Some(processing) = &mut processing {
+                    processing.process().await;
 
-                    if state
-                        .messages
-                        .get(&experiment_uuid)
-                        .is_some_and(|experiment_messages| {
-                            experiment_messages.0.contains_key(&message_uuid)
-                        })
-                    {
-                        let experiment_uuid = {
-                            *state
-                                .idx_message_to_experiment
-                                .0
-                                .get(&message_uuid)
-                                .unwrap()
-                        };
-
-                        let events = state.events.entry(experiment_uuid).or_default();
-
-                        events.push(MessageEvent {
-                            message_uuid,
-                            timestamp_millis: now,
-                            topic: m.topic().into(),
-                            brokers: cfg.brokers.clone(),
-                            event_type: EventType::Received {
-                                consumer_group: cfg.consumer_group_id.clone(),
-                            },
-                        });
-
-                        if let Some(millis) = m.timestamp().to_millis() {
-                            events.push(MessageEvent {
-                                message_uuid,
-                                timestamp_millis: millis as u128,
-                                topic: m.topic().into(),
-                                brokers: cfg.brokers.clone(),
-                                event_type: EventType::KafkaTimestampSet {
-                                    consumer_group: cfg.consumer_group_id.clone(),
-                                },
-                            });
-                        }
-
-                        tracing::log::debug!("Message {} consumed", message_uuid);
-                    } else {
-                        tracing::log::debug!("Message is not destined for us. Skipping");
+                    if let Some((experiment_uuid, message_uuid)) = recorded {
+                        record_processed(&m, experiment_uuid, message_uuid, cfg, &state);
                     }
                 }
+
+                if commit_interval.is_some() {
+                    finished.finish(m.topic(), m.partition(), m.offset());
+                }
             }
         }
     }
 }
+
+/// Records the receipt of the experiment message. Returns the experiment and message uuids of
+/// the recorded message
+fn record_received(
+    m: &BorrowedMessage<'_>,
+    now: u128,
+    cfg: &KafkaBrokerCfg,
+    status: &ListenerStatusHandle,
+    correlator: &Correlator,
+    state: &MessagesState,
+) -> Option<(Uuid, Uuid)> {
+    let (message_uuid, experiment_uuid) = correlator.extract(m)?;
+
+    let Some(experiment_uuid) = experiment_uuid.or_else(|| state.message_experiment(&message_uuid))
+    else {
+        tracing::log::debug!("Message {} has no experiment", message_uuid);
+        return None;
+    };
+
+    if !status.lock().experiments.contains(&experiment_uuid) {
+        tracing::log::debug!("Message is not destined for us. Skipping");
+        return None;
+    }
+
+    let Some(experiment) = state.get(&experiment_uuid) else {
+        tracing::log::debug!("Message is not destined for us. Skipping");
+        return None;
+    };
+
+    experiment.record(Record::Received(Message {
+        uuid: message_uuid,
+        bytes_size: bytesize::ByteSize::b(m.payload_len() as u64).into(),
+    }));
+
+    experiment.record(Record::Event(MessageEvent {
+        message_uuid,
+        timestamp_millis: now,
+        topic: m.topic().into(),
+        brokers: cfg.brokers.clone(),
+        event_type: EventType::Received {
+            consumer_group: cfg.consumer_group_id.clone(),
+        },
+    }));
+
+    let kafka_timestamp = match m.timestamp() {
+        Timestamp::CreateTime(millis) => Some((millis, KafkaTimestampType::CreateTime)),
+        Timestamp::LogAppendTime(millis) => Some((millis, KafkaTimestampType::LogAppendTime)),
+        Timestamp::NotAvailable => None,
+    };
+
+    if let Some((millis, timestamp_type)) = kafka_timestamp {
+        experiment.record(Record::Event(MessageEvent {
+            message_uuid,
+            timestamp_millis: millis as u128,
+            topic: m.topic().into(),
+            brokers: cfg.brokers.clone(),
+            event_type: EventType::KafkaTimestampSet {
+                consumer_group: cfg.consumer_group_id.clone(),
+                timestamp_type,
+            },
+        }));
+    }
+
+    tracing::log::debug!("Message {} consumed", message_uuid);
+    Some((experiment_uuid, message_uuid))
+}
+
+fn record_processed(
+    m: &BorrowedMessage<'_>,
+    experiment_uuid: Uuid,
+    message_uuid: Uuid,
+    cfg: &KafkaBrokerCfg,
+    state: &MessagesState,
+) {
+    let now = get_now_millis();
+
+    // Experiment may have ended during the processing
+    if let Some(experiment) = state.get(&experiment_uuid) {
+        experiment.record(Record::Event(MessageEvent {
+            message_uuid,
+            timestamp_millis: now,
+            topic: m.topic().into(),
+            brokers: cfg.brokers.clone(),
+            event_type: EventType::Processed {
+                consumer_group: cfg.consumer_group_id.clone(),
+            },
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> KafkaBrokerCfg {
+        serde_json::from_value(serde_json::json!({
+            "brokers": "127.0.0.1:1",
+            "topic": "orders",
+            "ssl": false,
+            "message_timeout": "1s",
+            "consumer_group_id": "group",
+        }))
+        .unwrap()
+    }
+
+    fn context(restore: Option<RestorePosition>) -> ListenerContext {
+        ListenerContext {
+            status: Arc::new(parking_lot::Mutex::new(ListenerStatus::new(
+                cfg(),
+                "group".into(),
+            ))),
+            instance: 0,
+            rebalances: tokio::sync::mpsc::unbounded_channel().0,
+            restore,
+            restored: Default::default(),
+        }
+    }
+
+    fn offsets(partitions: &TopicPartitionList) -> Vec<(i32, Offset)> {
+        partitions
+            .elements()
+            .iter()
+            .map(|x| (x.partition(), x.offset()))
+            .collect()
+    }
+
+    fn committed(offsets: &TopicPartitionList) -> Vec<(String, i32, Offset)> {
+        let mut committed: Vec<_> = offsets
+            .elements()
+            .iter()
+            .map(|x| (x.topic().to_owned(), x.partition(), x.offset()))
+            .collect();
+        committed.sort_by_key(|x| (x.0.clone(), x.1));
+        committed
+    }
+
+    #[test]
+    fn finished_offsets_commit_the_next_message() {
+        let mut finished = FinishedOffsets::default();
+        assert!(finished.take().is_none());
+
+        finished.finish("orders", 0, 5);
+        finished.finish("orders", 0, 3);
+        finished.finish("orders", 1, 0);
+        finished.finish("payments", 0, 9);
+
+        assert_eq!(
+            committed(&finished.take().unwrap()),
+            vec![
+                ("orders".into(), 0, Offset::Offset(6)),
+                ("orders".into(), 1, Offset::Offset(1)),
+                ("payments".into(), 0, Offset::Offset(10)),
+            ]
+        );
+        assert!(finished.take().is_none());
+    }
+
+    #[test]
+    fn assigned_partitions_and_offsets_are_reported() {
+        let mut partitions = TopicPartitionList::new();
+        partitions
+            .add_partition_offset("orders", 0, Offset::Offset(7))
+            .unwrap();
+        partitions.add_partition("orders", 1);
+
+        assert_eq!(
+            assigned_partitions(&partitions),
+            vec![
+                AssignedPartition {
+                    topic: "orders".into(),
+                    partition: 0,
+                },
+                AssignedPartition {
+                    topic: "orders".into(),
+                    partition: 1,
+                },
+            ]
+        );
+        assert_eq!(offset_value(Offset::Offset(7)), Some(7));
+        assert_eq!(offset_value(Offset::Invalid), None);
+        assert_eq!(offset_value(Offset::End), None);
+    }
+
+    #[test]
+    fn partitions_are_restored_once() {
+        let offset = |partition, offset| PartitionOffset {
+            topic: "orders".into(),
+            partition,
+            offset,
+        };
+        let context = context(Some(RestorePosition::offsets(&[
+            offset(0, 5),
+            offset(1, 9),
+        ])));
+
+        let mut assigned = TopicPartitionList::new();
+        assigned.add_partition("orders", 0);
+        assigned.add_partition("orders", 2);
+        context.restore_offsets(&mut assigned);
+        assert_eq!(
+            offsets(&assigned),
+            vec![(0, Offset::Offset(5)), (2, Offset::Invalid)]
+        );
+
+        // Partition moved back after a rebalance continues from the committed offset
+        let mut assigned = TopicPartitionList::new();
+        assigned.add_partition("orders", 0);
+        assigned.add_partition("orders", 1);
+        context.restore_offsets(&mut assigned);
+        assert_eq!(
+            offsets(&assigned),
+            vec![(0, Offset::Invalid), (1, Offset::Offset(9))]
+        );
+    }
+
+    #[test]
+    fn earliest_restore_keeps_the_assigned_offsets() {
+        let context = context(Some(RestorePosition::Earliest));
+
+        let mut assigned = TopicPartitionList::new();
+        assigned.add_partition("orders", 0);
+        context.restore_offsets(&mut assigned);
+
+        assert_eq!(offsets(&assigned), vec![(0, Offset::Invalid)]);
+    }
+
+    #[tokio::test]
+    async fn listener_is_shared_only_with_the_same_config() {
+        let mut consumers = Consumers::new(MessagesState::default());
+        let headers = CorrelationHeaders::default();
+        let running = Uuid::new_v4();
+
+        let attachment = consumers.attachment(&cfg(), None, &headers).unwrap();
+        assert!(matches!(attachment, ListenerAttachment::New(_)));
+        consumers.start(running, attachment).await;
+
+        let shared = consumers.attachment(&cfg(), None, &headers);
+        assert!(matches!(shared, Ok(ListenerAttachment::Shared(_))));
+
+        let other_isolation = KafkaBrokerCfg {
+            isolation_level: Some(crate::models::IsolationLevel::ReadUncommitted),
+            ..cfg()
+        };
+        assert!(matches!(
+            consumers.attachment(&other_isolation, None, &headers),
+            Err(ExperimentError::ListenerConfigConflict)
+        ));
+
+        let other_headers = CorrelationHeaders {
+            message_uuid: "other-message-uuid".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            consumers.attachment(&cfg(), None, &other_headers),
+            Err(ExperimentError::ListenerConfigConflict)
+        ));
+
+        let other_group = KafkaBrokerCfg {
+            consumer_group_id: "other-group".into(),
+            isolation_level: Some(crate::models::IsolationLevel::ReadUncommitted),
+            ..cfg()
+        };
+        assert!(matches!(
+            consumers.attachment(&other_group, None, &headers),
+            Ok(ListenerAttachment::New(_))
+        ));
+
+        consumers.stop(&running);
+    }
+
+    #[tokio::test]
+    async fn restore_conflicts_with_listeners_of_other_experiments() {
+        let mut consumers = Consumers::new(MessagesState::default());
+        let headers = CorrelationHeaders::default();
+        let running = Uuid::new_v4();
+        let restore = |experiment_uuid, ephemeral_group| ListenerRestore {
+            experiment_uuid,
+            position: RestorePosition::Earliest,
+            ephemeral_group,
+        };
+
+        let attachment = consumers.attachment(&cfg(), None, &headers).unwrap();
+        consumers.start(running, attachment).await;
+
+        // Own listener is stopped before the restore
+        let own = consumers.attachment(&cfg(), Some(&restore(running, false)), &headers);
+        assert!(matches!(own, Ok(ListenerAttachment::New(_))));
+
+        let other = consumers.attachment(&cfg(), Some(&restore(Uuid::new_v4(), false)), &headers);
+        assert!(matches!(
+            other,
+            Err(ExperimentError::ListenerConfigConflict)
+        ));
+
+        let ephemeral =
+            consumers.attachment(&cfg(), Some(&restore(Uuid::new_v4(), true)), &headers);
+        let Ok(ListenerAttachment::New(ephemeral)) = ephemeral else {
+            panic!("ephemeral restore is not a new listener");
+        };
+        assert_ne!(ephemeral.key, ListenerKey::new(&cfg(), "group"));
+
+        consumers.stop(&running);
+    }
+
+    #[test]
+    fn topic_patterns_are_anchored_for_the_kafka_client() {
+        let pattern = |topic: &str| KafkaBrokerCfg {
+            topic: topic.into(),
+            topic_pattern: true,
+            ..cfg()
+        };
+
+        assert_eq!(subscription(&cfg()).unwrap(), "orders");
+        assert_eq!(
+            subscription(&pattern("dc[0-9]+\\.orders")).unwrap(),
+            "^dc[0-9]+\\.orders"
+        );
+        assert_eq!(
+            subscription(&pattern("^dc[0-9]+\\.orders$")).unwrap(),
+            "^dc[0-9]+\\.orders$"
+        );
+        assert!(matches!(
+            subscription(&pattern("(")),
+            Err(ExperimentError::InvalidTopicPattern(_))
+        ));
+    }
+
+    #[test]
+    fn perl_syntax_is_rejected_in_topic_patterns() {
+        assert!(posix_pattern("^dc[0-9]+\\.orders$").is_ok());
+        assert!(posix_pattern("^orders-(eu|us)$").is_ok());
+
+        for (pattern, unsupported) in [
+            ("^dc\\d+\\.orders$", "\\d"),
+            ("^\\w+$", "\\w"),
+            ("(?i)^orders$", "(?"),
+        ] {
+            match posix_pattern(pattern) {
+                Err(ExperimentError::UnsupportedTopicPattern(x)) => assert_eq!(x, unsupported),
+                other => panic!("{pattern} is not rejected: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rebalances_are_recorded_into_the_listener_experiments() {
+        let state = MessagesState::default();
+        let experiment =
+            crate::models::Experiment::new(Uuid::new_v4(), vec![cfg()], Default::default());
+        let experiment_uuid = experiment.uuid;
+        let shard = state.insert(crate::state::ExperimentState::new(experiment));
+
+        let (sender, mut rebalances) = tokio::sync::mpsc::unbounded_channel();
+        let context = ListenerContext {
+            rebalances: sender,
+            ..context(None)
+        };
+        let status = context.status.clone();
+        {
+            let mut status = status.lock();
+            status.experiments.insert(experiment_uuid);
+            status.instance_started(0);
+            status.state = ListenerState::Subscribed;
+        }
+        let consumer: BaseConsumer<ListenerContext> = ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .set("group.id", "group")
+            .create_with_context(context)
+            .unwrap();
+
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition("orders", 0);
+        partitions.add_partition("orders", 1);
+
+        consumer
+            .context()
+            .post_rebalance(&consumer, &Rebalance::Assign(&partitions));
+        assert_eq!(status.lock().state, ListenerState::Assigned);
+        assert_eq!(status.lock().assigned_partitions.len(), 2);
+
+        consumer
+            .context()
+            .post_rebalance(&consumer, &Rebalance::Revoke(&partitions));
+        assert!(status.lock().assigned_partitions.is_empty());
+
+        let assigned = rebalances.recv().await.unwrap();
+        let revoked = rebalances.recv().await.unwrap();
+        assert!(matches!(assigned.rebalance_type, RebalanceType::Assigned));
+        assert!(matches!(revoked.rebalance_type, RebalanceType::Revoked));
+        assert_eq!(revoked.partitions, assigned_partitions(&partitions));
+        assert_eq!(revoked.consumer_group, "group");
+
+        record_listener_event(&status, &state, assigned, Record::Rebalance);
+        record_listener_event(&status, &state, revoked, Record::Rebalance);
+        shard.drained().await;
+        assert_eq!(shard.lock().rebalances.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn listener_starts_parallel_instances_in_one_group() {
+        let parallel = KafkaBrokerCfg {
+            parallelism: 3,
+            ..cfg()
+        };
+        let listener = Listener::new(parallel, None, &Default::default()).unwrap();
+
+        let indexes: Vec<_> = listener.instances.iter().map(|x| x.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+
+        // Zero parallelism still consumes with a single instance
+        let single = KafkaBrokerCfg {
+            parallelism: 0,
+            ..cfg()
+        };
+        let listener = Listener::new(single, None, &Default::default()).unwrap();
+        assert_eq!(listener.instances.len(), 1);
+    }
+
+    async fn processing_time(processing: &mut Processing) -> std::time::Duration {
+        let start = tokio::time::Instant::now();
+        processing.process().await;
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn processing_delays_the_listener() {
+        let millis = |x| crate::models::Duration(std::time::Duration::from_millis(x));
+
+        let mut fixed = Processing::new(ProcessingDelay::Fixed { delay: millis(30) });
+        assert!(processing_time(&mut fixed).await >= millis(30).0);
+
+        let mut inverted = Processing::new(ProcessingDelay::Random {
+            min: millis(30),
+            max: millis(10),
+        });
+        assert!(processing_time(&mut inverted).await >= millis(30).0);
+
+        let mut random = Processing::new(ProcessingDelay::Random {
+            min: millis(10),
+            max: millis(20),
+        });
+        assert!(processing_time(&mut random).await >= millis(10).0);
+    }
+
+    #[tokio::test]
+    async fn processing_stalls_once_per_interval() {
+        let millis = |x| crate::models::Duration(std::time::Duration::from_millis(x));
+        let mut stalls = Processing::new(ProcessingDelay::Stalls {
+            interval: millis(100),
+            stall: millis(200),
+        });
+
+        assert!(processing_time(&mut stalls).await < millis(200).0);
+
+        tokio::time::sleep(millis(100).0).await;
+        assert!(processing_time(&mut stalls).await >= millis(200).0);
+
+        // Interval is counted from the end of the stall
+        assert!(processing_time(&mut stalls).await < millis(200).0);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::models::{CorrelationExtractor, CorrelationHeaders};
+use rdkafka::message::{Headers, Message};
+use regex::Regex;
+use uuid::Uuid;
+
+/// Length of the uuid in its textual form
+const UUID_TEXT_LEN: usize = 36;
+
+enum Extractor {
+    Headers {
+        message_uuid: String,
+        experiment_uuid: Option<String>,
+    },
+    Key(Regex),
+    JsonPointer {
+        message_uuid: String,
+        experiment_uuid: Option<String>,
+    },
+    ByteOffset {
+        message_uuid: usize,
+        experiment_uuid: Option<usize>,
+    },
+}
+
+/// Finds the message and experiment uuids of the consumed message
+pub struct Correlator {
+    extractors: Vec<Extractor>,
+}
+
+impl Correlator {
+    /// Experiment headers are tried after the configured extractors, so the listener header
+    /// names act as aliases
+    pub fn new(
+        cfg: &[CorrelationExtractor],
+        headers: &CorrelationHeaders,
+    ) -> Result<Self, regex::Error> {
+        let default = CorrelationExtractor::Headers {
+            message_uuid: headers.message_uuid.clone(),
+            experiment_uuid: Some(headers.experiment_uuid.clone()),
+        };
+
+        let fallback = (!cfg.contains(&default)).then_some(&default);
+
+        let extractors = cfg
+            .iter()
+            .chain(fallback)
+            .map(|extractor| {
+                Ok(match extractor.clone() {
+                    CorrelationExtractor::Headers {
+                        message_uuid,
+                        experiment_uuid,
+                    } => Extractor::Headers {
+                        message_uuid,
+                        experiment_uuid,
+                    },
+                    CorrelationExtractor::Key { pattern } => Extractor::Key(Regex::new(&pattern)?),
+                    CorrelationExtractor::JsonPointer {
+                        message_uuid,
+                        experiment_uuid,
+                    } => Extractor::JsonPointer {
+                        message_uuid,
+                        experiment_uuid,
+                    },
+                    CorrelationExtractor::ByteOffset {
+                        message_uuid,
+                        experiment_uuid,
+                    } => Extractor::ByteOffset {
+                        message_uuid,
+                        experiment_uuid,
+                    },
+                })
+            })
+            .collect::<Result<_, regex::Error>>()?;
+
+        Ok(Self { extractors })
+    }
+
+    /// Message uuid with the experiment uuid, if the message carries it. The first extractor
+    /// finding the message uuid wins
+    pub fn extract(&self, message: &impl Message) -> Option<(Uuid, Option<Uuid>)> {
+        self.extractors
+            .iter()
+            .find_map(|extractor| extractor.extract(message))
+    }
+}
+
+impl Extractor {
+    fn extract(&self, message: &impl Message) -> Option<(Uuid, Option<Uuid>)> {
+        match self {
+            Self::Headers {
+                message_uuid,
+                experiment_uuid,
+            } => {
+                let headers = message.headers()?;
+                let header = |name: &str| {
+                    headers
+                        .iter()
+                        .find(|header| header.key == name)
+                        .and_then(|header| header.value)
+                        .and_then(parse_header_uuid)
+                };
+
+                Some((
+                    header(message_uuid)?,
+                    experiment_uuid.as_deref().and_then(header),
+                ))
+            }
+            Self::Key(pattern) => {
+                let key = std::str::from_utf8(message.key()?).ok()?;
+                let captures = pattern.captures(key)?;
+                let group = |name: &str| {
+                    captures
+                        .name(name)
+                        .and_then(|x| Uuid::try_parse(x.as_str()).ok())
+                };
+
+                Some((group("message_uuid")?, group("experiment_uuid")))
+            }
+            Self::JsonPointer {
+                message_uuid,
+                experiment_uuid,
+            } => {
+                let payload: serde_json::Value = serde_json::from_slice(message.payload()?).ok()?;
+                let pointer = |pointer: &str| {
+                    payload
+                        .pointer(pointer)
+                        .and_then(|x| x.as_str())
+                        .and_then(|x| Uuid::try_parse(x).ok())
+                };
+
+                Some((
+                    pointer(message_uuid)?,
+                    experiment_uuid.as_deref().and_then(pointer),
+                ))
+            }
+            Self::ByteOffset {
+                message_uuid,
+                experiment_uuid,
+            } => {
+                let payload = message.payload()?;
+                let at = |offset: usize| {
+                    payload
+                        .get(offset..offset.checked_add(UUID_TEXT_LEN)?)
+                        .and_then(parse_uuid)
+                };
+
+                Some((at(*message_uuid)?, experiment_uuid.and_then(at)))
+            }
+        }
+    }
+}
+
+/// Header value is either the textual or the binary (16 bytes) uuid
+fn parse_header_uuid(bytes: &[u8]) -> Option<Uuid> {
+    match bytes.len() {
+        16 => Uuid::from_slice(bytes).ok(),
+        _ => parse_uuid(bytes),
+    }
+}
+
+fn parse_uuid(bytes: &[u8]) -> Option<Uuid> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|x| Uuid::try_parse(x).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::Timestamp;
+    use rdkafka::message::{Header, OwnedHeaders, OwnedMessage};
+
+    const MESSAGE_UUID: Uuid = Uuid::from_u64_pair(1, 1);
+    const EXPERIMENT_UUID: Uuid = Uuid::from_u64_pair(2, 2);
+
+    fn message(
+        key: Option<&str>,
+        payload: Option<&[u8]>,
+        headers: &[(&str, &[u8])],
+    ) -> OwnedMessage {
+        let headers = headers
+            .iter()
+            .fold(OwnedHeaders::new(), |all, (key, value)| {
+                all.insert(Header {
+                    key,
+                    value: Some(*value),
+                })
+            });
+
+        OwnedMessage::new(
+            payload.map(|x| x.to_vec()),
+            key.map(|x| x.as_bytes().to_vec()),
+            "topic".into(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(headers),
+        )
+    }
+
+    fn correlator(cfg: &[CorrelationExtractor]) -> Correlator {
+        Correlator::new(cfg, &CorrelationHeaders::default()).unwrap()
+    }
+
+    #[test]
+    fn default_headers_accept_textual_and_binary_uuids() {
+        let headers = CorrelationHeaders::default();
+        let textual = message(
+            None,
+            None,
+            &[
+                (&headers.message_uuid, MESSAGE_UUID.to_string().as_bytes()),
+                (
+                    &headers.experiment_uuid,
+                    EXPERIMENT_UUID.to_string().as_bytes(),
+                ),
+            ],
+        );
+        let binary = message(
+            None,
+            None,
+            &[
+                (&headers.message_uuid, MESSAGE_UUID.as_bytes()),
+                (&headers.experiment_uuid, EXPERIMENT_UUID.as_bytes()),
+            ],
+        );
+
+        let correlator = correlator(&[]);
+        assert_eq!(
+            correlator.extract(&textual),
+            Some((MESSAGE_UUID, Some(EXPERIMENT_UUID)))
+        );
+        assert_eq!(
+            correlator.extract(&binary),
+            Some((MESSAGE_UUID, Some(EXPERIMENT_UUID)))
+        );
+    }
+
+    #[test]
+    fn configured_headers_fall_back_to_experiment_headers() {
+        let headers = CorrelationHeaders::default();
+        let correlator = correlator(&[CorrelationExtractor::Headers {
+            message_uuid: "trace-id".into(),
+            experiment_uuid: None,
+        }]);
+
+        let aliased = message(
+            None,
+            None,
+            &[("trace-id", MESSAGE_UUID.to_string().as_bytes())],
+        );
+        let default = message(
+            None,
+            None,
+            &[(&headers.message_uuid, MESSAGE_UUID.to_string().as_bytes())],
+        );
+
+        assert_eq!(correlator.extract(&aliased), Some((MESSAGE_UUID, None)));
+        assert_eq!(correlator.extract(&default), Some((MESSAGE_UUID, None)));
+        assert_eq!(correlator.extract(&message(None, None, &[])), None);
+    }
+
+    #[test]
+    fn key_pattern_extracts_named_groups() {
+        let correlator = correlator(&[CorrelationExtractor::Key {
+            pattern: "^(?P<experiment_uuid>[0-9a-f-]{36}):(?P<message_uuid>[0-9a-f-]{36})$".into(),
+        }]);
+        let key = format!("{EXPERIMENT_UUID}:{MESSAGE_UUID}");
+
+        assert_eq!(
+            correlator.extract(&message(Some(&key), None, &[])),
+            Some((MESSAGE_UUID, Some(EXPERIMENT_UUID)))
+        );
+        assert_eq!(
+            correlator.extract(&message(Some("not-a-uuid"), None, &[])),
+            None
+        );
+    }
+
+    #[test]
+    fn json_pointer_reads_payload_fields() {
+        let correlator = correlator(&[CorrelationExtractor::JsonPointer {
+            message_uuid: "/ids/message".into(),
+            experiment_uuid: Some("/ids/experiment".into()),
+        }]);
+        let payload = serde_json::json!({
+            "ids": {"message": MESSAGE_UUID, "experiment": EXPERIMENT_UUID}
+        })
+        .to_string();
+        let without_experiment = serde_json::json!({"ids": {"message": MESSAGE_UUID}}).to_string();
+
+        assert_eq!(
+            correlator.extract(&message(None, Some(payload.as_bytes()), &[])),
+            Some((MESSAGE_UUID, Some(EXPERIMENT_UUID)))
+        );
+        assert_eq!(
+            correlator.extract(&message(None, Some(without_experiment.as_bytes()), &[])),
+            Some((MESSAGE_UUID, None))
+        );
+        assert_eq!(
+            correlator.extract(&message(None, Some(b"not json"), &[])),
+            None
+        );
+    }
+
+    #[test]
+    fn byte_offset_reads_textual_uuids() {
+        let correlator = correlator(&[CorrelationExtractor::ByteOffset {
+            message_uuid: 4,
+            experiment_uuid: Some(40),
+        }]);
+        let payload = format!("abcd{MESSAGE_UUID}{EXPERIMENT_UUID}tail");
+
+        assert_eq!(
+            correlator.extract(&message(None, Some(payload.as_bytes()), &[])),
+            Some((MESSAGE_UUID, Some(EXPERIMENT_UUID)))
+        );
+        assert_eq!(
+            correlator.extract(&message(None, Some(&payload.as_bytes()[..50]), &[])),
+            Some((MESSAGE_UUID, None))
+        );
+        assert_eq!(
+            correlator.extract(&message(None, Some(b"short"), &[])),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_key_pattern_is_rejected() {
+        let cfg = [CorrelationExtractor::Key {
+            pattern: "(".into(),
+        }];
+
+        assert!(Correlator::new(&cfg, &CorrelationHeaders::default()).is_err());
+    }
+}
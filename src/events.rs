@@ -0,0 +1,399 @@
+use crate::models::{DeliveryFailureReason, EventType, KafkaTimestampType, MessageEvent};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Events of the experiment are stored in chunks of that many events. Only the last chunk is
+/// copied when it is written while being read
+const CHUNK_LEN: usize = 4096;
+
+/// Event type with the consumer group replaced by its id in the dictionary
+#[derive(Debug, Clone, Copy)]
+enum CompactEventType {
+    Sent,
+    SendFailed(DeliveryFailureReason),
+    TransactionCommitted,
+    TransactionAborted,
+    KafkaTimestampSet(u16, KafkaTimestampType),
+    Received(u16),
+    Processed(u16),
+}
+
+/// Interned brokers, topics and consumer groups of the experiment events. Experiments have
+/// only a few distinct ones
+#[derive(Debug, Clone, Default)]
+struct Dictionary {
+    strings: Vec<Box<str>>,
+    ids: HashMap<Box<str>, u16>,
+}
+
+impl Dictionary {
+    fn get(&self, id: u16) -> &str {
+        self.strings.get(id as usize).map_or("", |x| x)
+    }
+}
+
+#[derive(Debug)]
+struct EventChunk {
+    timestamps_millis: Vec<u64>,
+    message_uuids: Vec<Uuid>,
+    brokers: Vec<u16>,
+    topics: Vec<u16>,
+    event_types: Vec<CompactEventType>,
+}
+
+impl EventChunk {
+    fn new() -> Self {
+        Self {
+            timestamps_millis: Vec::with_capacity(CHUNK_LEN),
+            message_uuids: Vec::with_capacity(CHUNK_LEN),
+            brokers: Vec::with_capacity(CHUNK_LEN),
+            topics: Vec::with_capacity(CHUNK_LEN),
+            event_types: Vec::with_capacity(CHUNK_LEN),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.timestamps_millis.len()
+    }
+}
+
+/// Keeps the full capacity, so the copied chunk is not reallocated while being filled up
+impl Clone for EventChunk {
+    fn clone(&self) -> Self {
+        let mut chunk = Self::new();
+        chunk.timestamps_millis.extend(&self.timestamps_millis);
+        chunk.message_uuids.extend(&self.message_uuids);
+        chunk.brokers.extend(&self.brokers);
+        chunk.topics.extend(&self.topics);
+        chunk.event_types.extend(&self.event_types);
+        chunk
+    }
+}
+
+/// Columnar storage of the experiment events
+#[derive(Debug, Clone, Default)]
+pub struct EventStore {
+    chunks: Vec<Arc<EventChunk>>,
+    dictionary: Arc<Dictionary>,
+    len: usize,
+}
+
+impl EventStore {
+    pub fn push(&mut self, event: MessageEvent) {
+        let brokers = self.intern(&event.brokers);
+        let topic = self.intern(&event.topic);
+        let event_type = match event.event_type {
+            EventType::Sent => CompactEventType::Sent,
+            EventType::SendFailed { reason } => CompactEventType::SendFailed(reason),
+            EventType::TransactionCommitted => CompactEventType::TransactionCommitted,
+            EventType::TransactionAborted => CompactEventType::TransactionAborted,
+            EventType::KafkaTimestampSet {
+                consumer_group,
+                timestamp_type,
+            } => CompactEventType::KafkaTimestampSet(self.intern(&consumer_group), timestamp_type),
+            EventType::Received { consumer_group } => {
+                CompactEventType::Received(self.intern(&consumer_group))
+            }
+            EventType::Processed { consumer_group } => {
+                CompactEventType::Processed(self.intern(&consumer_group))
+            }
+        };
+
+        if self.chunks.last().is_none_or(|x| x.len() >= CHUNK_LEN) {
+            self.chunks.push(Arc::new(EventChunk::new()));
+        }
+
+        // Copies the last chunk only if it is being read
+        let Some(chunk) = self.chunks.last_mut().map(Arc::make_mut) else {
+            return;
+        };
+
+        chunk.timestamps_millis.push(event.timestamp_millis as u64);
+        chunk.message_uuids.push(event.message_uuid);
+        chunk.brokers.push(brokers);
+        chunk.topics.push(topic);
+        chunk.event_types.push(event_type);
+
+        self.len += 1;
+    }
+
+    fn intern(&mut self, value: &str) -> u16 {
+        if let Some(id) = self.dictionary.ids.get(value) {
+            return *id;
+        }
+
+        // Never assigned, so it reads as an empty string
+        let id = self.dictionary.strings.len() as u16;
+        if id == u16::MAX {
+            tracing::warn!("Too many distinct brokers, topics and groups in the experiment");
+            return u16::MAX;
+        }
+
+        // Readers keep the previous dictionary
+        let dictionary = Arc::make_mut(&mut self.dictionary);
+        dictionary.strings.push(value.into());
+        dictionary.ids.insert(value.into(), id);
+
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Events recorded so far. Later events are not visible in the snapshot
+    pub fn snapshot(&self) -> EventsSnapshot {
+        EventsSnapshot {
+            chunks: self.chunks.clone(),
+            dictionary: self.dictionary.clone(),
+            len: self.len,
+        }
+    }
+}
+
+/// Experiment events shared with the store. Taking the snapshot does not copy the events
+#[derive(Debug, Clone, Default)]
+pub struct EventsSnapshot {
+    chunks: Vec<Arc<EventChunk>>,
+    dictionary: Arc<Dictionary>,
+    len: usize,
+}
+
+impl EventsSnapshot {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = EventRef<'_>> {
+        iter_chunks(&self.chunks, &self.dictionary, 0)
+    }
+
+    /// Events starting from the provided position, in the recording order
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = EventRef<'_>> {
+        iter_chunks(&self.chunks, &self.dictionary, start)
+    }
+}
+
+fn iter_chunks<'a>(
+    chunks: &'a [Arc<EventChunk>],
+    dictionary: &'a Dictionary,
+    start: usize,
+) -> impl Iterator<Item = EventRef<'a>> {
+    let (first_chunk, first_idx) = (start / CHUNK_LEN, start % CHUNK_LEN);
+
+    chunks
+        .iter()
+        .enumerate()
+        .skip(first_chunk)
+        .flat_map(move |(chunk_idx, chunk)| {
+            let from = if chunk_idx == first_chunk {
+                first_idx
+            } else {
+                0
+            };
+
+            (from..chunk.len()).map(move |idx| EventRef {
+                message_uuid: chunk.message_uuids[idx],
+                timestamp_millis: chunk.timestamps_millis[idx] as u128,
+                brokers: dictionary.get(chunk.brokers[idx]),
+                topic: dictionary.get(chunk.topics[idx]),
+                event_type: match chunk.event_types[idx] {
+                    CompactEventType::Sent => EventTypeRef::Sent,
+                    CompactEventType::SendFailed(reason) => EventTypeRef::SendFailed { reason },
+                    CompactEventType::TransactionCommitted => EventTypeRef::TransactionCommitted,
+                    CompactEventType::TransactionAborted => EventTypeRef::TransactionAborted,
+                    CompactEventType::KafkaTimestampSet(group, timestamp_type) => {
+                        EventTypeRef::KafkaTimestampSet {
+                            consumer_group: dictionary.get(group),
+                            timestamp_type,
+                        }
+                    }
+                    CompactEventType::Received(group) => EventTypeRef::Received {
+                        consumer_group: dictionary.get(group),
+                    },
+                    CompactEventType::Processed(group) => EventTypeRef::Processed {
+                        consumer_group: dictionary.get(group),
+                    },
+                },
+            })
+        })
+}
+
+/// Event read from the store. Strings are borrowed from the dictionary of the store
+#[derive(Debug, Clone, Copy)]
+pub struct EventRef<'a> {
+    pub message_uuid: Uuid,
+    pub timestamp_millis: u128,
+    pub brokers: &'a str,
+    pub topic: &'a str,
+    pub event_type: EventTypeRef<'a>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum EventTypeRef<'a> {
+    Sent,
+    SendFailed {
+        reason: DeliveryFailureReason,
+    },
+    TransactionCommitted,
+    TransactionAborted,
+    KafkaTimestampSet {
+        consumer_group: &'a str,
+        timestamp_type: KafkaTimestampType,
+    },
+    Received {
+        consumer_group: &'a str,
+    },
+    Processed {
+        consumer_group: &'a str,
+    },
+}
+
+impl EventRef<'_> {
+    pub fn to_event(&self) -> MessageEvent {
+        MessageEvent {
+            message_uuid: self.message_uuid,
+            timestamp_millis: self.timestamp_millis,
+            brokers: self.brokers.into(),
+            topic: self.topic.into(),
+            event_type: match self.event_type {
+                EventTypeRef::Sent => EventType::Sent,
+                EventTypeRef::SendFailed { reason } => EventType::SendFailed { reason },
+                EventTypeRef::TransactionCommitted => EventType::TransactionCommitted,
+                EventTypeRef::TransactionAborted => EventType::TransactionAborted,
+                EventTypeRef::KafkaTimestampSet {
+                    consumer_group,
+                    timestamp_type,
+                } => EventType::KafkaTimestampSet {
+                    consumer_group: consumer_group.into(),
+                    timestamp_type,
+                },
+                EventTypeRef::Received { consumer_group } => EventType::Received {
+                    consumer_group: consumer_group.into(),
+                },
+                EventTypeRef::Processed { consumer_group } => EventType::Processed {
+                    consumer_group: consumer_group.into(),
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(idx: u64) -> MessageEvent {
+        MessageEvent {
+            message_uuid: Uuid::from_u64_pair(0, idx),
+            timestamp_millis: idx as u128,
+            brokers: "localhost:9092".into(),
+            topic: format!("topic-{}", idx % 3),
+            event_type: EventType::Received {
+                consumer_group: "group".into(),
+            },
+        }
+    }
+
+    fn store(len: usize) -> EventStore {
+        let mut store = EventStore::default();
+        for idx in 0..len as u64 {
+            store.push(event(idx));
+        }
+        store
+    }
+
+    fn indexes<'a>(events: impl Iterator<Item = EventRef<'a>>) -> Vec<u64> {
+        events.map(|x| x.message_uuid.as_u64_pair().1).collect()
+    }
+
+    #[test]
+    fn push_spans_chunks() {
+        let store = store(2 * CHUNK_LEN + 10);
+
+        assert_eq!(store.len(), 2 * CHUNK_LEN + 10);
+        assert_eq!(store.chunks.len(), 3);
+        assert_eq!(
+            indexes(store.snapshot().iter()),
+            (0..2 * CHUNK_LEN as u64 + 10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn iter_from_crosses_chunk_boundary() {
+        let store = store(2 * CHUNK_LEN + 10);
+        let snapshot = store.snapshot();
+
+        for start in [
+            0,
+            1,
+            CHUNK_LEN - 1,
+            CHUNK_LEN,
+            CHUNK_LEN + 1,
+            2 * CHUNK_LEN + 9,
+        ] {
+            let expected: Vec<_> = (start as u64..2 * CHUNK_LEN as u64 + 10).collect();
+            assert_eq!(
+                indexes(snapshot.iter_from(start)),
+                expected,
+                "start {start}"
+            );
+        }
+
+        assert_eq!(snapshot.iter_from(2 * CHUNK_LEN + 10).count(), 0);
+        assert_eq!(snapshot.iter_from(3 * CHUNK_LEN).count(), 0);
+    }
+
+    #[test]
+    fn snapshot_does_not_see_later_events() {
+        let mut store = store(CHUNK_LEN - 1);
+        let snapshot = store.snapshot();
+
+        // Fills up the shared chunk and starts the next one
+        store.push(event(CHUNK_LEN as u64 - 1));
+        store.push(event(CHUNK_LEN as u64));
+
+        assert_eq!(snapshot.len(), CHUNK_LEN - 1);
+        assert_eq!(snapshot.iter().count(), CHUNK_LEN - 1);
+        assert_eq!(store.snapshot().iter().count(), CHUNK_LEN + 1);
+        assert_eq!(store.chunks[0].len(), CHUNK_LEN);
+    }
+
+    #[test]
+    fn to_event_restores_strings() {
+        let mut store = EventStore::default();
+        store.push(event(4));
+        store.push(MessageEvent {
+            event_type: EventType::KafkaTimestampSet {
+                consumer_group: "other-group".into(),
+                timestamp_type: KafkaTimestampType::LogAppendTime,
+            },
+            ..event(5)
+        });
+
+        let events: Vec<_> = store.snapshot().iter().map(|x| x.to_event()).collect();
+
+        assert_eq!(events[0].topic, "topic-1");
+        assert_eq!(events[0].brokers, "localhost:9092");
+        assert!(matches!(
+            &events[0].event_type,
+            EventType::Received { consumer_group } if consumer_group == "group"
+        ));
+        assert_eq!(events[1].topic, "topic-2");
+        assert_eq!(events[1].timestamp_millis, 5);
+        assert!(matches!(
+            &events[1].event_type,
+            EventType::KafkaTimestampSet { consumer_group, timestamp_type: KafkaTimestampType::LogAppendTime }
+                if consumer_group == "other-group"
+        ));
+    }
+}
@@ -3,8 +3,14 @@ use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+pub mod aggregates;
 pub mod consumers;
+pub mod correlation;
+pub mod events;
+pub mod memory;
 pub mod models;
+pub mod persistence;
+pub mod producers;
 pub mod routes;
 pub mod state;
 
@@ -24,8 +30,10 @@ use utoipa_swagger_ui::SwaggerUi;
 use uuid::Uuid;
 
 use crate::{
-    models::{Experiment, MessageEvent},
-    state::MessageMapping,
+    aggregates::AggregateStats,
+    events::EventsSnapshot,
+    models::{ChurnEvent, Experiment, RebalanceEvent},
+    state::MessagesState,
 };
 
 pub const MESSAGE_UUID_HEADER: &str = "x-message-uuid";
@@ -35,6 +43,13 @@ pub const DEFAULT_BROKERS_ENV: &str = "DEFAULT_BROKERS";
 pub const DEFAULT_TOPIC_ENV: &str = "DEFAULT_TOPIC";
 pub const HOST_ENV: &str = "HTTP_HOST";
 pub const APP_PORT_ENV: &str = "HTTP_PORT";
+pub const PRODUCER_IDLE_TIMEOUT_ENV: &str = "PRODUCER_IDLE_TIMEOUT";
+/// Directory the experiments are persisted into. Experiments are kept in memory only when unset
+pub const STATE_DIR_ENV: &str = "STATE_DIR";
+pub const STATE_FLUSH_INTERVAL_ENV: &str = "STATE_FLUSH_INTERVAL";
+/// Allocated memory (e.g. "2GiB") above which the limit policies of the experiments apply.
+/// Memory allocated by librdkafka is not counted
+pub const MEMORY_BUDGET_ENV: &str = "MEMORY_BUDGET";
 
 pub fn get_now_millis() -> u128 {
     std::time::SystemTime::now()
@@ -64,14 +79,32 @@ impl StopHandle {
 #[derive(Debug)]
 pub struct AppData {
     pub app_state: Mutex<crate::state::State>,
+    /// Same as the one of the app state, so the experiments are read without locking it
+    pub messages_state: MessagesState,
     pub should_tokio_finish: Arc<AtomicBool>,
     pub stop_handle: StopHandle,
 }
 
 impl AppData {
     pub fn new(should_tokio_finish: Arc<AtomicBool>) -> Self {
+        let producer_idle_timeout = std::env::var(PRODUCER_IDLE_TIMEOUT_ENV)
+            .ok()
+            .and_then(|x| humantime::parse_duration(&x).ok())
+            .unwrap_or(crate::state::DEFAULT_PRODUCER_IDLE_TIMEOUT);
+
+        let budget = std::env::var(MEMORY_BUDGET_ENV)
+            .ok()
+            .and_then(|x| str::parse::<bytesize::ByteSize>(&x).ok())
+            .map(|x| x.as_u64() as usize);
+
+        let app_state = crate::state::State::new(
+            producer_idle_timeout,
+            crate::memory::MemoryBudget::new(budget),
+        );
+
         AppData {
-            app_state: Mutex::new(crate::state::State::new()),
+            messages_state: app_state.messages_state.clone(),
+            app_state: Mutex::new(app_state),
             stop_handle: StopHandle::default(),
             should_tokio_finish,
         }
@@ -79,48 +112,42 @@ impl AppData {
 }
 
 impl AppData {
-    pub async fn experiment_related_data(
+    /// Experiment with the snapshot of its events. The experiment is locked only to take them
+    pub fn experiment_related_data(
         &self,
         experiment_uuid: &Uuid,
-    ) -> Option<(Experiment, MessageMapping, Vec<MessageEvent>)> {
-        let experiment = {
-            self.app_state
-                .lock()
-                .await
-                .messages_state
-                .lock()
-                .await
-                .experiments
-                .get(experiment_uuid)
-                .cloned()
-        }?;
-
-        let events: Vec<MessageEvent> = {
-            self.app_state
-                .lock()
-                .await
-                .messages_state
-                .lock()
-                .await
-                .events
-                .get(experiment_uuid)
-                .cloned()
-                .unwrap_or_default()
-        };
-
-        let messages = self
-            .app_state
-            .lock()
-            .await
-            .messages_state
-            .lock()
-            .await
-            .messages
+    ) -> Option<(Experiment, EventsSnapshot)> {
+        let shard = self.messages_state.get(experiment_uuid)?;
+        let state = shard.lock();
+
+        Some((state.experiment.clone(), state.events.snapshot()))
+    }
+
+    pub fn experiment_rebalances(&self, experiment_uuid: &Uuid) -> Vec<RebalanceEvent> {
+        self.messages_state
             .get(experiment_uuid)
-            .cloned()
-            .unwrap_or_default();
+            .map(|x| x.lock().rebalances.clone())
+            .unwrap_or_default()
+    }
 
-        Some((experiment, messages, events))
+    pub fn experiment_churns(&self, experiment_uuid: &Uuid) -> Vec<ChurnEvent> {
+        self.messages_state
+            .get(experiment_uuid)
+            .map(|x| x.lock().churns.clone())
+            .unwrap_or_default()
+    }
+
+    /// Result of `f` on the statistics of the experiment with the aggregated storage. The
+    /// experiment is locked meanwhile, so `f` should only merge the few matching statistics
+    pub fn with_aggregates<R>(
+        &self,
+        experiment_uuid: &Uuid,
+        f: impl FnOnce(&AggregateStats) -> R,
+    ) -> Option<R> {
+        let shard = self.messages_state.get(experiment_uuid)?;
+        let state = shard.lock();
+
+        state.aggregator.as_ref().map(|x| f(x.stats()))
     }
 }
 
@@ -145,6 +172,32 @@ async fn main_async(should_tokio_finish: Arc<AtomicBool>) -> std::io::Result<()>
         .and_then(|x| str::parse(&x).ok())
         .unwrap_or(8080);
 
+    let producers = app_data.app_state.lock().await.producers.clone();
+    tokio::spawn(producers.evict_idle_loop());
+
+    let persistence = if let Ok(state_dir) = std::env::var(STATE_DIR_ENV) {
+        let flush_interval = std::env::var(STATE_FLUSH_INTERVAL_ENV)
+            .ok()
+            .and_then(|x| humantime::parse_duration(&x).ok())
+            .unwrap_or(persistence::DEFAULT_FLUSH_INTERVAL);
+
+        let persistence = persistence::Persistence::open(state_dir.as_ref(), flush_interval)
+            .map_err(std::io::Error::other)?;
+        let persisted = persistence.load().await.map_err(std::io::Error::other)?;
+
+        let mut app_state = app_data.app_state.lock().await;
+        app_state.resume(persisted).await;
+        let messages_state = app_state.messages_state.clone();
+        tokio::spawn(persistence.clone().flush_loop(messages_state));
+        app_state.persistence = Some(persistence.clone());
+        Some(persistence)
+    } else {
+        None
+    };
+
+    // Evicted experiments are archived, so the persistence has to be set up beforehand
+    tokio::spawn(memory::budget_loop(app_data.clone()));
+
     let app_data_clone = app_data.clone();
     let srv = HttpServer::new(move || {
         let (app, mut api) = App::new()
@@ -155,28 +208,47 @@ async fn main_async(should_tokio_finish: Arc<AtomicBool>) -> std::io::Result<()>
             .service(
                 scope::scope("/runtime")
                     .service(routes::restart_runtime)
-                    .service(routes::stop_runtime),
+                    .service(routes::stop_runtime)
+                    .service(routes::memory_usage),
             )
             .service(
                 scope::scope("/experiment")
                     .service(routes::experiment::begin)
                     .service(routes::experiment::end)
+                    .service(routes::experiment::stop)
+                    .service(routes::experiment::archive)
                     .service(routes::experiment::reset)
                     .service(routes::experiment::restore)
+                    .service(routes::experiment::add_listener)
+                    .service(routes::experiment::remove_listener)
+                    .service(routes::experiment::add_route)
                     .service(routes::experiment::get_insights)
                     .service(routes::experiment::get_config)
+                    .service(routes::experiment::get_lag)
                     .service(routes::experiment::list_experiments),
             )
             .service(
                 scope::scope("/message")
                     .service(routes::messages::send)
-                    .service(routes::messages::send_job),
+                    .service(routes::messages::send_job)
+                    .service(routes::messages::job_status),
             )
+            .service(scope::scope("/producers").service(routes::producers::list_producers))
             .service(
                 scope::scope("/measurements")
                     .service(routes::measurements::kafka_latencies)
+                    .service(routes::measurements::kafka_latencies_histogram)
+                    .service(routes::measurements::kafka_timestamp_latencies)
+                    .service(routes::measurements::kafka_timestamp_histogram)
                     .service(routes::measurements::send_receive_latencies)
-                    .service(routes::measurements::messaged_bytes_size),
+                    .service(routes::measurements::send_receive_histogram)
+                    .service(routes::measurements::send_receive_timeline)
+                    .service(routes::measurements::churn_periods)
+                    .service(routes::measurements::delivery)
+                    .service(routes::measurements::messaged_bytes_size)
+                    .service(routes::measurements::messaged_bytes_size_histogram)
+                    .service(routes::measurements::timestamp_preservation)
+                    .service(routes::measurements::transactions_verification),
             )
             .split_for_parts();
 
@@ -205,7 +277,17 @@ async fn main_async(should_tokio_finish: Arc<AtomicBool>) -> std::io::Result<()>
             .description(Some("Endpoints to retrive statistical data from experiments events"))
             .build();
 
-        api.tags = Some(vec![experiments_tag, messages_tag, measure_tag]);
+        let producers_tag = TagBuilder::new()
+            .name("producers")
+            .description(Some("Endpoints to inspect the pool of kafka producers"))
+            .build();
+
+        api.tags = Some(vec![
+            experiments_tag,
+            messages_tag,
+            measure_tag,
+            producers_tag,
+        ]);
 
         app.service(SwaggerUi::new("/docs/{_:.*}").url("/api-docs/openapi.json", api))
             .service(web::redirect("/docs", "/docs/"))
@@ -215,5 +297,12 @@ async fn main_async(should_tokio_finish: Arc<AtomicBool>) -> std::io::Result<()>
 
     app_data.stop_handle.register(srv.handle());
 
-    srv.await
+    srv.await?;
+
+    // Runtime may be restarted, which reloads the persisted state
+    if let Some(persistence) = persistence {
+        persistence.flush(&app_data.messages_state).await;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,153 @@
+use crate::AppData;
+use crate::models::LimitPolicy;
+use actix_web::web;
+use std::ffi::{CStr, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Memory usage is compared with the budget that often
+pub const BUDGET_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Statistics of the global allocator in bytes. Only the Rust heap is served by it, memory that
+/// librdkafka allocates with the C malloc is not included
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorStats {
+    pub allocated: usize,
+    pub active: usize,
+    pub resident: usize,
+}
+
+impl AllocatorStats {
+    /// Reads the current statistics. Jemalloc caches them until the epoch is advanced
+    pub fn read() -> Option<Self> {
+        let mut epoch: u64 = 1;
+        let mut len = std::mem::size_of::<u64>();
+        // SAFETY: the epoch is u64 and both the lengths match it
+        let code = unsafe {
+            jemalloc_sys::mallctl(
+                c"epoch".as_ptr(),
+                &mut epoch as *mut u64 as *mut c_void,
+                &mut len,
+                &mut epoch as *mut u64 as *mut c_void,
+                len,
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+
+        Some(Self {
+            allocated: stat(c"stats.allocated")?,
+            active: stat(c"stats.active")?,
+            resident: stat(c"stats.resident")?,
+        })
+    }
+}
+
+fn stat(name: &CStr) -> Option<usize> {
+    let mut value: usize = 0;
+    let mut len = std::mem::size_of::<usize>();
+    // SAFETY: the statistics are size_t and the length matches it
+    let code = unsafe {
+        jemalloc_sys::mallctl(
+            name.as_ptr(),
+            &mut value as *mut usize as *mut c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+
+    (code == 0).then_some(value)
+}
+
+/// Memory the emitter may allocate before the limit policies of the experiments apply
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    pub bytes: Option<usize>,
+    exceeded: AtomicBool,
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: Option<usize>) -> Self {
+        Self {
+            bytes,
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+/// Periodically compares the allocated memory with the budget. Evicts the oldest stopped
+/// experiment while over the budget, if any running experiment has asked for it. Evicted
+/// experiment is archived when the state directory is set and deleted otherwise. The app state
+/// is locked only for the eviction, so its listeners are the current ones
+pub async fn budget_loop(data: web::Data<AppData>) {
+    let budget = data.messages_state.budget();
+    let Some(bytes) = budget.bytes else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(BUDGET_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let Some(stats) = AllocatorStats::read() else {
+            warn!("Allocator statistics are not available");
+            continue;
+        };
+
+        let exceeded = stats.allocated > bytes;
+        if budget.exceeded.swap(exceeded, Ordering::Relaxed) != exceeded {
+            match exceeded {
+                true => warn!(
+                    "Memory budget exceeded: {} bytes allocated",
+                    stats.allocated
+                ),
+                false => info!("Memory usage is back under the budget"),
+            }
+        }
+
+        if !exceeded {
+            continue;
+        }
+
+        let mut evict = false;
+        let mut oldest_ended = None;
+
+        for (uuid, shard) in data.messages_state.shards() {
+            let experiment = &shard.lock().experiment;
+
+            match experiment.experiment_end_timestamp_millis {
+                Some(ended) => {
+                    if oldest_ended.is_none_or(|(_, oldest)| ended < oldest) {
+                        oldest_ended = Some((uuid, ended));
+                    }
+                }
+                None => evict |= matches!(experiment.limits.policy, LimitPolicy::EvictFinished),
+            }
+        }
+
+        if evict && let Some((uuid, _)) = oldest_ended {
+            info!(
+                "Evicting stopped experiment {} to get under the memory budget",
+                uuid
+            );
+
+            let mut state = data.app_state.lock().await;
+            let evicted = match state.persistence {
+                Some(_) => state.archive_experiment(uuid).await.map(|_| ()),
+                None => state.delete_experiment(uuid).await.map(|_| ()),
+            };
+
+            if let Err(e) = evicted {
+                warn!("Could not evict experiment {}: {}", uuid, e);
+            }
+        }
+    }
+}
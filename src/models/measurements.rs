@@ -2,13 +2,24 @@ use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToResponse, ToSchema};
 use uuid::Uuid;
 
+use crate::models::{ChurnEvent, ChurnType, IsolationLevel, KafkaTimestampType, RebalanceEvent};
+
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
 pub struct SendReceiveLatencyRequestBrokerSource {
     #[schema(examples(crate::models::default_brokers))]
     pub brokers: String,
 
+    /// Other bootstrap strings of the same cluster, e.g. with a different host alias
+    #[serde(default)]
+    pub broker_aliases: Vec<String>,
+
     #[schema(examples(crate::models::default_topic))]
     pub topic: String,
+
+    /// Match the events of all the topics matching the `topic` regex
+    #[serde(default)]
+    #[schema(examples(false))]
+    pub topic_pattern: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
@@ -16,25 +27,126 @@ pub struct KafkaLatencyRequestBroker {
     #[schema(examples(crate::models::default_brokers))]
     pub brokers: String,
 
+    /// Other bootstrap strings of the same cluster, e.g. with a different host alias
+    #[serde(default)]
+    pub broker_aliases: Vec<String>,
+
     #[schema(examples(crate::models::default_topic))]
     pub topic: String,
 
+    /// Match the events of all the topics matching the `topic` regex
+    #[serde(default)]
+    #[schema(examples(false))]
+    pub topic_pattern: bool,
+
     #[schema(examples("default-consumer-group"))]
     pub consumer_group: String,
 }
 
+/// Named mapping of the source (cluster, topic) to the dest (cluster, topic), e.g. of the
+/// replicated topic
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ExperimentRoute {
+    #[schema(examples("dc1-to-dc2"))]
+    pub name: String,
+    pub source: KafkaLatencyRequestBroker,
+    pub dest: KafkaLatencyRequestBroker,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct AddRoute {
+    pub experiment_uuid: Uuid,
+    pub route: ExperimentRoute,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
 pub struct KafkaLatencyRequest {
     pub experiment_uuid: Uuid,
-    pub source: KafkaLatencyRequestBroker,
-    pub dest: KafkaLatencyRequestBroker,
+
+    /// Name of the experiment route providing the source and dest. Explicit source and dest
+    /// take precedence
+    #[serde(default)]
+    #[schema(examples("dc1-to-dc2"))]
+    pub route: Option<String>,
+
+    #[serde(default)]
+    pub source: Option<KafkaLatencyRequestBroker>,
+
+    #[serde(default)]
+    pub dest: Option<KafkaLatencyRequestBroker>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
+pub struct KafkaTimestampLatencyRequest {
+    pub experiment_uuid: Uuid,
+
+    /// Name of the experiment route providing the source and dest. Explicit source and dest
+    /// take precedence
+    #[serde(default)]
+    #[schema(examples("dc1-to-dc2"))]
+    pub route: Option<String>,
+
+    #[serde(default)]
+    pub source: Option<KafkaLatencyRequestBroker>,
+
+    #[serde(default)]
+    pub dest: Option<KafkaLatencyRequestBroker>,
+
+    /// Type of the kafka timestamp taken from the source. Any type is accepted when not set
+    #[serde(default)]
+    pub source_timestamp_type: Option<KafkaTimestampType>,
+
+    /// Type of the kafka timestamp taken from the dest. Any type is accepted when not set
+    #[serde(default)]
+    pub dest_timestamp_type: Option<KafkaTimestampType>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
+pub struct TimestampPreservationRequest {
+    pub experiment_uuid: Uuid,
+
+    /// Name of the experiment route providing the source and dest. Explicit source and dest
+    /// take precedence
+    #[serde(default)]
+    #[schema(examples("dc1-to-dc2"))]
+    pub route: Option<String>,
+
+    #[serde(default)]
+    pub source: Option<KafkaLatencyRequestBroker>,
+
+    #[serde(default)]
+    pub dest: Option<KafkaLatencyRequestBroker>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
 pub struct SendReceiveLatencyRequest {
     pub experiment_uuid: Uuid,
-    pub source: SendReceiveLatencyRequestBrokerSource,
-    pub dest: KafkaLatencyRequestBroker,
+
+    /// Name of the experiment route providing the source and dest. Explicit source and dest
+    /// take precedence
+    #[serde(default)]
+    #[schema(examples("dc1-to-dc2"))]
+    pub route: Option<String>,
+
+    #[serde(default)]
+    pub source: Option<SendReceiveLatencyRequestBrokerSource>,
+
+    #[serde(default)]
+    pub dest: Option<KafkaLatencyRequestBroker>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
+pub struct TransactionVerificationRequest {
+    pub experiment_uuid: Uuid,
+
+    /// Name of the experiment route which dest listener is verified
+    #[serde(default)]
+    #[schema(examples("dc1-to-dc2"))]
+    pub route: Option<String>,
+
+    /// Experiment listener to be verified. Takes precedence over the route
+    #[serde(default)]
+    pub dest: Option<KafkaLatencyRequestBroker>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
@@ -51,6 +163,49 @@ pub struct StatsLatencies {
     pub latencies_ms: MinMaxAvg,
 }
 
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct LatencyPoint {
+    pub timestamp_millis: u128,
+    pub latency_ms: u128,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct LatencyTimeline {
+    pub points: Vec<LatencyPoint>,
+    pub rebalances: Vec<RebalanceEvent>,
+    pub churns: Vec<ChurnEvent>,
+}
+
+/// Messages received by the dest listener between two churns of the listener
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct ChurnPeriodStats {
+    pub start_millis: u128,
+    /// Not set for the period after the last churn
+    pub end_millis: Option<u128>,
+    /// Churn starting the period. Not set for the period before the first churn
+    pub churn: Option<ChurnType>,
+    pub received_messages: usize,
+    /// Messages that had been already received before
+    pub duplicated_messages: usize,
+    /// Send/receive latencies. Not set if no message has been received
+    pub latencies_ms: Option<MinMaxAvg>,
+}
+
+/// Messages sent to the source and received by the dest listener
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct DeliveryStats {
+    pub sent_messages: usize,
+    /// Distinct messages received
+    pub received_messages: usize,
+    /// Messages that had been already received before
+    pub duplicated_messages: usize,
+    /// Messages not received. For the aggregated experiments only the messages that have left
+    /// the pending window are counted
+    pub lost_messages: usize,
+    /// Messages still awaiting receipt. Tracked only for the aggregated experiments
+    pub pending_messages: usize,
+}
+
 #[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
 pub struct MinMaxAvg {
     pub min: u128,
@@ -58,6 +213,65 @@ pub struct MinMaxAvg {
     pub avg: u128,
 }
 
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct Percentile {
+    #[schema(examples(99.0))]
+    pub percentile: f64,
+    pub value: u128,
+}
+
+/// Recorded values with the same value rounded to the histogram precision
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct HistogramBucket {
+    pub value: u128,
+    pub count: u64,
+}
+
+/// Distribution of the values. Values are exact up to 2048 and keep 11 significant bits above
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct Distribution {
+    pub count: u64,
+    /// Not set if no value has been recorded
+    pub min_max_avg: Option<MinMaxAvg>,
+    /// 50th, 90th, 99th and 99.9th percentiles
+    pub percentiles: Vec<Percentile>,
+    /// Non-empty buckets in the ascending order
+    pub buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct TimestampPreservationStats {
+    /// Number of messages with the source CreateTime and any dest kafka timestamp
+    pub compared_messages: usize,
+
+    /// Messages which dest kafka timestamp equals the source CreateTime
+    pub copied_timestamps: usize,
+
+    /// Messages which dest kafka timestamp differs from the source CreateTime
+    pub restamped_timestamps: usize,
+
+    /// True if every compared message had its timestamp copied rather than re-stamped
+    pub timestamps_copied: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct TransactionVerification {
+    pub isolation_level: IsolationLevel,
+
+    /// Messages sent in the committed transactions
+    pub committed_messages: usize,
+
+    /// Messages sent in the aborted transactions
+    pub aborted_messages: usize,
+
+    pub committed_received: usize,
+    pub aborted_received: usize,
+
+    /// True if every committed message was received, and no aborted message was received by
+    /// read committed listener, or all of them were received by read uncommitted listener
+    pub verified: bool,
+}
+
 #[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
 pub struct TotalAvg {
     pub total: u128,
@@ -1,5 +1,7 @@
 pub mod measurements;
 
+use std::collections::{BTreeMap, BTreeSet};
+
 use serde::{Deserialize, Serialize};
 use utoipa::{
     IntoParams, PartialSchema, ToResponse, ToSchema,
@@ -32,7 +34,7 @@ impl From<bytesize::ByteSize> for ByteSize {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct Duration(#[serde(with = "humantime_serde")] pub std::time::Duration);
 
 impl PartialSchema for ByteSize {
@@ -66,7 +68,7 @@ impl PartialSchema for Duration {
 impl ToSchema for ByteSize {}
 impl ToSchema for Duration {}
 
-#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq)]
 pub struct KafkaBrokerCfg {
     #[schema(examples(default_brokers))]
     pub brokers: String,
@@ -74,12 +76,213 @@ pub struct KafkaBrokerCfg {
     #[schema(examples(default_topic))]
     pub topic: String,
 
+    /// Subscribe to all the topics matching the `topic` POSIX regex, e.g. `^dc[0-9]+\.orders$`.
+    /// Events record the actual topic of the message
+    #[serde(default)]
+    #[schema(examples(false))]
+    pub topic_pattern: bool,
+
     #[schema(examples(false))]
     pub ssl: bool,
     pub message_timeout: Duration,
 
     #[schema(examples("default-consumer-group"))]
     pub consumer_group_id: String,
+
+    /// Kafka default (read committed) is used when not set
+    #[serde(default)]
+    pub isolation_level: Option<IsolationLevel>,
+
+    #[serde(default)]
+    pub commit: CommitStrategy,
+
+    /// Extractors of the message and experiment uuids, tried in order. Headers of the experiment
+    /// are tried last
+    #[serde(default)]
+    pub correlation: Vec<CorrelationExtractor>,
+
+    /// Periodically restart or add consumer instances of the listener to cause rebalances.
+    /// Listeners with churn are never shared, so their consumer group is not available to the
+    /// other experiments while they run
+    #[serde(default)]
+    pub churn: Option<ChurnCfg>,
+
+    /// Consumers started in the group for the listener. Received events of all of them are
+    /// recorded into the experiment
+    #[serde(default = "default_parallelism")]
+    #[schema(examples(1))]
+    pub parallelism: usize,
+
+    /// Simulated processing of every consumed message. The listener consumes nothing else
+    /// meanwhile, so the lag builds up
+    #[serde(default)]
+    pub processing: Option<ProcessingDelay>,
+}
+
+fn default_parallelism() -> usize {
+    1
+}
+
+/// Delay applied to the consumed message after its receipt has been recorded
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq, Eq, Hash)]
+pub enum ProcessingDelay {
+    Fixed {
+        delay: Duration,
+    },
+    /// Uniformly distributed delay within the bounds
+    Random {
+        min: Duration,
+        max: Duration,
+    },
+    /// Listener stalls for the duration once the interval since the previous stall has passed.
+    /// Messages in between are processed without delay
+    Stalls {
+        interval: Duration,
+        stall: Duration,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq)]
+pub struct ChurnCfg {
+    pub schedule: ChurnSchedule,
+    pub action: ChurnAction,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq)]
+pub enum ChurnSchedule {
+    Every {
+        interval: Duration,
+    },
+    /// Random delay within the bounds before every churn
+    Random {
+        min: Duration,
+        max: Duration,
+    },
+}
+
+impl ChurnCfg {
+    /// Churn without any delay would restart the consumers in a busy loop
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let delay = match &self.schedule {
+            ChurnSchedule::Every { interval } => interval.0,
+            ChurnSchedule::Random { min, max } => min.0.max(max.0),
+        };
+
+        if delay.is_zero() {
+            return Err("churn schedule must have a non zero delay");
+        }
+
+        if let ChurnAction::AddRemove { hold } = &self.action
+            && hold.0.is_zero()
+        {
+            return Err("churn hold must be non zero");
+        }
+
+        Ok(())
+    }
+}
+
+impl ChurnSchedule {
+    pub fn next_delay(&self) -> std::time::Duration {
+        match self {
+            Self::Every { interval } => interval.0,
+            Self::Random { min, max } if max.0 > min.0 => {
+                rand::Rng::random_range(&mut rand::rng(), min.0..=max.0)
+            }
+            Self::Random { min, .. } => min.0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq)]
+pub enum ChurnAction {
+    /// Replace the consumer instance with a new one. Messages not committed by the old instance
+    /// are consumed again or skipped, depending on the commit strategy
+    Restart,
+    /// Add consumer instance to the group and remove it after the hold time
+    AddRemove { hold: Duration },
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChurnType {
+    InstanceRestarted,
+    InstanceAdded,
+    InstanceRemoved,
+}
+
+/// Consumer instance change injected into the experiment listener
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct ChurnEvent {
+    pub timestamp_millis: u128,
+    pub brokers: String,
+    pub topic: String,
+    pub consumer_group: String,
+    pub churn_type: ChurnType,
+}
+
+/// Where the listener finds the uuids of the consumed message. Experiment uuid is optional:
+/// messages sent by the emitter are matched with their experiment by the message uuid
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq, Eq, Hash)]
+pub enum CorrelationExtractor {
+    /// Headers with the provided names
+    Headers {
+        #[schema(examples("x-message-uuid"))]
+        message_uuid: String,
+        #[schema(examples("x-experiment-uuid"))]
+        experiment_uuid: Option<String>,
+    },
+    /// Regex matching the message key with `message_uuid` and optional `experiment_uuid` named
+    /// groups
+    Key {
+        #[schema(examples(
+            "^(?P<experiment_uuid>[0-9a-f-]{36}):(?P<message_uuid>[0-9a-f-]{36})$"
+        ))]
+        pattern: String,
+    },
+    /// JSON pointers into the payload
+    JsonPointer {
+        #[schema(examples("/message_uuid"))]
+        message_uuid: String,
+        #[schema(examples("/experiment_uuid"))]
+        experiment_uuid: Option<String>,
+    },
+    /// Payload byte offsets of the uuids in their textual (36 bytes) form
+    ByteOffset {
+        #[schema(examples(0))]
+        message_uuid: usize,
+        #[schema(examples(36))]
+        experiment_uuid: Option<usize>,
+    },
+}
+
+/// How the listener commits offsets of the consumed messages
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, Default, PartialEq)]
+pub enum CommitStrategy {
+    /// Offsets are never committed. Listener joins an ephemeral group named after the
+    /// configured one, so it consumes every partition from the latest offsets
+    #[default]
+    None,
+    /// Offsets of the received messages are committed by kafka client with the interval
+    Auto { interval: Duration },
+    /// Offsets are committed with the interval, only of the messages which events have been
+    /// recorded and which processing is over
+    Manual { interval: Duration },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, ToSchema, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    #[default]
+    ReadCommitted,
+    ReadUncommitted,
+}
+
+impl IsolationLevel {
+    pub fn as_kafka_config(&self) -> &'static str {
+        match self {
+            Self::ReadCommitted => "read_committed",
+            Self::ReadUncommitted => "read_uncommitted",
+        }
+    }
 }
 
 fn default_body_size() -> ByteSize {
@@ -97,12 +300,180 @@ fn default_timeout() -> Duration {
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct NewExperiment {
     pub listeners: Vec<KafkaBrokerCfg>,
+
+    #[serde(default)]
+    pub headers: CorrelationHeaders,
+
+    #[serde(default)]
+    pub routes: Vec<measurements::ExperimentRoute>,
+
+    #[serde(default)]
+    pub storage: StorageMode,
+
+    #[serde(default)]
+    pub limits: ExperimentLimits,
+}
+
+/// Bounds of the data recorded for the experiment
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, Default)]
+pub struct ExperimentLimits {
+    /// Events stored for the experiment at most. Unlimited when not set
+    #[serde(default)]
+    #[schema(examples(1_000_000))]
+    pub max_events: Option<usize>,
+
+    /// Applied once the experiment has stored the max events or the memory budget of the
+    /// emitter is exceeded
+    #[serde(default)]
+    pub policy: LimitPolicy,
+}
+
+/// What happens to the experiment once its limit is reached
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, Default)]
+pub enum LimitPolicy {
+    /// New messages are not sent. Receipts of the sent ones are still recorded
+    #[default]
+    RejectSends,
+    /// Messages are sent, but neither they nor their receipts are recorded anymore
+    StopRecording,
+    /// Only the provided fraction of the new messages is recorded, with all their events
+    Sample {
+        #[schema(examples(0.1))]
+        ratio: f64,
+    },
+    /// Oldest experiments ended by `/experiment/stop` are removed until the memory usage gets
+    /// under the budget. Recording stops once the experiment itself reaches the max events
+    EvictFinished,
+}
+
+impl ExperimentLimits {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if let LimitPolicy::Sample { ratio } = self.policy
+            && !(0.0..=1.0).contains(&ratio)
+        {
+            return Err("sample ratio must be between 0 and 1");
+        }
+
+        Ok(())
+    }
+}
+
+fn default_pending_window() -> usize {
+    100_000
+}
+
+/// How the measured data of the experiment is kept
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, Default)]
+pub enum StorageMode {
+    /// Every message and its events are kept, so any endpoints can be measured afterwards
+    #[default]
+    Events,
+    /// Events only update the statistics of the endpoints and are discarded. Memory does not
+    /// grow with the number of messages. Persisted statistics do not include the messages
+    /// awaiting receipt, which are counted neither as received nor as lost after a restart
+    Aggregated {
+        /// Messages awaiting receipt that are tracked at most. Messages leaving the window are
+        /// counted as lost by the listeners that have not received them
+        #[serde(default = "default_pending_window")]
+        #[schema(examples(default_pending_window))]
+        pending_window: usize,
+    },
+}
+
+fn default_message_uuid_header() -> String {
+    crate::MESSAGE_UUID_HEADER.into()
+}
+
+fn default_experiment_uuid_header() -> String {
+    crate::EXPERIMENT_UUID_HEADER.into()
+}
+
+/// Names and encoding of the headers carrying the message and experiment uuids
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq, Eq, Hash)]
+pub struct CorrelationHeaders {
+    #[serde(default = "default_message_uuid_header")]
+    #[schema(examples(default_message_uuid_header))]
+    pub message_uuid: String,
+
+    #[serde(default = "default_experiment_uuid_header")]
+    #[schema(examples(default_experiment_uuid_header))]
+    pub experiment_uuid: String,
+
+    /// Send the uuids as 16 raw bytes instead of 36 characters. Listeners accept both
+    #[serde(default)]
+    #[schema(examples(false))]
+    pub binary: bool,
+}
+
+impl Default for CorrelationHeaders {
+    fn default() -> Self {
+        Self {
+            message_uuid: default_message_uuid_header(),
+            experiment_uuid: default_experiment_uuid_header(),
+            binary: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq, Eq)]
+pub struct PartitionOffset {
+    #[schema(examples(default_topic))]
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Position the restored experiment listeners start consuming from
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, Default)]
+pub enum RestoreFrom {
+    /// Consume from the earliest offset. Doesn't work if the group has committed offsets
+    #[default]
+    Earliest,
+    /// Start from the timestamp of the experiment start
+    ExperimentStart,
+    /// Start from the first message with the kafka timestamp equal or greater than the provided
+    /// one. Partitions without such message start from their end. Partitions are looked up when
+    /// restoring, so the partitions created later start like with `Earliest`
+    Timestamp { timestamp_millis: i64 },
+    /// Start from the explicit offsets. Partitions not listed start like with `Earliest`, so
+    /// from the committed offset of the group if there is any
+    Offsets { offsets: Vec<PartitionOffset> },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct RestoreExperiment {
     pub experiment_uuid: Uuid,
     pub listeners: Vec<KafkaBrokerCfg>,
+
+    #[serde(default)]
+    pub from: RestoreFrom,
+
+    /// Consume with a fresh consumer group id, so the committed offsets are not used. Events
+    /// are still recorded with the configured consumer group id
+    #[serde(default)]
+    #[schema(examples(true))]
+    pub ephemeral_group: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct AddListener {
+    pub experiment_uuid: Uuid,
+    pub listener: KafkaBrokerCfg,
+}
+
+/// Listener is identified by its brokers, topic and consumer group
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct RemoveListener {
+    pub experiment_uuid: Uuid,
+
+    #[schema(examples(default_brokers))]
+    pub brokers: String,
+
+    #[schema(examples(default_topic))]
+    pub topic: String,
+
+    #[schema(examples("default-consumer-group"))]
+    pub consumer_group_id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
@@ -139,11 +510,90 @@ pub struct EndResponse {
     pub experiment_uuid: Uuid,
 }
 
+/// Stage of the experiment. Experiments move only forward unless restored
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema, Default)]
+pub enum ExperimentLifecycle {
+    /// Listeners are running and new messages can be sent
+    #[default]
+    Running,
+    /// Listeners and jobs are stopped. Data is kept in memory for the analysis
+    Stopped,
+    /// Data has been moved to the persistent storage and is no longer in memory
+    Archived,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToResponse, ToSchema)]
+pub struct ExperimentSummary {
+    pub experiment_uuid: Uuid,
+    pub lifecycle: ExperimentLifecycle,
+    pub experiment_start_timestamp_millis: u128,
+    pub experiment_end_timestamp_millis: Option<u128>,
+}
+
+impl From<&Experiment> for ExperimentSummary {
+    fn from(experiment: &Experiment) -> Self {
+        Self {
+            experiment_uuid: experiment.uuid,
+            lifecycle: experiment.lifecycle,
+            experiment_start_timestamp_millis: experiment.experiment_start_timestamp_millis,
+            experiment_end_timestamp_millis: experiment.experiment_end_timestamp_millis,
+        }
+    }
+}
+
+/// Kind of the timestamp attached by kafka to the consumed message
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KafkaTimestampType {
+    /// Timestamp set by the producer (or copied by the replicator)
+    CreateTime,
+    /// Timestamp set by the broker when appending message to the log
+    LogAppendTime,
+}
+
+/// Reason of the message delivery failure reported by the producer
+#[derive(
+    Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum DeliveryFailureReason {
+    MessageTimedOut,
+    QueueFull,
+    UnknownTopic,
+    AuthorizationFailed,
+    AuthenticationFailed,
+    RecordTooLarge,
+    BrokerUnavailable,
+    Canceled,
+    /// Experiment was removed before the delivery has been reported
+    ExperimentNotFound,
+    /// Transaction could not be initialized or started
+    TransactionFailed,
+    /// Experiment has reached its limit and rejects new messages
+    LimitReached,
+    /// Sending task panicked or has been aborted
+    Internal,
+    Other,
+}
+
 #[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
 pub enum EventType {
     Sent,
-    KafkaTimestampSet { consumer_group: String },
-    Received { consumer_group: String },
+    SendFailed {
+        reason: DeliveryFailureReason,
+    },
+    TransactionCommitted,
+    TransactionAborted,
+    KafkaTimestampSet {
+        consumer_group: String,
+        timestamp_type: KafkaTimestampType,
+    },
+    Received {
+        consumer_group: String,
+    },
+    /// Listener has finished the simulated processing of the message. Recorded only for
+    /// listeners with the processing delay
+    Processed {
+        consumer_group: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
@@ -160,16 +610,33 @@ pub struct Experiment {
     pub uuid: Uuid,
     pub consumers: Vec<KafkaBrokerCfg>,
     pub experiment_start_timestamp_millis: u128,
+    /// Set once the experiment is stopped
     pub experiment_end_timestamp_millis: Option<u128>,
+    #[serde(default)]
+    pub lifecycle: ExperimentLifecycle,
+    #[serde(default)]
+    pub headers: CorrelationHeaders,
+    /// Named source to dest mappings the measurements can be requested by
+    #[serde(default)]
+    pub routes: Vec<measurements::ExperimentRoute>,
+    #[serde(default)]
+    pub storage: StorageMode,
+    #[serde(default)]
+    pub limits: ExperimentLimits,
 }
 
 impl Experiment {
-    pub fn new(uuid: Uuid, consumers: Vec<KafkaBrokerCfg>) -> Self {
+    pub fn new(uuid: Uuid, consumers: Vec<KafkaBrokerCfg>, headers: CorrelationHeaders) -> Self {
         Self {
             uuid,
             consumers,
             experiment_start_timestamp_millis: get_now_millis(),
             experiment_end_timestamp_millis: None,
+            lifecycle: ExperimentLifecycle::Running,
+            headers,
+            routes: Vec::new(),
+            storage: StorageMode::Events,
+            limits: ExperimentLimits::default(),
         }
     }
 }
@@ -190,6 +657,50 @@ pub struct SentMessage {
     pub total_sent_bytes: usize,
     pub total_sent_bytes_human_readable: ByteSize,
     pub delivery_failures: usize,
+    /// Number of delivery failures per reason
+    pub delivery_failure_reasons: BTreeMap<DeliveryFailureReason, usize>,
+    pub backpressure: BackpressureStats,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct JobScheduled {
+    pub job_uuid: Uuid,
+    pub experiment_uuid: Uuid,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct JobStatus {
+    pub job_uuid: Uuid,
+    pub experiment_uuid: Uuid,
+    /// Total messages to be sent
+    pub messages_number: usize,
+    /// Messages passed to the producer so far
+    pub scheduled_messages: usize,
+    pub delivered_messages: usize,
+    pub delivery_failures: usize,
+    /// Number of delivery failures per reason
+    pub delivery_failure_reasons: BTreeMap<DeliveryFailureReason, usize>,
+    pub backpressure: BackpressureStats,
+    pub finished: bool,
+    /// Experiment was stopped before all the messages were scheduled
+    pub canceled: bool,
+}
+
+impl JobStatus {
+    pub fn new(job_uuid: Uuid, experiment_uuid: Uuid, messages_number: usize) -> Self {
+        Self {
+            job_uuid,
+            experiment_uuid,
+            messages_number,
+            scheduled_messages: 0,
+            delivered_messages: 0,
+            delivery_failures: 0,
+            delivery_failure_reasons: BTreeMap::new(),
+            backpressure: BackpressureStats::default(),
+            finished: false,
+            canceled: false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
@@ -197,19 +708,312 @@ pub struct Insights {
     pub messages: Vec<Message>,
     pub experiment: Experiment,
     pub events: Vec<MessageEvent>,
+    pub rebalances: Vec<RebalanceEvent>,
+    pub churns: Vec<ChurnEvent>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub enum ListenerState {
+    Starting,
+    Subscribed,
+    /// Listener has partitions assigned and consumes messages
+    Assigned,
+    /// Kafka reported an error. Listener still tries to consume messages
+    Erroring,
+    /// Listener has stopped after the fatal error
+    Dead,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct AssignedPartition {
+    pub topic: String,
+    pub partition: i32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub enum RebalanceType {
+    Assigned,
+    Revoked,
+    Failed { reason: String },
+}
+
+/// Partition assignment change of the experiment listener
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct RebalanceEvent {
+    pub timestamp_millis: u128,
+    pub brokers: String,
+    /// Topic (or topic pattern) the listener is subscribed to
+    pub topic: String,
+    pub consumer_group: String,
+    /// Consumer instance of the listener the partitions are assigned to or revoked from
+    #[serde(default)]
+    pub instance: usize,
+    pub rebalance_type: RebalanceType,
+    /// Partitions assigned or revoked by the rebalance
+    pub partitions: Vec<AssignedPartition>,
+}
+
+/// Throughput of the instance is calculated over windows of this length
+const THROUGHPUT_WINDOW_MILLIS: u128 = 1000;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct InstanceStatus {
+    pub instance: usize,
+    pub assigned_partitions: Vec<AssignedPartition>,
+    pub messages_seen: usize,
+    pub last_message_timestamp_millis: Option<u128>,
+    /// Consumed messages per second over the last completed throughput window
+    pub messages_per_second: f64,
+    #[serde(skip)]
+    window_start_millis: u128,
+    #[serde(skip)]
+    window_messages: usize,
+}
+
+impl InstanceStatus {
+    pub fn new(instance: usize) -> Self {
+        Self {
+            instance,
+            assigned_partitions: Vec::new(),
+            messages_seen: 0,
+            last_message_timestamp_millis: None,
+            messages_per_second: 0.0,
+            window_start_millis: get_now_millis(),
+            window_messages: 0,
+        }
+    }
+
+    fn message_seen(&mut self, timestamp_millis: u128) {
+        self.messages_seen += 1;
+        self.last_message_timestamp_millis = Some(timestamp_millis);
+        self.window_messages += 1;
+
+        let elapsed = timestamp_millis.saturating_sub(self.window_start_millis);
+        if elapsed >= THROUGHPUT_WINDOW_MILLIS {
+            self.messages_per_second = self.window_messages as f64 * 1000.0 / elapsed as f64;
+            self.window_start_millis = timestamp_millis;
+            self.window_messages = 0;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct ListenerStatus {
+    pub listener: KafkaBrokerCfg,
+    /// Consumer group id used by the listener. Differs from the configured one for ephemeral groups
+    pub group_id: String,
+    pub state: ListenerState,
+    pub assigned_partitions: Vec<AssignedPartition>,
+    pub last_error: Option<String>,
+    pub last_error_timestamp_millis: Option<u128>,
+    /// All consumed messages, including the ones not related to any experiment
+    pub messages_seen: usize,
+    pub last_message_timestamp_millis: Option<u128>,
+    /// Last offsets committed for the consumer group per partition
+    pub committed_offsets: Vec<PartitionOffset>,
+    pub last_commit_timestamp_millis: Option<u128>,
+    /// Experiments the listener routes received messages to. The listener is shared by the
+    /// experiments with the same brokers, topic, consumer group and security
+    pub experiments: BTreeSet<Uuid>,
+    /// Running consumers of the listener
+    pub instances: Vec<InstanceStatus>,
+}
+
+impl ListenerStatus {
+    pub fn new(listener: KafkaBrokerCfg, group_id: String) -> Self {
+        Self {
+            listener,
+            group_id,
+            state: ListenerState::Starting,
+            assigned_partitions: Vec::new(),
+            last_error: None,
+            last_error_timestamp_millis: None,
+            messages_seen: 0,
+            last_message_timestamp_millis: None,
+            committed_offsets: Vec::new(),
+            last_commit_timestamp_millis: None,
+            experiments: BTreeSet::new(),
+            instances: Vec::new(),
+        }
+    }
+
+    pub fn instance_started(&mut self, instance: usize) {
+        self.instance_stopped(instance);
+
+        let idx = self.instances.partition_point(|x| x.instance < instance);
+        self.instances.insert(idx, InstanceStatus::new(instance));
+    }
+
+    pub fn instance_stopped(&mut self, instance: usize) {
+        self.instances.retain(|x| x.instance != instance);
+        self.update_assignment();
+    }
+
+    pub fn instance_assigned(&mut self, instance: usize, partitions: Vec<AssignedPartition>) {
+        if let Some(status) = self.instances.iter_mut().find(|x| x.instance == instance) {
+            status.assigned_partitions = partitions;
+        }
+        self.update_assignment();
+    }
+
+    /// Assigned partitions and the state of the listener from the ones of its instances
+    fn update_assignment(&mut self) {
+        self.assigned_partitions = self
+            .instances
+            .iter()
+            .flat_map(|x| x.assigned_partitions.iter().cloned())
+            .collect();
+
+        match self.state {
+            ListenerState::Subscribed if !self.assigned_partitions.is_empty() => {
+                self.state = ListenerState::Assigned;
+            }
+            ListenerState::Assigned if self.assigned_partitions.is_empty() => {
+                self.state = ListenerState::Subscribed;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn offsets_committed(&mut self, offsets: impl Iterator<Item = PartitionOffset>) {
+        for offset in offsets {
+            match self
+                .committed_offsets
+                .iter_mut()
+                .find(|x| x.topic == offset.topic && x.partition == offset.partition)
+            {
+                Some(committed) => committed.offset = offset.offset,
+                None => self.committed_offsets.push(offset),
+            }
+        }
+
+        self.last_commit_timestamp_millis = Some(get_now_millis());
+    }
+
+    pub fn set_error(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.last_error_timestamp_millis = Some(get_now_millis());
+
+        if self.state != ListenerState::Dead {
+            self.state = ListenerState::Erroring;
+        }
+    }
+
+    pub fn message_seen(&mut self, instance: usize, timestamp_millis: u128) {
+        self.messages_seen += 1;
+        self.last_message_timestamp_millis = Some(timestamp_millis);
+
+        if let Some(status) = self.instances.iter_mut().find(|x| x.instance == instance) {
+            status.message_seen(timestamp_millis);
+        }
+
+        if self.state == ListenerState::Erroring {
+            self.state = ListenerState::Assigned;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: i32,
+    pub committed_offset: Option<i64>,
+    /// Offset of the next message to be consumed by the listener
+    pub position: Option<i64>,
+    pub low_watermark: i64,
+    pub high_watermark: i64,
+    /// Messages between the position (or committed offset) and the high watermark
+    pub lag: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct ListenerLag {
+    pub listener: KafkaBrokerCfg,
+    pub partitions: Vec<PartitionLag>,
+    pub total_lag: i64,
+    /// Set if kafka could not be queried for the offsets
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
 pub struct ExperimentOverview{
     pub experiment: Experiment,
     pub messages: usize,
-    pub events: usize
+    pub events: usize,
+    pub listeners: Vec<ListenerStatus>,
+}
+
+fn default_key_template() -> String {
+    "msg-{index}".into()
+}
+
+/// Payload of the sent messages
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, Default)]
+pub enum Payload {
+    /// Random alphanumeric string of the body size
+    #[default]
+    Random,
+    /// JSON document with `{message_uuid}`, `{experiment_uuid}` and `{body}` placeholders in
+    /// its strings. `{body}` is replaced with the random string of the body size
+    JsonTemplate {
+        #[schema(value_type = Object)]
+        template: serde_json::Value,
+    },
 }
 
 pub fn default_buffering_ms() -> u32 {
     5
 }
 
+fn default_queue_full_backoff() -> Duration {
+    Duration(std::time::Duration::from_millis(100))
+}
+
+/// Retry policy used when the local producer queue is full
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct QueueFullRetry {
+    /// Wait before the first retry. Doubled with every next retry up to `max_backoff`
+    #[serde(default = "default_queue_full_backoff")]
+    pub backoff: Duration,
+
+    /// Ten times the `backoff` when not set
+    #[serde(default)]
+    pub max_backoff: Option<Duration>,
+
+    /// Message fails with QueueFull after that many retries. When not set, the message is
+    /// retried until the message timeout
+    #[serde(default)]
+    #[schema(examples(10))]
+    pub max_retries: Option<u32>,
+}
+
+impl Default for QueueFullRetry {
+    fn default() -> Self {
+        Self {
+            backoff: default_queue_full_backoff(),
+            max_backoff: None,
+            max_retries: None,
+        }
+    }
+}
+
+impl QueueFullRetry {
+    pub fn max_backoff(&self) -> std::time::Duration {
+        self.max_backoff
+            .as_ref()
+            .map_or(self.backoff.0 * 10, |x| x.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+pub struct BackpressureStats {
+    /// Number of times sending had to wait because of the in flight messages limit
+    pub in_flight_limit_waits: usize,
+
+    /// Number of times sending had to wait because of the full local producer queue
+    pub queue_full_waits: usize,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct SendMessage {
     #[schema(examples(5))]
@@ -239,6 +1043,86 @@ pub struct SendMessage {
     #[serde(alias= "async", default)]
     #[schema(examples(false))]
     pub async_mode: bool,
+
+    /// Maximum number of messages awaiting the delivery report. Unlimited when not set
+    #[serde(default)]
+    #[schema(examples(1000))]
+    pub max_in_flight: Option<usize>,
+
+    #[serde(default)]
+    pub queue_full_retry: QueueFullRetry,
+
+    /// Send messages in kafka transactions. Transactions never span job batches
+    #[serde(default)]
+    pub transaction: Option<TransactionCfg>,
+
+    #[serde(default)]
+    pub payload: Payload,
+
+    /// Message key with `{index}`, `{message_uuid}` and `{experiment_uuid}` placeholders
+    #[serde(default = "default_key_template")]
+    #[schema(examples(default_key_template))]
+    pub key_template: String,
+
+    /// Headers of the experiment are used when not set
+    #[serde(default)]
+    pub headers: Option<CorrelationHeaders>,
+}
+
+fn default_messages_per_transaction() -> usize {
+    100
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct TransactionCfg {
+    #[schema(examples("kafka-http-emitter-tx"))]
+    pub transactional_id: String,
+
+    #[serde(default = "default_messages_per_transaction")]
+    #[schema(examples(default_messages_per_transaction))]
+    pub messages_per_transaction: usize,
+
+    /// Fraction (0.0 - 1.0) of the transactions to be aborted instead of committed
+    #[serde(default)]
+    #[schema(examples(0.1))]
+    pub abort_ratio: f64,
+}
+
+/// Effective configuration of the producer. Requests with the same config share the producer
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, PartialEq, Eq, Hash)]
+pub struct ProducerCfg {
+    pub brokers: String,
+    pub ssl: bool,
+    pub buffering_ms: u32,
+    pub message_timeout_ms: u128,
+    pub transactional_id: Option<String>,
+}
+
+impl From<&SendMessage> for ProducerCfg {
+    fn from(value: &SendMessage) -> Self {
+        Self {
+            brokers: value.brokers.clone(),
+            ssl: value.ssl,
+            buffering_ms: value.buffering_ms,
+            message_timeout_ms: value.message_timeout.0.as_millis(),
+            transactional_id: value
+                .transaction
+                .as_ref()
+                .map(|x| x.transactional_id.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct ProducerPoolEntry {
+    pub config: ProducerCfg,
+    pub created_at_millis: u128,
+    pub last_used_millis: u128,
+    pub idle_ms: u128,
+    /// Number of requests or jobs currently using the producer
+    pub leases: usize,
+    /// Number of requests or jobs that used the producer so far
+    pub acquisitions: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
@@ -272,11 +1156,226 @@ pub struct SendMessageTask {
     #[schema(examples(1))]
     pub messages_number: usize,
 
-    pub message_rate: MessageRate
+    pub message_rate: MessageRate,
+
+    /// Maximum number of messages awaiting the delivery report. Unlimited when not set
+    #[serde(default)]
+    #[schema(examples(1000))]
+    pub max_in_flight: Option<usize>,
+
+    #[serde(default)]
+    pub queue_full_retry: QueueFullRetry,
+
+    /// Send messages in kafka transactions. Transactions never span job batches
+    #[serde(default)]
+    pub transaction: Option<TransactionCfg>,
 
+    #[serde(default)]
+    pub payload: Payload,
+
+    /// Message key with `{index}`, `{message_uuid}` and `{experiment_uuid}` placeholders
+    #[serde(default = "default_key_template")]
+    #[schema(examples(default_key_template))]
+    pub key_template: String,
+
+    /// Headers of the experiment are used when not set
+    #[serde(default)]
+    pub headers: Option<CorrelationHeaders>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
 pub struct InsightsRequest {
     pub experiment_uuid: Uuid,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema, IntoParams)]
+pub struct JobStatusRequest {
+    pub job_uuid: Uuid,
+}
+
+/// Memory of the emitter according to the allocator statistics
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct MemoryUsage {
+    /// Bytes allocated by the application on the Rust heap. Buffers and queues of librdkafka
+    /// are allocated by the C malloc and are not counted
+    pub allocated_bytes: usize,
+    /// Bytes of the pages holding the allocations
+    pub active_bytes: usize,
+    /// Bytes of the physical memory held by the allocator
+    pub resident_bytes: usize,
+    /// Not set when no budget has been configured
+    pub budget_bytes: Option<usize>,
+    pub budget_exceeded: bool,
+    pub experiments: Vec<ExperimentMemory>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, ToResponse, Debug, Clone)]
+pub struct ExperimentMemory {
+    pub experiment_uuid: Uuid,
+    pub messages: usize,
+    pub events: usize,
+    /// Experiment has reached its max events or the memory budget is exceeded
+    pub limit_reached: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(millis: u64) -> Duration {
+        Duration(std::time::Duration::from_millis(millis))
+    }
+
+    fn listener_status() -> ListenerStatus {
+        let listener = serde_json::from_value(serde_json::json!({
+            "brokers": "localhost:9092",
+            "topic": "topic",
+            "ssl": false,
+            "message_timeout": "1s",
+            "consumer_group_id": "group",
+        }))
+        .unwrap();
+
+        ListenerStatus::new(listener, "group".into())
+    }
+
+    #[test]
+    fn every_schedule_has_fixed_delay() {
+        let schedule = ChurnSchedule::Every {
+            interval: duration(1500),
+        };
+
+        assert_eq!(
+            schedule.next_delay(),
+            std::time::Duration::from_millis(1500)
+        );
+    }
+
+    #[test]
+    fn random_schedule_stays_within_bounds() {
+        let schedule = ChurnSchedule::Random {
+            min: duration(100),
+            max: duration(200),
+        };
+
+        for _ in 0..100 {
+            let delay = schedule.next_delay().as_millis();
+            assert!((100..=200).contains(&delay), "delay {delay}");
+        }
+    }
+
+    #[test]
+    fn random_schedule_with_inverted_bounds_uses_min() {
+        let schedule = ChurnSchedule::Random {
+            min: duration(300),
+            max: duration(100),
+        };
+
+        assert_eq!(schedule.next_delay(), std::time::Duration::from_millis(300));
+    }
+
+    #[test]
+    fn churn_without_delay_is_rejected() {
+        let churn = |schedule, hold| ChurnCfg {
+            schedule,
+            action: ChurnAction::AddRemove { hold },
+        };
+        let every = |millis| ChurnSchedule::Every {
+            interval: duration(millis),
+        };
+        let random = ChurnSchedule::Random {
+            min: duration(0),
+            max: duration(10),
+        };
+
+        assert!(churn(every(0), duration(10)).validate().is_err());
+        assert!(churn(every(10), duration(0)).validate().is_err());
+        assert!(churn(every(10), duration(10)).validate().is_ok());
+        assert!(churn(random, duration(10)).validate().is_ok());
+    }
+
+    #[test]
+    fn listener_recovers_from_errors_until_dead() {
+        let mut status = listener_status();
+        status.state = ListenerState::Assigned;
+
+        status.set_error("broker down".into());
+        assert_eq!(status.state, ListenerState::Erroring);
+        assert_eq!(status.last_error.as_deref(), Some("broker down"));
+        assert!(status.last_error_timestamp_millis.is_some());
+
+        status.message_seen(0, 10);
+        assert_eq!(status.state, ListenerState::Assigned);
+        assert_eq!(status.messages_seen, 1);
+        assert_eq!(status.last_message_timestamp_millis, Some(10));
+
+        status.state = ListenerState::Dead;
+        status.set_error("fatal".into());
+        status.message_seen(0, 20);
+        assert_eq!(status.state, ListenerState::Dead);
+        assert_eq!(status.last_error.as_deref(), Some("fatal"));
+    }
+
+    #[test]
+    fn committed_offsets_are_updated_per_partition() {
+        let mut status = listener_status();
+        let offset = |partition, offset| PartitionOffset {
+            topic: "topic".into(),
+            partition,
+            offset,
+        };
+
+        status.offsets_committed([offset(0, 5), offset(1, 3)].into_iter());
+        status.offsets_committed([offset(0, 8)].into_iter());
+
+        let committed: Vec<_> = status
+            .committed_offsets
+            .iter()
+            .map(|x| (x.partition, x.offset))
+            .collect();
+        assert_eq!(committed, vec![(0, 8), (1, 3)]);
+        assert!(status.last_commit_timestamp_millis.is_some());
+    }
+
+    #[test]
+    fn listener_assignment_combines_its_instances() {
+        let mut status = listener_status();
+        status.state = ListenerState::Subscribed;
+        let partition = |partition| AssignedPartition {
+            topic: "topic".into(),
+            partition,
+        };
+
+        status.instance_started(1);
+        status.instance_started(0);
+        let instances: Vec<_> = status.instances.iter().map(|x| x.instance).collect();
+        assert_eq!(instances, vec![0, 1]);
+
+        status.instance_assigned(0, vec![partition(0)]);
+        status.instance_assigned(1, vec![partition(1)]);
+        assert_eq!(status.state, ListenerState::Assigned);
+        assert_eq!(status.assigned_partitions, vec![partition(0), partition(1)]);
+
+        status.instance_stopped(0);
+        assert_eq!(status.assigned_partitions, vec![partition(1)]);
+
+        status.instance_assigned(1, Vec::new());
+        assert_eq!(status.state, ListenerState::Subscribed);
+    }
+
+    #[test]
+    fn instance_throughput_is_measured_over_the_window() {
+        let mut instance = InstanceStatus::new(0);
+        let start = instance.window_start_millis;
+
+        for offset in [100, 200, 300] {
+            instance.message_seen(start + offset);
+        }
+        assert_eq!(instance.messages_per_second, 0.0);
+
+        instance.message_seen(start + 2000);
+        assert_eq!(instance.messages_seen, 4);
+        assert_eq!(instance.messages_per_second, 2.0);
+        assert_eq!(instance.last_message_timestamp_millis, Some(start + 2000));
+    }
+}
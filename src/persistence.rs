@@ -0,0 +1,723 @@
+use crate::aggregates::AggregateStats;
+use crate::events::EventsSnapshot;
+use crate::models::{ChurnEvent, Experiment, Message, RebalanceEvent};
+use crate::state::{ExperimentShard, ExperimentState, MessagesSnapshot, MessagesState};
+use rusqlite::{Connection, ToSql, params};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Name of the database file created in the state directory
+pub const STATE_FILE_NAME: &str = "state.sqlite";
+
+/// State is written to the disk that often unless configured otherwise
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Kind of the record holding the statistics of the aggregated experiment. Replaced on change
+const AGGREGATES_KIND: &str = "aggregates";
+
+/// Condition on the experiments table matching the archived experiments
+const ARCHIVED: &str = "json_extract(data, '$.lifecycle') IS 'Archived'";
+
+#[derive(thiserror::Error, Debug)]
+pub enum PersistenceError {
+    #[error("Could not create state directory: {0}")]
+    Directory(#[from] std::io::Error),
+
+    #[error("State database failed: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("Could not (de)serialize persisted state: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Invalid uuid in persisted state: {0}")]
+    Uuid(#[from] uuid::Error),
+
+    #[error("Persisting task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Collections of the experiment persisted record by record. Only messages are rewritten once
+/// persisted, as the sent message replaces the received one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RecordKind {
+    Message,
+    Event,
+    Rebalance,
+    Churn,
+}
+
+impl RecordKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Message => "message",
+            Self::Event => "event",
+            Self::Rebalance => "rebalance",
+            Self::Churn => "churn",
+        }
+    }
+}
+
+/// Records of the experiment added since the previous flush
+#[derive(Default)]
+struct ExperimentChanges {
+    /// Serialized experiment, if it has changed
+    experiment: Option<String>,
+    /// Kinds which records have been removed from the state, so they are written from scratch
+    rewrite: Vec<RecordKind>,
+    records: Vec<(RecordKind, usize, String)>,
+    /// Serialized statistics of the aggregated experiment, if they have changed
+    aggregates: Option<String>,
+}
+
+/// Experiments, messages and events stored in the SQLite database under the state directory
+#[derive(Clone)]
+pub struct Persistence {
+    connection: Arc<parking_lot::Mutex<Connection>>,
+    /// Held for the time of the flush, so the flushes do not interleave
+    cursors: Arc<Mutex<Cursors>>,
+    flush_interval: Duration,
+}
+
+impl std::fmt::Debug for Persistence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Persistence")
+            .field("flush_interval", &self.flush_interval)
+            .finish()
+    }
+}
+
+impl Persistence {
+    pub fn open(directory: &Path, flush_interval: Duration) -> Result<Self, PersistenceError> {
+        std::fs::create_dir_all(directory)?;
+
+        let path = directory.join(STATE_FILE_NAME);
+        info!("Persisting experiments into {}", path.display());
+
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS experiments (
+                uuid TEXT PRIMARY KEY,
+                data TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS records (
+                experiment_uuid TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (experiment_uuid, kind, seq)
+            );",
+        )?;
+
+        Ok(Self {
+            connection: Arc::new(parking_lot::Mutex::new(connection)),
+            cursors: Default::default(),
+            flush_interval,
+        })
+    }
+
+    /// Reads the persisted experiments with their data. Archived experiments are left on the disk
+    pub async fn load(&self) -> Result<Vec<ExperimentState>, PersistenceError> {
+        let state = self.read(&format!("NOT {ARCHIVED}"), &[])?;
+        info!("Loaded {} persisted experiments", state.len());
+
+        let mut cursors = self.cursors.lock().await;
+        *cursors = Default::default();
+        for (uuid, experiment) in &state {
+            cursors.track(*uuid, experiment);
+        }
+
+        Ok(state.into_values().collect())
+    }
+
+    /// Reads the archived experiment with its data. It is kept archived on the disk until
+    /// passed to `track` and changed
+    pub async fn read_archived(
+        &self,
+        uuid: Uuid,
+    ) -> Result<Option<ExperimentState>, PersistenceError> {
+        let persistence = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let uuid = uuid.to_string();
+            let mut state = persistence.read(&format!("uuid = ?1 AND {ARCHIVED}"), &[&uuid])?;
+            Ok(state.drain().next().map(|(_, experiment)| experiment))
+        })
+        .await?
+    }
+
+    /// Marks the experiment read from the disk as persisted, so only its further changes are
+    /// written
+    pub async fn track(&self, state: &ExperimentState) {
+        self.cursors
+            .lock()
+            .await
+            .track(state.experiment.uuid, state);
+    }
+
+    /// Experiments matching the condition on the experiments table with their records
+    fn read(
+        &self,
+        condition: &str,
+        args: &[&dyn ToSql],
+    ) -> Result<HashMap<Uuid, ExperimentState>, PersistenceError> {
+        let connection = self.connection.lock();
+        let mut state = HashMap::new();
+
+        let mut experiments =
+            connection.prepare(&format!("SELECT data FROM experiments WHERE {condition}"))?;
+        for data in experiments.query_map(args, |row| row.get::<_, String>(0))? {
+            let experiment: Experiment = serde_json::from_str(&data?)?;
+            state.insert(experiment.uuid, ExperimentState::new(experiment));
+        }
+
+        let mut records = connection.prepare(&format!(
+            "SELECT experiment_uuid, kind, data FROM records
+            WHERE experiment_uuid IN (SELECT uuid FROM experiments WHERE {condition})
+            ORDER BY experiment_uuid, kind, seq"
+        ))?;
+        let rows = records.query_map(args, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (experiment_uuid, kind, data) = row?;
+            let experiment_uuid = Uuid::try_parse(&experiment_uuid)?;
+
+            let Some(experiment) = state.get_mut(&experiment_uuid) else {
+                continue;
+            };
+
+            match kind.as_str() {
+                "message" => {
+                    let message: Message = serde_json::from_str(&data)?;
+                    experiment.insert_message(message, true);
+                }
+                "event" => experiment.events.push(serde_json::from_str(&data)?),
+                "rebalance" => experiment.rebalances.push(serde_json::from_str(&data)?),
+                "churn" => experiment.churns.push(serde_json::from_str(&data)?),
+                AGGREGATES_KIND => match &mut experiment.aggregator {
+                    Some(aggregator) => aggregator.restore(serde_json::from_str(&data)?),
+                    None => warn!(
+                        "Aggregates of the experiment {} without the aggregated storage",
+                        experiment_uuid
+                    ),
+                },
+                kind => warn!("Unknown persisted record kind: {}", kind),
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Experiments which data has been moved to the disk
+    pub async fn archived(&self) -> Result<Vec<Experiment>, PersistenceError> {
+        let persistence = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let connection = persistence.connection.lock();
+            let mut experiments =
+                connection.prepare(&format!("SELECT data FROM experiments WHERE {ARCHIVED}"))?;
+
+            experiments
+                .query_map([], |row| row.get::<_, String>(0))?
+                .map(|data| Ok(serde_json::from_str(&data?)?))
+                .collect()
+        })
+        .await?
+    }
+
+    /// Removes the experiment from the state and writes its remaining records. The experiment
+    /// has to be marked as archived beforehand, so it is not loaded again. The experiment stays
+    /// in the state if it could not be written
+    pub async fn archive(&self, uuid: Uuid, state: &MessagesState) -> Result<(), PersistenceError> {
+        let mut cursors = self.cursors.lock().await;
+
+        // Removed before the copy, so no records are ingested past it
+        let Some(shard) = state.remove(&uuid) else {
+            return Ok(());
+        };
+        shard.drained().await;
+
+        let copy = cursors.copy(uuid, &mut shard.lock());
+        let changes = cursors.experiment_changes(uuid, copy);
+
+        let persistence = self.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            persistence.write(HashMap::from([(uuid, changes)]), Vec::new())
+        })
+        .await
+        .map_err(PersistenceError::from)
+        .and_then(|x| x);
+
+        match written {
+            // Cursors are held, so the flush does not see the experiment as deleted
+            Ok(()) => cursors.forget(&uuid),
+            Err(_) => state.insert_shard(uuid, shard),
+        }
+
+        written
+    }
+
+    /// Deletes the experiment and its records, whether it is archived or not
+    pub async fn delete(&self, uuid: Uuid) -> Result<(), PersistenceError> {
+        let mut cursors = self.cursors.lock().await;
+        cursors.forget(&uuid);
+
+        let persistence = self.clone();
+        tokio::task::spawn_blocking(move || persistence.write(HashMap::new(), vec![uuid])).await?
+    }
+
+    /// Periodically writes the changes of the state
+    pub async fn flush_loop(self, state: MessagesState) {
+        let mut interval = tokio::time::interval(self.flush_interval);
+
+        loop {
+            interval.tick().await;
+            self.flush(&state).await;
+        }
+    }
+
+    /// Writes records added to the state since the previous flush
+    pub async fn flush(&self, state: &MessagesState) {
+        let mut cursors = self.cursors.lock().await;
+        let (changes, removed) = cursors.changes(&state.shards());
+
+        if changes.is_empty() && removed.is_empty() {
+            return;
+        }
+
+        let persistence = self.clone();
+        let written =
+            tokio::task::spawn_blocking(move || persistence.write(changes, removed)).await;
+
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Could not persist experiments: {}", e),
+            Err(e) => warn!("Persisting experiments panicked: {}", e),
+        }
+    }
+
+    fn write(
+        &self,
+        changes: HashMap<Uuid, ExperimentChanges>,
+        removed: Vec<Uuid>,
+    ) -> Result<(), PersistenceError> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+
+        for uuid in removed {
+            let uuid = uuid.to_string();
+            transaction.execute("DELETE FROM experiments WHERE uuid = ?1", params![uuid])?;
+            transaction.execute(
+                "DELETE FROM records WHERE experiment_uuid = ?1",
+                params![uuid],
+            )?;
+        }
+
+        for (uuid, changes) in changes {
+            let uuid = uuid.to_string();
+
+            if let Some(experiment) = changes.experiment {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO experiments (uuid, data) VALUES (?1, ?2)",
+                    params![uuid, experiment],
+                )?;
+            }
+
+            for kind in changes.rewrite {
+                transaction.execute(
+                    "DELETE FROM records WHERE experiment_uuid = ?1 AND kind = ?2",
+                    params![uuid, kind.as_str()],
+                )?;
+            }
+
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO records (experiment_uuid, kind, seq, data)
+                VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (kind, seq, data) in changes.records {
+                insert.execute(params![uuid, kind.as_str(), seq as i64, data])?;
+            }
+
+            if let Some(aggregates) = changes.aggregates {
+                insert.execute(params![uuid, AGGREGATES_KIND, 0, aggregates])?;
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+/// Records of the experiment copied out under its lock, so they are serialized without holding
+/// it. Rebalances and churns are copied from the position of the first one
+struct ExperimentCopy {
+    experiment: Experiment,
+    messages: MessagesSnapshot,
+    /// Positions of the messages replaced since the previous copy
+    replaced: Vec<usize>,
+    events: EventsSnapshot,
+    rebalances: (usize, Vec<RebalanceEvent>),
+    churns: (usize, Vec<ChurnEvent>),
+    aggregates: Option<(u64, AggregateStats)>,
+}
+
+/// Persisted state of the experiments, so only the changes are written
+#[derive(Default)]
+struct Cursors {
+    experiments: HashMap<Uuid, String>,
+    records: HashMap<(Uuid, RecordKind), usize>,
+    /// Versions of the persisted statistics of the aggregated experiments
+    aggregates: HashMap<Uuid, u64>,
+}
+
+impl Cursors {
+    /// Everything in the experiment loaded from the disk is already persisted
+    fn track(&mut self, uuid: Uuid, experiment: &ExperimentState) {
+        if let Ok(data) = serde_json::to_string(&experiment.experiment) {
+            self.experiments.insert(uuid, data);
+        }
+
+        let lengths = [
+            (RecordKind::Message, experiment.messages.len()),
+            (RecordKind::Event, experiment.events.len()),
+            (RecordKind::Rebalance, experiment.rebalances.len()),
+            (RecordKind::Churn, experiment.churns.len()),
+        ];
+
+        for (kind, length) in lengths {
+            self.records.insert((uuid, kind), length);
+        }
+
+        if let Some(aggregator) = &experiment.aggregator {
+            self.aggregates.insert(uuid, aggregator.version());
+        }
+    }
+
+    /// Advances the cursors to the current state. Returns the changed and removed experiments.
+    /// Experiments are locked one at a time
+    fn changes(
+        &mut self,
+        shards: &[(Uuid, ExperimentShard)],
+    ) -> (HashMap<Uuid, ExperimentChanges>, Vec<Uuid>) {
+        let removed: Vec<Uuid> = self
+            .experiments
+            .keys()
+            .filter(|uuid| !shards.iter().any(|(x, _)| x == *uuid))
+            .copied()
+            .collect();
+
+        for uuid in &removed {
+            self.forget(uuid);
+        }
+
+        let mut changes = HashMap::new();
+
+        for (uuid, shard) in shards {
+            let copy = self.copy(*uuid, &mut shard.lock());
+            let experiment_changes = self.experiment_changes(*uuid, copy);
+
+            if !experiment_changes.is_empty() {
+                changes.insert(*uuid, experiment_changes);
+            }
+        }
+
+        (changes, removed)
+    }
+
+    fn forget(&mut self, uuid: &Uuid) {
+        self.experiments.remove(uuid);
+        self.records
+            .retain(|(experiment_uuid, _), _| experiment_uuid != uuid);
+        self.aggregates.remove(uuid);
+    }
+
+    /// Copies out the records that are not persisted yet. Messages and events are shared with
+    /// the state, so only the other records past the cursors are copied
+    fn copy(&self, uuid: Uuid, state: &mut ExperimentState) -> ExperimentCopy {
+        let rebalances = self.start(uuid, RecordKind::Rebalance, state.rebalances.len());
+        let churns = self.start(uuid, RecordKind::Churn, state.churns.len());
+        let version = self.aggregates.get(&uuid).copied().unwrap_or_default();
+
+        ExperimentCopy {
+            experiment: state.experiment.clone(),
+            messages: state.messages.snapshot(),
+            replaced: state.messages.take_replaced(),
+            events: state.events.snapshot(),
+            rebalances: (rebalances, state.rebalances[rebalances..].to_vec()),
+            churns: (churns, state.churns[churns..].to_vec()),
+            aggregates: state
+                .aggregator
+                .as_ref()
+                .filter(|x| x.version() != version)
+                .map(|x| (x.version(), x.stats().clone())),
+        }
+    }
+
+    /// Position of the first record to persist. Records cleared from the state are rewritten
+    fn start(&self, uuid: Uuid, kind: RecordKind, len: usize) -> usize {
+        self.records
+            .get(&(uuid, kind))
+            .copied()
+            .filter(|x| *x <= len)
+            .unwrap_or(0)
+    }
+
+    /// Serializes the copied records. Called without holding the experiment lock
+    fn experiment_changes(&mut self, uuid: Uuid, state: ExperimentCopy) -> ExperimentChanges {
+        let mut changes = ExperimentChanges::default();
+
+        match serde_json::to_string(&state.experiment) {
+            Ok(data) if self.experiments.get(&uuid) != Some(&data) => {
+                self.experiments.insert(uuid, data.clone());
+                changes.experiment = Some(data);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Could not serialize experiment {}: {}", uuid, e),
+        }
+
+        // Replaced messages past the cursor are written by the advance
+        if let Some(cursor) = self.records.get(&(uuid, RecordKind::Message))
+            && *cursor <= state.messages.len()
+        {
+            for position in state.replaced.iter().filter(|x| **x < *cursor) {
+                if let Some(message) = state.messages.get(*position) {
+                    match serde_json::to_string(message) {
+                        Ok(data) => changes.records.push((RecordKind::Message, *position, data)),
+                        Err(e) => warn!("Could not serialize message record: {}", e),
+                    }
+                }
+            }
+        }
+
+        self.advance(
+            uuid,
+            RecordKind::Message,
+            state.messages.len(),
+            |from| state.messages.iter_from(from).map(Some),
+            &mut changes,
+        );
+
+        self.advance(
+            uuid,
+            RecordKind::Event,
+            state.events.len(),
+            |from| {
+                state
+                    .events
+                    .iter_from(from)
+                    .map(|event| Some(event.to_event()))
+            },
+            &mut changes,
+        );
+
+        let (start, rebalances) = state.rebalances;
+        self.advance(
+            uuid,
+            RecordKind::Rebalance,
+            start + rebalances.len(),
+            |from| rebalances[from - start..].iter().map(Some),
+            &mut changes,
+        );
+
+        let (start, churns) = state.churns;
+        self.advance(
+            uuid,
+            RecordKind::Churn,
+            start + churns.len(),
+            |from| churns[from - start..].iter().map(Some),
+            &mut changes,
+        );
+
+        if let Some((version, stats)) = state.aggregates {
+            self.aggregates.insert(uuid, version);
+            match serde_json::to_string(&stats) {
+                Ok(data) => changes.aggregates = Some(data),
+                Err(e) => warn!("Could not serialize aggregates {}: {}", uuid, e),
+            }
+        }
+
+        changes
+    }
+
+    /// Serializes the records past the cursor
+    fn advance<T: Serialize, I: Iterator<Item = Option<T>>>(
+        &mut self,
+        uuid: Uuid,
+        kind: RecordKind,
+        len: usize,
+        records_from: impl FnOnce(usize) -> I,
+        changes: &mut ExperimentChanges,
+    ) {
+        let cursor = self.records.entry((uuid, kind)).or_default();
+
+        // Records have been cleared from the state since the previous flush
+        if len < *cursor {
+            *cursor = 0;
+            changes.rewrite.push(kind);
+        }
+
+        for (seq, record) in (*cursor..).zip(records_from(*cursor)) {
+            if let Some(record) = record {
+                match serde_json::to_string(&record) {
+                    Ok(data) => changes.records.push((kind, seq, data)),
+                    Err(e) => warn!("Could not serialize {} record: {}", kind.as_str(), e),
+                }
+            }
+        }
+
+        *cursor = len;
+    }
+}
+
+impl ExperimentChanges {
+    fn is_empty(&self) -> bool {
+        self.experiment.is_none()
+            && self.rewrite.is_empty()
+            && self.records.is_empty()
+            && self.aggregates.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{EventType, ExperimentLifecycle, MessageEvent, RebalanceType};
+    use crate::state::Record;
+    use std::path::PathBuf;
+
+    fn directory() -> PathBuf {
+        std::env::temp_dir().join(format!("kafka-http-emitter-{}", Uuid::new_v4()))
+    }
+
+    fn message(uuid: Uuid, bytes: u64) -> Message {
+        Message {
+            uuid,
+            bytes_size: bytesize::ByteSize(bytes).into(),
+        }
+    }
+
+    fn experiment(state: &MessagesState) -> (Uuid, ExperimentShard) {
+        let uuid = Uuid::new_v4();
+        let experiment = Experiment::new(uuid, Vec::new(), Default::default());
+        (uuid, state.insert(ExperimentState::new(experiment)))
+    }
+
+    #[tokio::test]
+    async fn flushed_experiment_is_loaded_with_replaced_messages() {
+        let directory = directory();
+        let persistence = Persistence::open(&directory, Duration::from_secs(1)).unwrap();
+        let state = MessagesState::default();
+        let (uuid, shard) = experiment(&state);
+        let (received, sent) = (Uuid::new_v4(), Uuid::new_v4());
+
+        shard.record(Record::Received(message(received, 1)));
+        shard.record(Record::Sent(message(sent, 1)));
+        shard.drained().await;
+        persistence.flush(&state).await;
+
+        // Sent message replaces the already persisted receipt
+        shard.record(Record::Sent(message(received, 2)));
+        shard.drained().await;
+        persistence.flush(&state).await;
+
+        let loaded = Persistence::open(&directory, Duration::from_secs(1))
+            .unwrap()
+            .load()
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].experiment.uuid, uuid);
+        let messages = loaded[0].messages.snapshot();
+        let sizes: Vec<_> = messages
+            .iter()
+            .map(|x| (x.uuid, x.bytes_size.as_bytes()))
+            .collect();
+        assert_eq!(sizes, vec![(received, 2), (sent, 1)]);
+    }
+
+    #[tokio::test]
+    async fn archive_keeps_queued_records() {
+        let directory = directory();
+        let persistence = Persistence::open(&directory, Duration::from_secs(1)).unwrap();
+        let state = MessagesState::default();
+        let (uuid, shard) = experiment(&state);
+
+        shard.lock().experiment.lifecycle = ExperimentLifecycle::Archived;
+        shard.record(Record::Sent(message(Uuid::new_v4(), 1)));
+        persistence.archive(uuid, &state).await.unwrap();
+
+        assert!(!state.contains(&uuid));
+        assert!(persistence.load().await.unwrap().is_empty());
+        let archived = persistence.read_archived(uuid).await.unwrap().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(archived.messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_are_written_incrementally() {
+        let directory = directory();
+        let persistence = Persistence::open(&directory, Duration::from_secs(1)).unwrap();
+        let state = MessagesState::default();
+        let (uuid, shard) = experiment(&state);
+        let message_uuid = Uuid::new_v4();
+        let event = |timestamp_millis| MessageEvent {
+            message_uuid,
+            timestamp_millis,
+            brokers: "localhost:9092".into(),
+            topic: "topic".into(),
+            event_type: EventType::Sent,
+        };
+
+        shard.record(Record::Sent(message(message_uuid, 1)));
+        shard.record(Record::Event(event(1)));
+        shard.record(Record::Rebalance(RebalanceEvent {
+            timestamp_millis: 2,
+            brokers: "localhost:9092".into(),
+            topic: "topic".into(),
+            consumer_group: "group".into(),
+            instance: 0,
+            rebalance_type: RebalanceType::Assigned,
+            partitions: Vec::new(),
+        }));
+        shard.drained().await;
+        persistence.flush(&state).await;
+
+        shard.record(Record::Event(event(3)));
+        shard.lock().experiment.experiment_end_timestamp_millis = Some(4);
+        shard.drained().await;
+        persistence.flush(&state).await;
+
+        let loaded = persistence.load().await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let loaded = &loaded[0];
+        assert_eq!(loaded.experiment.uuid, uuid);
+        assert_eq!(loaded.experiment.experiment_end_timestamp_millis, Some(4));
+        assert_eq!(loaded.messages.len(), 1);
+        let timestamps: Vec<_> = loaded
+            .events
+            .snapshot()
+            .iter()
+            .map(|x| x.timestamp_millis)
+            .collect();
+        assert_eq!(timestamps, vec![1, 3]);
+        assert_eq!(loaded.rebalances.len(), 1);
+    }
+}
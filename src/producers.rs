@@ -0,0 +1,261 @@
+use crate::get_now_millis;
+use crate::models::{ProducerCfg, ProducerPoolEntry};
+use rdkafka::ClientConfig;
+use rdkafka::error::KafkaResult;
+use rdkafka::producer::FutureProducer;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tracing::info;
+
+/// Usage of the pooled producer shared with its leases
+#[derive(Debug)]
+struct Usage {
+    last_used_millis: AtomicU64,
+    acquisitions: AtomicUsize,
+}
+
+struct PooledProducer {
+    producer: FutureProducer,
+    created_at_millis: u128,
+    usage: Arc<Usage>,
+    transactions: Arc<tokio::sync::Mutex<bool>>,
+}
+
+impl PooledProducer {
+    /// Number of leases currently holding the producer
+    fn leases(&self) -> usize {
+        Arc::strong_count(&self.usage) - 1
+    }
+
+    fn idle_for(&self, now: u128) -> Duration {
+        let last_used = self.usage.last_used_millis.load(Ordering::Relaxed) as u128;
+        Duration::from_millis(now.saturating_sub(last_used) as u64)
+    }
+}
+
+type Pool = parking_lot::Mutex<HashMap<ProducerCfg, PooledProducer>>;
+
+/// Producer borrowed from the pool. The producer is not evicted as long as the lease exists
+pub struct ProducerLease {
+    pub producer: FutureProducer,
+    /// Held for the time of sending transactions. Set to true once transactions are initialized
+    pub transactions: Arc<tokio::sync::Mutex<bool>>,
+    usage: Arc<Usage>,
+    pool: Weak<Pool>,
+}
+
+impl ProducerLease {
+    /// Removes the producer from the pool, so the next acquisition creates a new one. Used once
+    /// the transactional producer has failed, since it cannot recover from most of the errors
+    pub fn discard(&self) {
+        let Some(pool) = self.pool.upgrade() else {
+            return;
+        };
+
+        pool.lock().retain(|cfg, pooled| {
+            let keep = !Arc::ptr_eq(&pooled.usage, &self.usage);
+            if !keep {
+                info!("Discarding failed producer for {}", cfg.brokers);
+            }
+            keep
+        });
+    }
+}
+
+impl Drop for ProducerLease {
+    fn drop(&mut self) {
+        self.usage
+            .last_used_millis
+            .store(get_now_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Pool of the kafka producers keyed by their effective config. Producers are reused across
+/// requests and experiments, so the connection setup does not affect the sent messages
+#[derive(Clone)]
+pub struct Producers {
+    pool: Arc<Pool>,
+    idle_timeout: Duration,
+}
+
+impl std::fmt::Debug for Producers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Producers")
+            .field("pool", &self.pool.lock().keys().collect::<Vec<_>>())
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl Producers {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            pool: Default::default(),
+            idle_timeout,
+        }
+    }
+
+    /// Get producer with the provided config. Creates a new one if there is none in the pool
+    pub fn acquire(&self, cfg: &ProducerCfg) -> KafkaResult<ProducerLease> {
+        let mut pool = self.pool.lock();
+
+        let pooled = match pool.entry(cfg.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                info!("Creating new producer for {}", cfg.brokers);
+                entry.insert(PooledProducer {
+                    producer: create_producer(cfg)?,
+                    created_at_millis: get_now_millis(),
+                    usage: Arc::new(Usage {
+                        last_used_millis: AtomicU64::new(get_now_millis() as u64),
+                        acquisitions: AtomicUsize::new(0),
+                    }),
+                    transactions: Default::default(),
+                })
+            }
+        };
+
+        pooled.usage.acquisitions.fetch_add(1, Ordering::Relaxed);
+        pooled
+            .usage
+            .last_used_millis
+            .store(get_now_millis() as u64, Ordering::Relaxed);
+
+        Ok(ProducerLease {
+            producer: pooled.producer.clone(),
+            transactions: pooled.transactions.clone(),
+            usage: pooled.usage.clone(),
+            pool: Arc::downgrade(&self.pool),
+        })
+    }
+
+    /// Remove producers that are not leased and have not been used for the idle timeout
+    pub fn evict_idle(&self) -> &Self {
+        let now = get_now_millis();
+
+        self.pool.lock().retain(|cfg, pooled| {
+            let keep = pooled.leases() > 0 || pooled.idle_for(now) < self.idle_timeout;
+            if !keep {
+                info!("Evicting idle producer for {}", cfg.brokers);
+            }
+            keep
+        });
+
+        self
+    }
+
+    /// Periodically evicts idle producers. Runs until the runtime is stopped
+    pub async fn evict_idle_loop(self) {
+        let mut interval =
+            tokio::time::interval(std::cmp::max(self.idle_timeout / 2, Duration::from_secs(1)));
+
+        loop {
+            interval.tick().await;
+            self.evict_idle();
+        }
+    }
+
+    pub fn entries(&self) -> Vec<ProducerPoolEntry> {
+        let now = get_now_millis();
+
+        self.pool
+            .lock()
+            .iter()
+            .map(|(cfg, pooled)| ProducerPoolEntry {
+                config: cfg.clone(),
+                created_at_millis: pooled.created_at_millis,
+                last_used_millis: pooled.usage.last_used_millis.load(Ordering::Relaxed) as u128,
+                idle_ms: pooled.idle_for(now).as_millis(),
+                leases: pooled.leases(),
+                acquisitions: pooled.usage.acquisitions.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+fn create_producer(cfg: &ProducerCfg) -> KafkaResult<FutureProducer> {
+    let mut config = ClientConfig::new();
+    config
+        .set("bootstrap.servers", cfg.brokers.clone())
+        .set("queue.buffering.max.ms", cfg.buffering_ms.to_string())
+        .set("message.timeout.ms", cfg.message_timeout_ms.to_string());
+
+    if let Some(transactional_id) = &cfg.transactional_id {
+        config.set("transactional.id", transactional_id.clone());
+    }
+
+    if cfg.ssl {
+        config.set("security.protocol", "ssl");
+        config.set("enable.ssl.certificate.verification", "false");
+    }
+
+    config.create()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(buffering_ms: u32) -> ProducerCfg {
+        ProducerCfg {
+            brokers: "127.0.0.1:1".into(),
+            ssl: false,
+            buffering_ms,
+            message_timeout_ms: 1000,
+            transactional_id: None,
+        }
+    }
+
+    #[test]
+    fn producer_is_reused_for_the_same_config() {
+        let producers = Producers::new(Duration::from_secs(60));
+
+        let first = producers.acquire(&cfg(5)).unwrap();
+        let second = producers.acquire(&cfg(5)).unwrap();
+        let other = producers.acquire(&cfg(10)).unwrap();
+
+        assert!(Arc::ptr_eq(&first.usage, &second.usage));
+        assert!(!Arc::ptr_eq(&first.usage, &other.usage));
+
+        let mut entries = producers.entries();
+        entries.sort_by_key(|x| x.config.buffering_ms);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].leases, entries[0].acquisitions), (2, 2));
+        assert_eq!((entries[1].leases, entries[1].acquisitions), (1, 1));
+
+        drop(second);
+        let leases: HashMap<_, _> = producers
+            .entries()
+            .into_iter()
+            .map(|x| (x.config.buffering_ms, x.leases))
+            .collect();
+        assert_eq!(leases, HashMap::from([(5, 1), (10, 1)]));
+    }
+
+    #[test]
+    fn invalid_config_is_an_error() {
+        let producers = Producers::new(Duration::from_secs(60));
+
+        assert!(producers.acquire(&cfg(u32::MAX)).is_err());
+        assert!(producers.entries().is_empty());
+    }
+
+    #[test]
+    fn only_idle_producers_without_leases_are_evicted() {
+        let producers = Producers::new(Duration::ZERO);
+
+        let leased = producers.acquire(&cfg(5)).unwrap();
+        drop(producers.acquire(&cfg(10)).unwrap());
+        producers.evict_idle();
+
+        let entries = producers.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].config, cfg(5));
+
+        leased.discard();
+        assert!(producers.entries().is_empty());
+    }
+}
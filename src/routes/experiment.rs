@@ -1,127 +1,352 @@
 use crate::AppData;
+use crate::consumers::LAG_QUERY_TIMEOUT;
+use crate::models::measurements::{AddRoute, ExperimentRoute};
 use crate::models::{
-    BeginResponse, EndRequest, EndResponse, ExperimentOverview, Insights, InsightsRequest,
-    NewExperiment, RestoreExperiment,
+    AddListener, BeginResponse, EndRequest, EndResponse, ExperimentOverview, ExperimentSummary,
+    Insights, InsightsRequest, ListenerLag, ListenerStatus, NewExperiment, RemoveListener,
+    RestoreExperiment,
 };
-use actix_web::{Responder, delete, get, post, web};
-use uuid::Uuid;
+use crate::state::ExperimentError;
+use actix_web::{Responder, delete, get, http::StatusCode, post, web};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ResponseError {
+    #[error(transparent)]
+    Experiment(#[from] ExperimentError),
+}
+
+impl actix_web::error::ResponseError for ResponseError {
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            Self::Experiment(
+                ExperimentError::ListenerCreation(_)
+                | ExperimentError::InvalidCorrelation(_)
+                | ExperimentError::InvalidTopicPattern(_)
+                | ExperimentError::InvalidChurn(_)
+                | ExperimentError::InvalidLimits(_)
+                | ExperimentError::UnsupportedTopicPattern(_)
+                | ExperimentError::UnknownExperimentStart
+                | ExperimentError::PersistenceDisabled,
+            ) => StatusCode::BAD_REQUEST,
+            Self::Experiment(
+                ExperimentError::ExperimentNotFound | ExperimentError::ListenerNotFound,
+            ) => StatusCode::NOT_FOUND,
+            Self::Experiment(
+                ExperimentError::ExperimentAlreadyExists
+                | ExperimentError::ListenerAlreadyExists
+                | ExperimentError::RouteAlreadyExists
+                | ExperimentError::ListenerConfigConflict
+                | ExperimentError::ExperimentStopped,
+            ) => StatusCode::CONFLICT,
+            Self::Experiment(ExperimentError::Persistence(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
 
 #[utoipa::path(
     tag = "experiment",
     responses(
-        (status = 200, description = "ID of the experiment", body = BeginResponse)
+        (status = 200, description = "ID of the experiment", body = BeginResponse),
+        (status = 400, description = "Listener could not be created or limits are invalid"),
+        (status = 409, description = "Listener of another experiment uses the consumer group with a different config, routes have the same name, or the experiment already exists")
     )
 )]
 #[post("/")]
 /// Create a new experiment
-async fn begin(body: web::Json<NewExperiment>, data: web::Data<AppData>) -> impl Responder {
+async fn begin(
+    body: web::Json<NewExperiment>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
     let experiment_uuid = uuid::Uuid::new_v4();
 
     {
         let mut data = data.app_state.lock().await;
 
-        data.new_experiment(experiment_uuid, body.listeners.clone())
-            .await;
+        data.new_experiment(
+            experiment_uuid,
+            body.listeners.clone(),
+            body.headers.clone(),
+            body.routes.clone(),
+            body.storage.clone(),
+            body.limits.clone(),
+        )
+        .await?;
     }
 
-    web::Json(BeginResponse { experiment_uuid })
+    Ok(web::Json(BeginResponse { experiment_uuid }))
 }
 
 #[utoipa::path(
     tag = "experiment",
     responses(
-        (status = 200, description = "ID of the experiment", body = BeginResponse)
+        (status = 200, description = "ID of the experiment", body = BeginResponse),
+        (status = 400, description = "Listener could not be created or restore position is unknown"),
+        (status = 409, description = "Consumer group is used by the listener of another experiment")
     )
 )]
 #[post("/restore")]
-/// Restore existing experiment. Tries to read messages from the start. It won't work if
-/// messages have been already read
-async fn restore(body: web::Json<RestoreExperiment>, data: web::Data<AppData>) -> impl Responder {
+/// Restore existing experiment by consuming its messages again. Listeners can start from the
+/// earliest offsets, the experiment start, the provided timestamp or explicit offsets. Use
+/// ephemeral group if the listener group has already committed offsets. Archived experiment is
+/// loaded back with its data
+async fn restore(
+    body: web::Json<RestoreExperiment>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
     let mut data = data.app_state.lock().await;
+    let body = body.into_inner();
     let experiment_uuid = body.experiment_uuid;
 
-    data.restore_experiment(experiment_uuid, body.listeners.clone())
-        .await;
+    data.restore_experiment(
+        experiment_uuid,
+        body.listeners,
+        body.from,
+        body.ephemeral_group,
+    )
+    .await?;
+
+    Ok(web::Json(BeginResponse { experiment_uuid }))
+}
+
+#[utoipa::path(
+    tag = "experiment",
+    responses(
+        (status = 200, description = "Status of the attached listener", body = ListenerStatus),
+        (status = 400, description = "Listener could not be created"),
+        (status = 404, description = "Experiment not found"),
+        (status = 409, description = "Experiment already has the listener, has been stopped or the consumer group is used with a different config")
+    )
+)]
+#[post("/listener")]
+/// Attach new listener to the running experiment. Already recorded events are kept
+async fn add_listener(
+    body: web::Json<AddListener>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
+    let body = body.into_inner();
+    let mut data = data.app_state.lock().await;
+
+    data.add_listener(body.experiment_uuid, body.listener.clone())
+        .await?;
+
+    let status = data
+        .consumers
+        .statuses(&body.experiment_uuid)
+        .into_iter()
+        .rfind(|x| {
+            x.listener.brokers == body.listener.brokers
+                && x.listener.topic == body.listener.topic
+                && x.listener.consumer_group_id == body.listener.consumer_group_id
+        })
+        .unwrap_or_else(|| {
+            ListenerStatus::new(
+                body.listener.clone(),
+                body.listener.consumer_group_id.clone(),
+            )
+        });
+
+    Ok(web::Json(status))
+}
+
+#[utoipa::path(
+    tag = "experiment",
+    responses(
+        (status = 200, description = "Last status of the detached listener", body = ListenerStatus),
+        (status = 404, description = "Experiment or listener not found")
+    )
+)]
+#[delete("/listener")]
+/// Stop and detach the experiment listener. Events it has recorded are kept
+async fn remove_listener(
+    body: web::Json<RemoveListener>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
+    let status = data
+        .app_state
+        .lock()
+        .await
+        .remove_listener(
+            body.experiment_uuid,
+            &body.brokers,
+            &body.topic,
+            &body.consumer_group_id,
+        )
+        .await?;
+
+    Ok(web::Json(status))
+}
+
+#[utoipa::path(
+    tag = "experiment",
+    responses(
+        (status = 200, description = "Added route", body = ExperimentRoute),
+        (status = 404, description = "Experiment not found"),
+        (status = 409, description = "Experiment already has route with the same name")
+    )
+)]
+#[post("/route")]
+/// Add named route mapping the source cluster and topic to the dest ones. Measurements can be
+/// requested by the route name
+async fn add_route(
+    body: web::Json<AddRoute>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
+    let body = body.into_inner();
+    let experiment_uuid = body.experiment_uuid;
+
+    data.app_state
+        .lock()
+        .await
+        .add_route(experiment_uuid, body.route.clone())
+        .await?;
+
+    Ok(web::Json(body.route))
+}
+
+#[utoipa::path(
+    tag = "experiment",
+    responses(
+        (status = 200, description = "Stopped experiment", body = ExperimentSummary),
+        (status = 404, description = "Experiment not found")
+    )
+)]
+#[post("/stop")]
+/// Stop the experiment listeners and jobs and set its end. Data is kept for the measurements
+async fn stop(
+    body: web::Json<EndRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
+    let experiment = data
+        .app_state
+        .lock()
+        .await
+        .stop_experiment(body.experiment_uuid)
+        .await?;
+
+    Ok(web::Json(ExperimentSummary::from(&experiment)))
+}
 
-    web::Json(BeginResponse { experiment_uuid })
+#[utoipa::path(
+    tag = "experiment",
+    responses(
+        (status = 200, description = "Archived experiment", body = ExperimentSummary),
+        (status = 400, description = "State directory is not set"),
+        (status = 404, description = "Experiment not found"),
+        (status = 500, description = "Experiment could not be written")
+    )
+)]
+#[post("/archive")]
+/// Stop the experiment and move its data from the memory to the state directory. Archived
+/// experiment is not measurable and is not loaded on startup
+async fn archive(
+    body: web::Json<EndRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
+    let experiment = data
+        .app_state
+        .lock()
+        .await
+        .archive_experiment(body.experiment_uuid)
+        .await?;
+
+    Ok(web::Json(ExperimentSummary::from(&experiment)))
 }
 
 #[utoipa::path(
     tag = "experiment",
     responses(
-        (status = 200, description = "Delete all experiment data", body = BeginResponse)
+        (status = 200, description = "Delete all experiment data", body = EndResponse),
+        (status = 500, description = "Experiment could not be deleted from the state directory")
     )
 )]
 #[delete("/")]
-/// Delete experiment and its events data. Keep the messages
-async fn end(body: web::Json<EndRequest>, data: web::Data<AppData>) -> impl Responder {
+/// Stop the experiment and delete all its data, including the archived one
+async fn end(
+    body: web::Json<EndRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
     let experiment_uuid = body.0.experiment_uuid;
 
     {
         let mut data = data.app_state.lock().await;
-        data.end_experiment(experiment_uuid).await;
+        data.delete_experiment(experiment_uuid).await?;
     }
 
-    web::Json(EndResponse { experiment_uuid })
+    Ok(web::Json(EndResponse { experiment_uuid }))
 }
 
 #[utoipa::path(
     tag = "experiment",
     responses(
-        (status = 200, description = "Delete all experiment data from all experiments")
+        (status = 200, description = "Delete all experiment data from all experiments"),
+        (status = 500, description = "Experiments could not be deleted from the state directory")
     )
 )]
 #[delete("/reset-all")]
-/// Delete all experiments and their data
-async fn reset(data: web::Data<AppData>) -> impl Responder {
+/// Delete all experiments and their data, including the archived ones
+async fn reset(data: web::Data<AppData>) -> actix_web::Result<impl Responder, ResponseError> {
     {
         let mut data = data.app_state.lock().await;
 
-        let experiments_uuids: Vec<Uuid> = {
-            let msg_state = data.messages_state.lock().await;
-            msg_state.experiments.keys().cloned().collect()
-        };
+        let mut experiments_uuids = data.messages_state.uuids();
+
+        if let Some(persistence) = &data.persistence {
+            let archived = persistence
+                .archived()
+                .await
+                .map_err(ExperimentError::from)?;
+            experiments_uuids.extend(archived.iter().map(|x| x.uuid));
+        }
 
         for experiment_uuid in experiments_uuids {
             // Needed to stop consumers
-            data.end_experiment(experiment_uuid).await;
+            data.delete_experiment(experiment_uuid).await?;
         }
 
         // Just to make sure that there is nothing left
-        let mut msg_state = data.messages_state.lock().await;
-        msg_state.experiments.clear();
-        msg_state.idx_message_to_experiment.0.clear();
-        msg_state.idx_experiment_to_messages.0.clear();
-        msg_state.messages.clear();
-        msg_state.events.clear();
-
+        data.messages_state.clear();
     }
 
-    "Experiments cleared"
+    Ok("Experiments cleared")
 }
 
 #[utoipa::path(
     tag = "experiment",
     responses(
-        (status = 200, description = "List of registered experiments", body = Vec<Uuid>)
+        (status = 200, description = "List of registered experiments with their lifecycle", body = Vec<ExperimentSummary>),
+        (status = 500, description = "Archived experiments could not be read")
     )
 )]
 #[get("/list")]
-/// Get all registered experiments
-async fn list_experiments(data: web::Data<AppData>) -> impl Responder {
-    let experiments = {
-        data.app_state
-            .lock()
-            .await
-            .messages_state
-            .clone()
-            .lock()
+/// Get all registered experiments, including the archived ones
+async fn list_experiments(
+    data: web::Data<AppData>,
+) -> actix_web::Result<impl Responder, ResponseError> {
+    let mut experiments: Vec<ExperimentSummary> = data
+        .messages_state
+        .shards()
+        .iter()
+        .map(|(_, shard)| ExperimentSummary::from(&shard.lock().experiment))
+        .collect();
+
+    let persistence = data.app_state.lock().await.persistence.clone();
+    if let Some(persistence) = persistence {
+        let archived = persistence
+            .archived()
             .await
-            .experiments
-            .clone()
-    };
+            .map_err(ExperimentError::from)?;
+        // Restored experiment stays archived on the disk until the next flush
+        experiments.extend(
+            archived
+                .iter()
+                .filter(|x| data.messages_state.get(&x.uuid).is_none())
+                .map(ExperimentSummary::from),
+        );
+    }
+
+    experiments.sort_by_key(|x| x.experiment_start_timestamp_millis);
 
-    web::Json(experiments.keys().cloned().collect::<Vec<Uuid>>())
+    Ok(web::Json(experiments))
 }
 
 #[utoipa::path(
@@ -139,17 +364,31 @@ async fn get_insights(
     params: web::Query<InsightsRequest>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<web::Json<Insights>> {
-    let (experiment, messages, events) = data
-        .experiment_related_data(&params.0.experiment_uuid)
-        .await
+    let shard = data
+        .messages_state
+        .get(&params.experiment_uuid)
         .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))?;
 
-    let messages = messages.0.into_values().collect();
+    let (experiment, messages, events, rebalances, churns) = {
+        let state = shard.lock();
+        (
+            state.experiment.clone(),
+            state.messages.snapshot(),
+            state.events.snapshot(),
+            state.rebalances.clone(),
+            state.churns.clone(),
+        )
+    };
+
+    let messages = messages.iter().cloned().collect();
+    let events = events.iter().map(|event| event.to_event()).collect();
 
     Ok(web::Json(Insights {
         messages,
         experiment,
         events,
+        rebalances,
+        churns,
     }))
 }
 
@@ -168,14 +407,69 @@ async fn get_config(
     params: web::Query<InsightsRequest>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<web::Json<ExperimentOverview>> {
-    let (experiment, messages, events) = data
-        .experiment_related_data(&params.0.experiment_uuid)
+    let (experiment, messages, events) = {
+        let shard = data
+            .messages_state
+            .get(&params.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))?;
+        let state = shard.lock();
+
+        (
+            state.experiment.clone(),
+            state.messages.len(),
+            state.events.len(),
+        )
+    };
+
+    let listeners = data
+        .app_state
+        .lock()
         .await
-        .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))?;
+        .consumers
+        .statuses(&params.experiment_uuid);
 
     Ok(web::Json(ExperimentOverview {
         experiment,
-        messages: messages.0.len(),
-        events: events.len(),
+        messages,
+        events,
+        listeners,
     }))
 }
+
+#[utoipa::path(
+    tag = "experiment",
+    params(
+        InsightsRequest
+    ),
+    responses(
+        (status = 200, description = "Lag of the experiment listeners", body = Vec<ListenerLag>),
+        (status = 404, description = "Experiment not found")
+    )
+)]
+#[get("/lag")]
+/// Get assigned partitions, offsets, watermarks and lag of every experiment listener
+async fn get_lag(
+    params: web::Query<InsightsRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Vec<ListenerLag>>> {
+    let listeners = {
+        let data = data.app_state.lock().await;
+
+        if !data.messages_state.contains(&params.experiment_uuid) {
+            return Err(actix_web::error::ErrorNotFound("Experiment not found"));
+        }
+
+        data.consumers.listeners(&params.experiment_uuid)
+    };
+
+    let lags = tokio::task::spawn_blocking(move || {
+        listeners
+            .iter()
+            .map(|listener| listener.lag(LAG_QUERY_TIMEOUT))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(web::Json(lags))
+}
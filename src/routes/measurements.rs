@@ -1,33 +1,205 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use crate::models::{EventType, measurements::*};
+use crate::aggregates::{
+    AggregateStats, BucketStats, EndpointKey, Histogram, KafkaRouteStats, RouteStats,
+};
+use crate::events::{EventRef, EventTypeRef, EventsSnapshot};
+use crate::models::{
+    Experiment, IsolationLevel, KafkaBrokerCfg, KafkaTimestampType, StorageMode, measurements::*,
+};
 use actix_web::{post, web};
+use uuid::Uuid;
 
 use crate::AppData;
 
+/// Topic of the measured events. Either the exact name or the regex pattern
+enum TopicFilter {
+    Exact(String),
+    Pattern(regex::Regex),
+}
+
+impl TopicFilter {
+    fn new(topic: &str, pattern: bool) -> actix_web::Result<Self> {
+        if !pattern {
+            return Ok(Self::Exact(topic.into()));
+        }
+
+        regex::Regex::new(topic)
+            .map(Self::Pattern)
+            .map_err(actix_web::error::ErrorBadRequest)
+    }
+
+    fn matches(&self, topic: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact == topic,
+            Self::Pattern(pattern) => pattern.is_match(topic),
+        }
+    }
+
+    /// Matches the subscription of the listener to the topic or to the topic pattern
+    fn matches_subscription(&self, topic: &str, pattern: bool) -> bool {
+        match self {
+            _ if !pattern => self.matches(topic),
+            Self::Exact(exact) => regex::Regex::new(topic).is_ok_and(|x| x.is_match(exact)),
+            Self::Pattern(measured) => measured.as_str() == topic,
+        }
+    }
+}
+
+/// Brokers of the bootstrap string without the protocol, lowercased. Order and duplicates of
+/// the brokers do not matter
+fn normalize_brokers(brokers: &str) -> BTreeSet<String> {
+    brokers
+        .split(',')
+        .map(|broker| {
+            let broker = broker.trim();
+            let broker = broker.split_once("://").map_or(broker, |(_, x)| x);
+            broker.trim_end_matches('/').to_lowercase()
+        })
+        .filter(|broker| !broker.is_empty())
+        .collect()
+}
+
+/// Matches the events of the measured cluster and topic. Bootstrap strings match if they
+/// share any broker
+struct EndpointFilter {
+    brokers: BTreeSet<String>,
+    topic: TopicFilter,
+    /// Events have only a few distinct bootstrap strings
+    matched_brokers: RefCell<HashMap<String, bool>>,
+}
+
+impl EndpointFilter {
+    fn new(
+        brokers: &str,
+        broker_aliases: &[String],
+        topic: &str,
+        topic_pattern: bool,
+    ) -> actix_web::Result<Self> {
+        Ok(Self {
+            brokers: std::iter::once(brokers)
+                .chain(broker_aliases.iter().map(String::as_str))
+                .flat_map(normalize_brokers)
+                .collect(),
+            topic: TopicFilter::new(topic, topic_pattern)?,
+            matched_brokers: Default::default(),
+        })
+    }
+
+    fn from_broker(broker: &KafkaLatencyRequestBroker) -> actix_web::Result<Self> {
+        Self::new(
+            &broker.brokers,
+            &broker.broker_aliases,
+            &broker.topic,
+            broker.topic_pattern,
+        )
+    }
+
+    fn from_source(source: &SendReceiveLatencyRequestBrokerSource) -> actix_web::Result<Self> {
+        Self::new(
+            &source.brokers,
+            &source.broker_aliases,
+            &source.topic,
+            source.topic_pattern,
+        )
+    }
+
+    fn matches_brokers(&self, brokers: &str) -> bool {
+        if let Some(matched) = self.matched_brokers.borrow().get(brokers) {
+            return *matched;
+        }
+
+        let matched = normalize_brokers(brokers)
+            .iter()
+            .any(|broker| self.brokers.contains(broker));
+        self.matched_brokers
+            .borrow_mut()
+            .insert(brokers.to_string(), matched);
+
+        matched
+    }
+
+    fn matches(&self, event: &EventRef) -> bool {
+        self.topic.matches(event.topic) && self.matches_brokers(event.brokers)
+    }
+
+    fn matches_key(&self, key: &EndpointKey) -> bool {
+        self.topic.matches(&key.topic) && self.matches_brokers(&key.brokers)
+    }
+
+    /// Matches the listener subscribed to the measured topic on the measured cluster
+    fn matches_subscription(&self, listener: &KafkaBrokerCfg) -> bool {
+        self.topic
+            .matches_subscription(&listener.topic, listener.topic_pattern)
+            && self.matches_brokers(&listener.brokers)
+    }
+
+    /// Matches the aggregated receipts or kafka timestamps of the listener
+    fn matches_listener(
+        &self,
+        key: &EndpointKey,
+        consumer_group: &str,
+        timestamp_type: Option<KafkaTimestampType>,
+    ) -> bool {
+        key.consumer_group.as_deref() == Some(consumer_group)
+            && key.timestamp_type == timestamp_type
+            && self.matches_key(key)
+    }
+}
+
+/// Route of the experiment with the provided name
+fn find_route<'a>(
+    experiment: &'a Experiment,
+    name: Option<&str>,
+) -> actix_web::Result<Option<&'a ExperimentRoute>> {
+    name.map(|name| {
+        experiment
+            .routes
+            .iter()
+            .find(|route| route.name == name)
+            .ok_or(actix_web::error::ErrorNotFound("Route not found"))
+    })
+    .transpose()
+}
+
+/// Explicitly provided endpoint or the one of the route
+fn endpoint<'a>(
+    explicit: Option<&'a KafkaLatencyRequestBroker>,
+    route: Option<&'a KafkaLatencyRequestBroker>,
+) -> actix_web::Result<&'a KafkaLatencyRequestBroker> {
+    explicit.or(route).ok_or(actix_web::error::ErrorBadRequest(
+        "Either route or source and dest must be provided",
+    ))
+}
+
 #[utoipa::path(
     tag = "measurements",
     responses(
         (status = 200, description = "Experiment stats about average message payload size in bytes", body = Vec<u128>),
         (status = 404, description = "Experiment not found"),
+        (status = 400, description = "Experiment with the aggregated storage"),
     )
 )]
-/// Get messages sizes
+/// Get messages sizes. Experiments with the aggregated storage provide only their distribution
 #[post("/bytes-size")]
 async fn messaged_bytes_size(
     params: web::Json<BytesSizeRequest>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<web::Json<Vec<u128>>> {
-    let (_, messages, _) = {
-        data.experiment_related_data(&params.0.experiment_uuid)
-            .await
-            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
-    }?;
+    let shard = data
+        .messages_state
+        .get(&params.experiment_uuid)
+        .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))?;
+    let messages = {
+        let state = shard.lock();
+        values_kept(&state.experiment, "/bytes-size-histogram")?;
+        state.messages.snapshot()
+    };
 
     Ok(web::Json(
         messages
-            .0
-            .into_values()
+            .iter()
             .map(|mess| mess.bytes_size.as_bytes())
             .collect(),
     ))
@@ -36,10 +208,56 @@ async fn messaged_bytes_size(
 #[utoipa::path(
     tag = "measurements",
     responses(
-        (status = 200, description = "Latencies calculated from send/receive events", body = Stats),
+        (status = 200, description = "Distribution of the message payload sizes in bytes", body = Distribution),
         (status = 404, description = "Experiment not found"),
     )
 )]
+/// Get distribution of the messages sizes
+#[post("/bytes-size-histogram")]
+async fn messaged_bytes_size_histogram(
+    params: web::Json<BytesSizeRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Distribution>> {
+    let shard = data
+        .messages_state
+        .get(&params.experiment_uuid)
+        .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))?;
+    let messages = {
+        let state = shard.lock();
+        match &state.aggregator {
+            Some(aggregator) => return Ok(web::Json(aggregator.stats().sizes.distribution())),
+            None => state.messages.snapshot(),
+        }
+    };
+
+    Ok(web::Json(
+        messages
+            .iter()
+            .map(|mess| mess.bytes_size.as_bytes() as u64)
+            .collect::<Histogram>()
+            .distribution(),
+    ))
+}
+
+/// Values of the measurement are not kept for the experiments with the aggregated storage,
+/// only their distribution provided by the histogram endpoint
+fn values_kept(experiment: &Experiment, histogram_path: &str) -> actix_web::Result<()> {
+    match experiment.storage {
+        StorageMode::Events => Ok(()),
+        StorageMode::Aggregated { .. } => Err(actix_web::error::ErrorBadRequest(format!(
+            "Experiment with the aggregated storage keeps only the distribution, see /measurements{histogram_path}"
+        ))),
+    }
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Latencies calculated from send/receive events", body = Stats),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern, no source and dest or experiment with the aggregated storage"),
+    )
+)]
 #[post("/send-receive-latency")]
 /// Get statistical information about latencies calculated from
 /// send/receive reported from different consumers
@@ -47,26 +265,378 @@ async fn send_receive_latencies(
     params: web::Json<SendReceiveLatencyRequest>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<web::Json<Vec<u128>>> {
-    let (_, _, events) = {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+    values_kept(&experiment, "/send-receive-histogram")?;
+
+    Ok(web::Json(
+        send_receive_points(&experiment, &events, &params)?
+            .into_iter()
+            .map(|(_, point)| point.latency_ms)
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Distribution of the send/receive latencies", body = Distribution),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/send-receive-histogram")]
+/// Get distribution of the latencies calculated from send/receive reported from different
+/// consumers
+async fn send_receive_histogram(
+    params: web::Json<SendReceiveLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Distribution>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+
+    if let Some(route) = data.with_aggregates(&params.experiment_uuid, |stats| {
+        aggregated_route(&experiment, stats, &params)
+    }) {
+        return Ok(web::Json(route?.latencies.distribution()));
+    }
+
+    Ok(web::Json(
+        send_receive_points(&experiment, &events, &params)?
+            .into_iter()
+            .map(|(_, point)| point.latency_ms as u64)
+            .collect::<Histogram>()
+            .distribution(),
+    ))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Send/receive latencies over time with the listener rebalances and churns", body = LatencyTimeline),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/send-receive-timeline")]
+/// Get send/receive latencies by the receive time together with the rebalances and churns of
+/// the experiment listeners, so latency outliers can be lined up with them. Experiments with
+/// the aggregated storage have the average latency of each second instead, or of the longer
+/// periods for the long experiments
+async fn send_receive_timeline(
+    params: web::Json<SendReceiveLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<LatencyTimeline>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+
+    let route = data.with_aggregates(&params.experiment_uuid, |stats| {
+        aggregated_route(&experiment, stats, &params)
+    });
+
+    let mut points: Vec<_> = match route {
+        Some(route) => route?
+            .timeline
+            .buckets()
+            .filter_map(|(start, stats)| {
+                Some(LatencyPoint {
+                    timestamp_millis: start as u128 * 1000,
+                    latency_ms: stats.min_max_avg()?.avg,
+                })
+            })
+            .collect(),
+        None => send_receive_points(&experiment, &events, &params)?
+            .into_iter()
+            .map(|(_, point)| point)
+            .collect(),
+    };
+    points.sort_by_key(|point| point.timestamp_millis);
+
+    Ok(web::Json(LatencyTimeline {
+        points,
+        rebalances: data.experiment_rebalances(&params.experiment_uuid),
+        churns: data.experiment_churns(&params.experiment_uuid),
+    }))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Received messages, duplicates and latencies between the churns of the dest listener", body = Vec<ChurnPeriodStats>),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/churn-periods")]
+/// Get send/receive stats split by the churns injected into the dest listener, to see
+/// how the consumer group recovers from each of them
+async fn churn_periods(
+    params: web::Json<SendReceiveLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Vec<ChurnPeriodStats>>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+
+    let dest_broker = dest_endpoint(&experiment, &params)?;
+    let dest = EndpointFilter::from_broker(dest_broker)?;
+
+    let mut churns: Vec<_> = data
+        .experiment_churns(&params.experiment_uuid)
+        .into_iter()
+        .filter(|churn| {
+            churn.consumer_group == dest_broker.consumer_group
+                && dest.topic.matches(&churn.topic)
+                && dest.matches_brokers(&churn.brokers)
+        })
+        .collect();
+    churns.sort_by_key(|churn| churn.timestamp_millis);
+
+    let mut periods = vec![ChurnPeriodStats {
+        start_millis: experiment.experiment_start_timestamp_millis,
+        end_millis: None,
+        churn: None,
+        received_messages: 0,
+        duplicated_messages: 0,
+        latencies_ms: None,
+    }];
+
+    for churn in churns {
+        if let Some(last) = periods.last_mut() {
+            last.end_millis = Some(churn.timestamp_millis);
+        }
+
+        periods.push(ChurnPeriodStats {
+            start_millis: churn.timestamp_millis,
+            end_millis: None,
+            churn: Some(churn.churn_type),
+            received_messages: 0,
+            duplicated_messages: 0,
+            latencies_ms: None,
+        });
+    }
+
+    let period_index = |periods: &[ChurnPeriodStats], timestamp_millis| {
+        periods
+            .partition_point(|period| period.start_millis <= timestamp_millis)
+            .saturating_sub(1)
+    };
+
+    // Receipts are aggregated by the timeline buckets, so the periods are split with that
+    // precision
+    if let Some(route) = data.with_aggregates(&params.experiment_uuid, |stats| {
+        aggregated_route(&experiment, stats, &params)
+    }) {
+        let mut merged = vec![BucketStats::default(); periods.len()];
+
+        for (start, stats) in route?.timeline.buckets() {
+            merged[period_index(&periods, start as u128 * 1000)].merge(stats);
+        }
+
+        for (period, stats) in periods.iter_mut().zip(merged) {
+            period.received_messages = stats.received as usize;
+            period.duplicated_messages = stats.duplicates as usize;
+            period.latencies_ms = stats.min_max_avg();
+        }
+
+        return Ok(web::Json(periods));
+    }
+
+    let mut points = send_receive_points(&experiment, &events, &params)?;
+    points.sort_by_key(|(_, point)| point.timestamp_millis);
+
+    let mut seen = HashSet::new();
+    let mut latencies = vec![Vec::new(); periods.len()];
+
+    for (message_uuid, point) in points {
+        let index = period_index(&periods, point.timestamp_millis);
+        let period = &mut periods[index];
+
+        period.received_messages += 1;
+        if !seen.insert(message_uuid) {
+            period.duplicated_messages += 1;
+        }
+        latencies[index].push(point.latency_ms);
+    }
+
+    for (period, latencies) in periods.iter_mut().zip(latencies) {
+        period.latencies_ms = min_max_avg(&latencies);
+    }
+
+    Ok(web::Json(periods))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Sent, received, duplicated and lost messages of the route", body = DeliveryStats),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/delivery")]
+/// Get how many of the messages sent to the source have been received by the dest listener
+async fn delivery(
+    params: web::Json<SendReceiveLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<DeliveryStats>> {
+    let (experiment, events) = {
         data.experiment_related_data(&params.0.experiment_uuid)
-            .await
             .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
     }?;
 
+    if let Some(delivery) = data.with_aggregates(&params.experiment_uuid, |stats| {
+        aggregated_delivery(&experiment, stats, &params)
+    }) {
+        return Ok(web::Json(delivery?));
+    }
+
+    let (source, _, _) = send_receive_filters(&experiment, &params)?;
+    let sent_messages = events
+        .iter()
+        .filter(|event| matches!(&event.event_type, EventTypeRef::Sent))
+        .filter(|event| source.matches(event))
+        .map(|event| event.message_uuid)
+        .collect::<HashSet<_>>()
+        .len();
+
+    let points = send_receive_points(&experiment, &events, &params)?;
+    let received_messages = points
+        .iter()
+        .map(|(message_uuid, _)| message_uuid)
+        .collect::<HashSet<_>>()
+        .len();
+
+    Ok(web::Json(DeliveryStats {
+        sent_messages,
+        received_messages,
+        duplicated_messages: points.len() - received_messages,
+        lost_messages: sent_messages.saturating_sub(received_messages),
+        pending_messages: 0,
+    }))
+}
+
+/// Delivery of the route of the experiment with the aggregated storage
+fn aggregated_delivery(
+    experiment: &Experiment,
+    stats: &AggregateStats,
+    params: &SendReceiveLatencyRequest,
+) -> actix_web::Result<DeliveryStats> {
+    let (source, dest_broker, dest) = send_receive_filters(experiment, params)?;
+    let route = aggregated_route(experiment, stats, params)?;
+    let by_source = |counts: &HashMap<u16, u64>| -> u64 {
+        counts
+            .iter()
+            .filter(|(id, _)| source.matches_key(stats.endpoint(**id)))
+            .map(|(_, count)| count)
+            .sum()
+    };
+
+    Ok(DeliveryStats {
+        sent_messages: by_source(&stats.sent) as usize,
+        received_messages: (route.received - route.duplicates) as usize,
+        duplicated_messages: route.duplicates as usize,
+        lost_messages: stats
+            .lost
+            .iter()
+            .filter(|((id, brokers, consumer_group), _)| {
+                *consumer_group == dest_broker.consumer_group
+                    && dest.matches_brokers(brokers)
+                    && source.matches_key(stats.endpoint(*id))
+            })
+            .map(|(_, count)| *count as usize)
+            .sum(),
+        pending_messages: by_source(&stats.pending) as usize,
+    })
+}
+
+fn min_max_avg(values: &[u128]) -> Option<MinMaxAvg> {
+    Some(MinMaxAvg {
+        min: *values.iter().min()?,
+        max: *values.iter().max()?,
+        avg: values.iter().sum::<u128>() / values.len() as u128,
+    })
+}
+
+/// Dest endpoint of the send/receive request, either explicit or of the route
+fn dest_endpoint<'a>(
+    experiment: &'a Experiment,
+    params: &'a SendReceiveLatencyRequest,
+) -> actix_web::Result<&'a KafkaLatencyRequestBroker> {
+    let route = find_route(experiment, params.route.as_deref())?;
+    endpoint(params.dest.as_ref(), route.map(|x| &x.dest))
+}
+
+/// Source and dest of the send/receive request, either explicit or of the route
+fn send_receive_filters<'a>(
+    experiment: &'a Experiment,
+    params: &'a SendReceiveLatencyRequest,
+) -> actix_web::Result<(
+    EndpointFilter,
+    &'a KafkaLatencyRequestBroker,
+    EndpointFilter,
+)> {
+    let route = find_route(experiment, params.route.as_deref())?;
+
+    let source = match &params.source {
+        Some(source) => EndpointFilter::from_source(source)?,
+        None => EndpointFilter::from_broker(endpoint(None, route.map(|x| &x.source))?)?,
+    };
+    let dest_broker = dest_endpoint(experiment, params)?;
+    let dest = EndpointFilter::from_broker(dest_broker)?;
+
+    Ok((source, dest_broker, dest))
+}
+
+/// Statistics of the aggregated routes from the source to the dest listener, merged
+fn aggregated_route(
+    experiment: &Experiment,
+    stats: &AggregateStats,
+    params: &SendReceiveLatencyRequest,
+) -> actix_web::Result<RouteStats> {
+    let (source, dest_broker, dest) = send_receive_filters(experiment, params)?;
+
+    let mut merged = RouteStats::default();
+
+    for ((source_id, dest_id), route) in &stats.routes {
+        if source.matches_key(stats.endpoint(*source_id))
+            && dest.matches_listener(stats.endpoint(*dest_id), &dest_broker.consumer_group, None)
+        {
+            merged.merge(route);
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Latencies between sending to the source and receiving from the dest by the receive time,
+/// with the uuids of the received messages
+fn send_receive_points(
+    experiment: &Experiment,
+    events: &EventsSnapshot,
+    params: &SendReceiveLatencyRequest,
+) -> actix_web::Result<Vec<(Uuid, LatencyPoint)>> {
+    let (source, dest_broker, dest) = send_receive_filters(experiment, params)?;
+
     let kafka_sent_events_source = events
         .iter()
-        .filter(|event| matches!(&event.event_type, EventType::Sent))
-        .filter(|event| {
-            event.topic == params.source.topic && event.brokers == params.source.brokers
-        });
+        .filter(|event| matches!(&event.event_type, EventTypeRef::Sent))
+        .filter(|event| source.matches(event));
 
     let kafka_received_events_dest = events
         .iter()
         .filter(|event| {
-            matches! {&event.event_type, EventType::Received { consumer_group }
-            if consumer_group == &params.0.dest.consumer_group}
+            matches! {event.event_type, EventTypeRef::Received { consumer_group }
+            if consumer_group == dest_broker.consumer_group}
         })
-        .filter(|event| event.topic == params.dest.topic && event.brokers == params.dest.brokers);
+        .filter(|event| dest.matches(event));
 
     let mut source_timestamps = HashMap::new();
 
@@ -77,6 +647,127 @@ async fn send_receive_latencies(
     let mut result = Vec::new();
 
     for dest in kafka_received_events_dest {
+        if let Some(source_value) = source_timestamps.get(&dest.message_uuid).cloned() {
+            if dest.timestamp_millis >= source_value {
+                result.push((
+                    dest.message_uuid,
+                    LatencyPoint {
+                        timestamp_millis: dest.timestamp_millis,
+                        latency_ms: dest.timestamp_millis - source_value,
+                    },
+                ))
+            } else {
+                tracing::warn!("Destination timestamp is lower than source timestamp");
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn kafka_timestamp_events<'a>(
+    events: &'a EventsSnapshot,
+    broker: &'a KafkaLatencyRequestBroker,
+    timestamp_type: Option<KafkaTimestampType>,
+) -> actix_web::Result<impl Iterator<Item = (EventRef<'a>, KafkaTimestampType)>> {
+    let endpoint = EndpointFilter::from_broker(broker)?;
+
+    Ok(events
+        .iter()
+        .filter_map(move |event| match event.event_type {
+            EventTypeRef::KafkaTimestampSet {
+                consumer_group,
+                timestamp_type: event_timestamp_type,
+            } if consumer_group == broker.consumer_group
+                && timestamp_type.is_none_or(|x| x == event_timestamp_type) =>
+            {
+                Some((event, event_timestamp_type))
+            }
+            _ => None,
+        })
+        .filter(move |(event, _)| endpoint.matches(event)))
+}
+
+/// Time of the message seen by the listener
+#[derive(Clone, Copy)]
+enum ListenerTime {
+    /// Receive time of the message
+    Received,
+    /// Kafka timestamp of the message, optionally of the specific type
+    Kafka(Option<KafkaTimestampType>),
+}
+
+impl ListenerTime {
+    fn matches(&self, timestamp_type: Option<KafkaTimestampType>) -> bool {
+        match self {
+            Self::Received => timestamp_type.is_none(),
+            Self::Kafka(expected) => {
+                timestamp_type.is_some_and(|x| expected.is_none_or(|y| x == y))
+            }
+        }
+    }
+}
+
+/// Aggregated times of the messages seen by the source and dest listeners
+fn aggregated_kafka<'a>(
+    stats: &'a AggregateStats,
+    (source, source_time): (&KafkaLatencyRequestBroker, ListenerTime),
+    (dest, dest_time): (&KafkaLatencyRequestBroker, ListenerTime),
+) -> actix_web::Result<Vec<&'a KafkaRouteStats>> {
+    let source_filter = EndpointFilter::from_broker(source)?;
+    let dest_filter = EndpointFilter::from_broker(dest)?;
+
+    let matches = |filter: &EndpointFilter,
+                   broker: &KafkaLatencyRequestBroker,
+                   time: ListenerTime,
+                   key: &EndpointKey| {
+        time.matches(key.timestamp_type)
+            && filter.matches_listener(key, &broker.consumer_group, key.timestamp_type)
+    };
+
+    Ok(stats
+        .kafka
+        .iter()
+        .filter(|((source_id, dest_id), _)| {
+            matches(
+                &source_filter,
+                source,
+                source_time,
+                stats.endpoint(*source_id),
+            ) && matches(&dest_filter, dest, dest_time, stats.endpoint(*dest_id))
+        })
+        .map(|(_, pair)| pair)
+        .collect())
+}
+
+/// Latencies of the aggregated experiment between the times of the source and dest listeners
+fn aggregated_kafka_latencies(
+    stats: &AggregateStats,
+    source: (&KafkaLatencyRequestBroker, ListenerTime),
+    dest: (&KafkaLatencyRequestBroker, ListenerTime),
+) -> actix_web::Result<Histogram> {
+    let mut latencies = Histogram::default();
+    for pair in aggregated_kafka(stats, source, dest)? {
+        latencies.merge(&pair.latencies);
+    }
+
+    Ok(latencies)
+}
+
+/// Differences of the dest and source times of the same messages, if not negative
+fn time_differences<'a>(
+    source: impl Iterator<Item = EventRef<'a>>,
+    dest: impl Iterator<Item = EventRef<'a>>,
+) -> Vec<u128> {
+    let mut source_timestamps = HashMap::new();
+
+    for source in source {
+        source_timestamps.insert(source.message_uuid, source.timestamp_millis);
+    }
+
+    let mut result = Vec::new();
+
+    for dest in dest {
         if let Some(source_value) = source_timestamps.get(&dest.message_uuid).cloned() {
             if dest.timestamp_millis >= source_value {
                 result.push(dest.timestamp_millis - source_value)
@@ -86,62 +777,610 @@ async fn send_receive_latencies(
         }
     }
 
-    Ok(web::Json(result))
+    result
+}
+
+fn received_events<'a>(
+    events: &'a EventsSnapshot,
+    broker: &'a KafkaLatencyRequestBroker,
+) -> actix_web::Result<impl Iterator<Item = EventRef<'a>>> {
+    let endpoint = EndpointFilter::from_broker(broker)?;
+
+    Ok(events
+        .iter()
+        .filter(move |event| {
+            matches! {event.event_type, EventTypeRef::Received { consumer_group }
+            if consumer_group == broker.consumer_group}
+        })
+        .filter(move |event| endpoint.matches(event)))
+}
+
+/// Events of the listener with its receive times or kafka timestamps
+fn listener_events<'a>(
+    events: &'a EventsSnapshot,
+    broker: &'a KafkaLatencyRequestBroker,
+    time: ListenerTime,
+) -> actix_web::Result<Box<dyn Iterator<Item = EventRef<'a>> + 'a>> {
+    Ok(match time {
+        ListenerTime::Received => Box::new(received_events(events, broker)?),
+        ListenerTime::Kafka(timestamp_type) => Box::new(
+            kafka_timestamp_events(events, broker, timestamp_type)?.map(|(event, _)| event),
+        ),
+    })
+}
+
+/// Source and dest listeners of the request, either explicit or of the route
+fn listener_endpoints<'a>(
+    experiment: &'a Experiment,
+    route: Option<&str>,
+    source: Option<&'a KafkaLatencyRequestBroker>,
+    dest: Option<&'a KafkaLatencyRequestBroker>,
+) -> actix_web::Result<(&'a KafkaLatencyRequestBroker, &'a KafkaLatencyRequestBroker)> {
+    let route = find_route(experiment, route)?;
+
+    Ok((
+        endpoint(source, route.map(|x| &x.source))?,
+        endpoint(dest, route.map(|x| &x.dest))?,
+    ))
+}
+
+/// Distribution of the latencies between the times of the source and dest listeners
+fn listener_latencies_distribution(
+    data: &AppData,
+    (experiment_uuid, events): (&Uuid, &EventsSnapshot),
+    (source, source_time): (&KafkaLatencyRequestBroker, ListenerTime),
+    (dest, dest_time): (&KafkaLatencyRequestBroker, ListenerTime),
+) -> actix_web::Result<Distribution> {
+    if let Some(latencies) = data.with_aggregates(experiment_uuid, |stats| {
+        aggregated_kafka_latencies(stats, (source, source_time), (dest, dest_time))
+    }) {
+        return Ok(latencies?.distribution());
+    }
+
+    Ok(time_differences(
+        listener_events(events, source, source_time)?,
+        listener_events(events, dest, dest_time)?,
+    )
+    .into_iter()
+    .map(|latency| latency as u64)
+    .collect::<Histogram>()
+    .distribution())
 }
+
 #[utoipa::path(
     tag = "measurements",
     responses(
         (status = 200, description = "Get summarized experiment data", body = Vec<u128>),
-        (status = 404, description = "Experiment not found"),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern, no source and dest or experiment with the aggregated storage"),
     )
 )]
 #[post("/kafka-latencies")]
-/// Get statistical information about latencies reported by kafka from different consumers
+/// Get statistical information about latencies between receiving the message by different
+/// consumers. Latencies of the kafka timestamps are provided by `/kafka-timestamp-latencies`
 async fn kafka_latencies(
     params: web::Json<KafkaLatencyRequest>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<web::Json<Vec<u128>>> {
-    let (_, _, events) = {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+    values_kept(&experiment, "/kafka-latencies-histogram")?;
+
+    let (source, dest) = listener_endpoints(
+        &experiment,
+        params.route.as_deref(),
+        params.source.as_ref(),
+        params.dest.as_ref(),
+    )?;
+
+    Ok(web::Json(time_differences(
+        received_events(&events, source)?,
+        received_events(&events, dest)?,
+    )))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Distribution of the latencies between receiving the message by different consumers", body = Distribution),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/kafka-latencies-histogram")]
+/// Get distribution of the latencies between receiving the message by different consumers
+async fn kafka_latencies_histogram(
+    params: web::Json<KafkaLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Distribution>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+
+    let (source, dest) = listener_endpoints(
+        &experiment,
+        params.route.as_deref(),
+        params.source.as_ref(),
+        params.dest.as_ref(),
+    )?;
+
+    Ok(web::Json(listener_latencies_distribution(
+        &data,
+        (&params.experiment_uuid, &events),
+        (source, ListenerTime::Received),
+        (dest, ListenerTime::Received),
+    )?))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Differences of the source and dest kafka timestamps", body = Vec<u128>),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern, no source and dest or experiment with the aggregated storage"),
+    )
+)]
+#[post("/kafka-timestamp-latencies")]
+/// Get statistical information about latencies between the kafka timestamps of the message
+/// seen by different consumers. Kafka timestamps of the specific type (CreateTime or
+/// LogAppendTime) can be selected for both the source and the dest
+async fn kafka_timestamp_latencies(
+    params: web::Json<KafkaTimestampLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Vec<u128>>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+    values_kept(&experiment, "/kafka-timestamp-histogram")?;
+
+    let (source, dest) = listener_endpoints(
+        &experiment,
+        params.route.as_deref(),
+        params.source.as_ref(),
+        params.dest.as_ref(),
+    )?;
+
+    Ok(web::Json(time_differences(
+        listener_events(
+            &events,
+            source,
+            ListenerTime::Kafka(params.source_timestamp_type),
+        )?,
+        listener_events(
+            &events,
+            dest,
+            ListenerTime::Kafka(params.dest_timestamp_type),
+        )?,
+    )))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Distribution of the differences of the source and dest kafka timestamps", body = Distribution),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/kafka-timestamp-histogram")]
+/// Get distribution of the latencies between the kafka timestamps of the message seen by
+/// different consumers
+async fn kafka_timestamp_histogram(
+    params: web::Json<KafkaTimestampLatencyRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<Distribution>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+
+    let (source, dest) = listener_endpoints(
+        &experiment,
+        params.route.as_deref(),
+        params.source.as_ref(),
+        params.dest.as_ref(),
+    )?;
+
+    Ok(web::Json(listener_latencies_distribution(
+        &data,
+        (&params.experiment_uuid, &events),
+        (source, ListenerTime::Kafka(params.source_timestamp_type)),
+        (dest, ListenerTime::Kafka(params.dest_timestamp_type)),
+    )?))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Comparison of the source and dest kafka timestamps", body = TimestampPreservationStats),
+        (status = 404, description = "Experiment or route not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/timestamp-preservation")]
+/// Check whether the dest kafka timestamps equal the source CreateTime timestamps. Equal
+/// timestamps mean that the timestamp was copied (e.g. by the replicator) rather than re-stamped
+async fn timestamp_preservation(
+    params: web::Json<TimestampPreservationRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<TimestampPreservationStats>> {
+    let (experiment, events) = {
         data.experiment_related_data(&params.0.experiment_uuid)
-            .await
             .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
     }?;
 
-    let kafka_received_events_source = events
+    let route = find_route(&experiment, params.route.as_deref())?;
+    let source = endpoint(params.source.as_ref(), route.map(|x| &x.source))?;
+    let dest = endpoint(params.dest.as_ref(), route.map(|x| &x.dest))?;
+
+    let aggregated = data.with_aggregates(&params.experiment_uuid, |stats| {
+        Ok::<_, actix_web::Error>(
+            aggregated_kafka(
+                stats,
+                (
+                    source,
+                    ListenerTime::Kafka(Some(KafkaTimestampType::CreateTime)),
+                ),
+                (dest, ListenerTime::Kafka(None)),
+            )?
+            .into_iter()
+            .fold((0, 0), |(compared, copied), pair| {
+                (
+                    compared + pair.compared as usize,
+                    copied + pair.copied as usize,
+                )
+            }),
+        )
+    });
+
+    let (compared_messages, copied_timestamps) = match aggregated {
+        Some(compared) => compared?,
+        None => compare_timestamps(&events, source, dest)?,
+    };
+
+    Ok(web::Json(TimestampPreservationStats {
+        compared_messages,
+        copied_timestamps,
+        restamped_timestamps: compared_messages - copied_timestamps,
+        timestamps_copied: compared_messages > 0 && copied_timestamps == compared_messages,
+    }))
+}
+
+/// Messages with the source CreateTime and any dest kafka timestamp, and those which
+/// timestamps are equal
+fn compare_timestamps(
+    events: &EventsSnapshot,
+    source: &KafkaLatencyRequestBroker,
+    dest: &KafkaLatencyRequestBroker,
+) -> actix_web::Result<(usize, usize)> {
+    let mut source_timestamps = HashMap::new();
+
+    for (source, _) in kafka_timestamp_events(events, source, Some(KafkaTimestampType::CreateTime))?
+    {
+        source_timestamps.insert(source.message_uuid, source.timestamp_millis);
+    }
+
+    let mut compared_messages = 0;
+    let mut copied_timestamps = 0;
+
+    for (dest, _) in kafka_timestamp_events(events, dest, None)? {
+        if let Some(source_value) = source_timestamps.get(&dest.message_uuid) {
+            compared_messages += 1;
+            if dest.timestamp_millis == *source_value {
+                copied_timestamps += 1;
+            }
+        }
+    }
+
+    Ok((compared_messages, copied_timestamps))
+}
+
+#[utoipa::path(
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Transactional messages seen by the listener", body = TransactionVerification),
+        (status = 404, description = "Experiment, route or listener not found"),
+        (status = 400, description = "Invalid topic pattern or no source and dest"),
+    )
+)]
+#[post("/transactions")]
+/// Verify that messages from the aborted transactions never show up for read committed listener
+/// and do show up for read uncommitted one
+async fn transactions_verification(
+    params: web::Json<TransactionVerificationRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<TransactionVerification>> {
+    let (experiment, events) = {
+        data.experiment_related_data(&params.0.experiment_uuid)
+            .ok_or(actix_web::error::ErrorNotFound("Experiment not found"))
+    }?;
+
+    let route = find_route(&experiment, params.route.as_deref())?;
+    let dest_broker = endpoint(params.dest.as_ref(), route.map(|x| &x.dest))?;
+    let dest = EndpointFilter::from_broker(dest_broker)?;
+
+    let listener = experiment
+        .consumers
         .iter()
-        .filter(|event| {
-            matches! {&event.event_type, EventType::Received { consumer_group }
-            if consumer_group == &params.0.source.consumer_group}
+        .find(|listener| {
+            listener.consumer_group_id == dest_broker.consumer_group
+                && dest.matches_subscription(listener)
         })
-        .filter(|event| {
-            event.topic == params.source.topic && event.brokers == params.source.brokers
-        });
+        .ok_or(actix_web::error::ErrorNotFound("Listener not found"))?;
 
-    let kafka_received_events_dest = events
+    let isolation_level = listener.isolation_level.unwrap_or_default();
+
+    if let Some(verification) = data.with_aggregates(&params.experiment_uuid, |stats| {
+        let (committed_received, aborted_received) = stats
+            .transactions_received
+            .iter()
+            .filter(|(id, _)| {
+                dest.matches_listener(stats.endpoint(**id), &dest_broker.consumer_group, None)
+            })
+            .fold((0, 0), |(committed, aborted), (_, receipts)| {
+                (
+                    committed + receipts.committed as usize,
+                    aborted + receipts.aborted as usize,
+                )
+            });
+
+        transaction_verification(
+            isolation_level,
+            (stats.committed as usize, stats.aborted as usize),
+            (committed_received, aborted_received),
+        )
+    }) {
+        return Ok(web::Json(verification));
+    }
+
+    let mut committed = HashSet::new();
+    let mut aborted = HashSet::new();
+
+    for event in events.iter() {
+        match event.event_type {
+            EventTypeRef::TransactionCommitted => {
+                committed.insert(event.message_uuid);
+            }
+            EventTypeRef::TransactionAborted => {
+                aborted.insert(event.message_uuid);
+            }
+            _ => {}
+        }
+    }
+
+    let received: HashSet<_> = events
         .iter()
         .filter(|event| {
-            matches! {&event.event_type, EventType::Received { consumer_group }
-            if consumer_group == &params.0.dest.consumer_group}
+            matches! {event.event_type, EventTypeRef::Received { consumer_group }
+            if consumer_group == dest_broker.consumer_group}
         })
-        .filter(|event| event.topic == params.dest.topic && event.brokers == params.dest.brokers);
+        .filter(|event| dest.matches(event))
+        .map(|event| event.message_uuid)
+        .collect();
 
-    let mut source_timestamps = HashMap::new();
+    Ok(web::Json(transaction_verification(
+        isolation_level,
+        (committed.len(), aborted.len()),
+        (
+            committed.intersection(&received).count(),
+            aborted.intersection(&received).count(),
+        ),
+    )))
+}
 
-    for source in kafka_received_events_source {
-        source_timestamps.insert(source.message_uuid, source.timestamp_millis);
+fn transaction_verification(
+    isolation_level: IsolationLevel,
+    (committed_messages, aborted_messages): (usize, usize),
+    (committed_received, aborted_received): (usize, usize),
+) -> TransactionVerification {
+    // Listener that has received nothing does not verify the isolation
+    let verified = committed_received == committed_messages
+        && match isolation_level {
+            IsolationLevel::ReadCommitted => aborted_received == 0,
+            IsolationLevel::ReadUncommitted => aborted_received == aborted_messages,
+        };
+
+    TransactionVerification {
+        isolation_level,
+        committed_messages,
+        aborted_messages,
+        committed_received,
+        aborted_received,
+        verified,
     }
+}
 
-    let mut result = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventStore;
+    use crate::models::{EventType, MessageEvent};
 
-    for dest in kafka_received_events_dest {
-        if let Some(source_value) = source_timestamps.get(&dest.message_uuid).cloned() {
-            if dest.timestamp_millis >= source_value {
-                result.push(dest.timestamp_millis - source_value)
-            } else {
-                tracing::warn!("Destination timestamp is lower than source timestamp");
-            }
+    const BROKERS: &str = "localhost:9092";
+
+    fn brokers(values: &[&str]) -> BTreeSet<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn broker(consumer_group: &str) -> KafkaLatencyRequestBroker {
+        serde_json::from_value(serde_json::json!({
+            "brokers": BROKERS,
+            "topic": "topic",
+            "consumer_group": consumer_group,
+        }))
+        .unwrap()
+    }
+
+    fn event(message: u64, timestamp_millis: u128, event_type: EventType) -> MessageEvent {
+        MessageEvent {
+            message_uuid: Uuid::from_u64_pair(0, message),
+            timestamp_millis,
+            brokers: BROKERS.into(),
+            topic: "topic".into(),
+            event_type,
         }
     }
 
-    Ok(web::Json(result))
+    fn kafka_timestamp(consumer_group: &str, timestamp_type: KafkaTimestampType) -> EventType {
+        EventType::KafkaTimestampSet {
+            consumer_group: consumer_group.into(),
+            timestamp_type,
+        }
+    }
+
+    fn events(events: impl IntoIterator<Item = MessageEvent>) -> EventsSnapshot {
+        let mut store = EventStore::default();
+        for event in events {
+            store.push(event);
+        }
+        store.snapshot()
+    }
+
+    fn messages<'a>(events: impl Iterator<Item = EventRef<'a>>) -> Vec<u64> {
+        events.map(|x| x.message_uuid.as_u64_pair().1).collect()
+    }
+
+    #[test]
+    fn brokers_are_normalized() {
+        assert_eq!(
+            normalize_brokers(" SSL://Kafka-1:9093/, kafka-2:9093,,kafka-1:9093 "),
+            brokers(&["kafka-1:9093", "kafka-2:9093"])
+        );
+        assert_eq!(
+            normalize_brokers("kafka-2:9093,kafka-1:9093"),
+            normalize_brokers("PLAINTEXT://kafka-1:9093,kafka-2:9093")
+        );
+        assert!(normalize_brokers(" , ").is_empty());
+    }
+
+    #[test]
+    fn exact_topic_matches_only_itself() {
+        let filter = TopicFilter::new("orders.v1", false).unwrap();
+
+        assert!(filter.matches("orders.v1"));
+        assert!(!filter.matches("ordersXv1"));
+        assert!(!filter.matches("dc1.orders.v1"));
+    }
+
+    #[test]
+    fn topic_pattern_matches_regex() {
+        let filter = TopicFilter::new("^dc[0-9]+\\.orders$", true).unwrap();
+
+        assert!(filter.matches("dc1.orders"));
+        assert!(filter.matches("dc42.orders"));
+        assert!(!filter.matches("orders"));
+        assert!(TopicFilter::new("(", true).is_err());
+    }
+
+    #[test]
+    fn subscription_matches_topic_or_pattern() {
+        let exact = TopicFilter::new("dc1.orders", false).unwrap();
+        assert!(exact.matches_subscription("dc1.orders", false));
+        assert!(exact.matches_subscription("^dc[0-9]+\\.orders$", true));
+        assert!(!exact.matches_subscription("^dc[0-9]+\\.payments$", true));
+
+        let pattern = TopicFilter::new("^dc[0-9]+\\.orders$", true).unwrap();
+        assert!(pattern.matches_subscription("dc1.orders", false));
+        assert!(pattern.matches_subscription("^dc[0-9]+\\.orders$", true));
+        assert!(!pattern.matches_subscription("^dc1\\.orders$", true));
+    }
+
+    #[test]
+    fn listener_subscription_matches_broker_aliases() {
+        let listener: KafkaBrokerCfg = serde_json::from_value(serde_json::json!({
+            "brokers": "kafka-dc1:9092",
+            "topic": "^dc[0-9]+\\.orders$",
+            "topic_pattern": true,
+            "ssl": false,
+            "message_timeout": "2s",
+            "consumer_group_id": "g",
+        }))
+        .unwrap();
+        let aliases = ["kafka-dc1:9092".to_string()];
+
+        let filter = EndpointFilter::new("kafka-lb:9092", &aliases, "dc1.orders", false).unwrap();
+        assert!(filter.matches_subscription(&listener));
+
+        let filter = EndpointFilter::new("kafka-lb:9092", &[], "dc1.orders", false).unwrap();
+        assert!(!filter.matches_subscription(&listener));
+    }
+
+    #[test]
+    fn kafka_timestamps_are_chosen_by_type() {
+        let (source, dest) = (broker("source"), broker("dest"));
+        let events = events([
+            event(
+                1,
+                100,
+                kafka_timestamp("source", KafkaTimestampType::CreateTime),
+            ),
+            event(
+                1,
+                150,
+                kafka_timestamp("dest", KafkaTimestampType::LogAppendTime),
+            ),
+            event(
+                2,
+                200,
+                kafka_timestamp("source", KafkaTimestampType::CreateTime),
+            ),
+            event(
+                2,
+                200,
+                kafka_timestamp("dest", KafkaTimestampType::CreateTime),
+            ),
+        ]);
+
+        let append_time = Some(KafkaTimestampType::LogAppendTime);
+        let dest_events = kafka_timestamp_events(&events, &dest, append_time).unwrap();
+        assert_eq!(messages(dest_events.map(|(x, _)| x)), vec![1]);
+
+        let source_events = listener_events(&events, &source, ListenerTime::Kafka(None)).unwrap();
+        let dest_events = listener_events(&events, &dest, ListenerTime::Kafka(None)).unwrap();
+        assert_eq!(time_differences(source_events, dest_events), vec![50, 0]);
+
+        assert_eq!(compare_timestamps(&events, &source, &dest).unwrap(), (2, 1));
+    }
+
+    #[test]
+    fn listener_time_matches_receipts_or_kafka_timestamps() {
+        let create_time = Some(KafkaTimestampType::CreateTime);
+        let append_time = Some(KafkaTimestampType::LogAppendTime);
+
+        assert!(ListenerTime::Received.matches(None));
+        assert!(!ListenerTime::Received.matches(create_time));
+        assert!(ListenerTime::Kafka(None).matches(append_time));
+        assert!(!ListenerTime::Kafka(None).matches(None));
+        assert!(ListenerTime::Kafka(create_time).matches(create_time));
+        assert!(!ListenerTime::Kafka(create_time).matches(append_time));
+    }
+
+    #[test]
+    fn read_committed_listener_sees_no_aborted_messages() {
+        let verification =
+            transaction_verification(IsolationLevel::ReadCommitted, (10, 5), (10, 0));
+        assert!(verification.verified);
+
+        let leaked = transaction_verification(IsolationLevel::ReadCommitted, (10, 5), (10, 1));
+        assert!(!leaked.verified);
+        assert_eq!(leaked.aborted_received, 1);
+
+        // Listener that has received nothing does not verify the isolation
+        let idle = transaction_verification(IsolationLevel::ReadCommitted, (10, 5), (0, 0));
+        assert!(!idle.verified);
+    }
+
+    #[test]
+    fn read_uncommitted_listener_sees_every_message() {
+        let verification =
+            transaction_verification(IsolationLevel::ReadUncommitted, (10, 5), (10, 5));
+        assert!(verification.verified);
+
+        let missing = transaction_verification(IsolationLevel::ReadUncommitted, (10, 5), (10, 4));
+        assert!(!missing.verified);
+    }
 }
@@ -1,45 +1,191 @@
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
 
-use actix_web::{Responder, http::StatusCode, post, web};
+use actix_web::{CustomizeResponder, Responder, get, http::StatusCode, post, web};
 use rand::Rng;
 use rdkafka::{
-    ClientConfig,
-    message::{Header, OwnedHeaders},
-    producer::{FutureProducer, FutureRecord},
+    error::{KafkaError, KafkaResult, RDKafkaErrorCode},
+    message::{Header, OwnedHeaders, ToBytes},
+    producer::{FutureProducer, FutureRecord, Producer as _, future_producer::Delivery},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
 
 use crate::{
-    AppData, EXPERIMENT_UUID_HEADER, MESSAGE_UUID_HEADER, get_now_millis,
-    models::{EventType, Message, MessageEvent, SendMessage, SendMessageTask, SentMessage},
-    state::MessagesState,
+    AppData, get_now_millis,
+    models::{
+        BackpressureStats, CorrelationHeaders, DeliveryFailureReason, EventType,
+        ExperimentLifecycle, JobScheduled, JobStatus, JobStatusRequest, Message, MessageEvent,
+        Payload, ProducerCfg, QueueFullRetry, SendMessage, SendMessageTask, SentMessage,
+        TransactionCfg,
+    },
+    producers::ProducerLease,
+    state::{JobsState, MessagesState, Record},
 };
 
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                         abcdefghijklmnopqrstuvwxyz\
                         0123456789";
 
-fn handle_message_delivery_failure(message: &mut SentMessage, bytes_unsent: usize) {
-    message.delivery_failures += 1;
-    message.total_sent_bytes -= bytes_unsent;
+fn delivery_failure_reason(error: &KafkaError) -> DeliveryFailureReason {
+    use RDKafkaErrorCode as Code;
+
+    if matches!(error, KafkaError::Canceled) {
+        return DeliveryFailureReason::Canceled;
+    }
+
+    match error.rdkafka_error_code() {
+        Some(Code::MessageTimedOut) => DeliveryFailureReason::MessageTimedOut,
+        Some(Code::QueueFull) => DeliveryFailureReason::QueueFull,
+        Some(Code::UnknownTopic | Code::UnknownTopicOrPartition | Code::UnknownPartition) => {
+            DeliveryFailureReason::UnknownTopic
+        }
+        Some(
+            Code::TopicAuthorizationFailed
+            | Code::ClusterAuthorizationFailed
+            | Code::TransactionalIdAuthorizationFailed,
+        ) => DeliveryFailureReason::AuthorizationFailed,
+        Some(Code::Authentication | Code::SaslAuthenticationFailed) => {
+            DeliveryFailureReason::AuthenticationFailed
+        }
+        Some(Code::MessageSizeTooLarge | Code::InvalidMessageSize | Code::MessageBatchTooLarge) => {
+            DeliveryFailureReason::RecordTooLarge
+        }
+        Some(Code::AllBrokersDown | Code::BrokerTransportFailure) => {
+            DeliveryFailureReason::BrokerUnavailable
+        }
+        _ => DeliveryFailureReason::Other,
+    }
 }
 
-fn create_producer(params: &SendMessage) -> FutureProducer {
-    let mut config = ClientConfig::new();
-    config
-        .set("bootstrap.servers", params.brokers.clone())
-        .set("queue.buffering.max.ms", params.buffering_ms.to_string())
-        .set(
-            "message.timeout.ms",
-            params.message_timeout.0.as_millis().to_string(),
-        );
+/// Limits the number of messages awaiting the delivery report and counts waits caused by the
+/// producer backpressure
+#[derive(Debug)]
+struct Backpressure {
+    in_flight: Option<Arc<Semaphore>>,
+    queue_full_retry: QueueFullRetry,
+    in_flight_limit_waits: AtomicUsize,
+    queue_full_waits: AtomicUsize,
+}
+
+impl Backpressure {
+    fn new(max_in_flight: Option<usize>, queue_full_retry: QueueFullRetry) -> Arc<Self> {
+        Arc::new(Self {
+            in_flight: max_in_flight.map(|limit| Arc::new(Semaphore::new(limit.max(1)))),
+            queue_full_retry,
+            in_flight_limit_waits: AtomicUsize::new(0),
+            queue_full_waits: AtomicUsize::new(0),
+        })
+    }
 
-    if params.ssl {
-        config.set("security.protocol", "ssl");
-        config.set("enable.ssl.certificate.verification", "false");
+    async fn in_flight_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.in_flight.as_ref()?;
+
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                self.in_flight_limit_waits.fetch_add(1, Ordering::Relaxed);
+                semaphore.clone().acquire_owned().await.ok()
+            }
+        }
+    }
+
+    /// Spawns the future once the in flight limit allows it
+    async fn spawn<T: Send + 'static>(
+        &self,
+        join_set: &mut tokio::task::JoinSet<T>,
+        future: impl Future<Output = T> + Send + 'static,
+    ) {
+        let permit = self.in_flight_permit().await;
+
+        join_set.spawn(async move {
+            let result = future.await;
+            drop(permit);
+            result
+        });
+    }
+
+    fn stats(&self) -> BackpressureStats {
+        BackpressureStats {
+            in_flight_limit_waits: self.in_flight_limit_waits.load(Ordering::Relaxed),
+            queue_full_waits: self.queue_full_waits.load(Ordering::Relaxed),
+        }
     }
+}
+
+/// Enqueues the record, retrying with backoff while the local producer queue is full
+async fn send_with_backpressure<K, P>(
+    producer: &FutureProducer,
+    mut record: FutureRecord<'_, K, P>,
+    message_timeout: std::time::Duration,
+    backpressure: &Backpressure,
+) -> Result<Delivery, KafkaError>
+where
+    K: ToBytes + ?Sized,
+    P: ToBytes + ?Sized,
+{
+    let retry = &backpressure.queue_full_retry;
+    let start = std::time::Instant::now();
+    let mut retries = 0;
+    let mut backoff = retry.backoff.0;
+
+    loop {
+        match producer.send_result(record) {
+            Ok(delivery) => {
+                return match delivery.await {
+                    Ok(Ok(delivery)) => Ok(delivery),
+                    Ok(Err((e, _))) => Err(e),
+                    Err(_) => Err(KafkaError::Canceled),
+                };
+            }
+            Err((e, returned_record))
+                if e == KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull)
+                    && retry.max_retries.is_none_or(|max| retries < max)
+                    && start.elapsed() < message_timeout =>
+            {
+                record = returned_record;
+                retries += 1;
+                backpressure
+                    .queue_full_waits
+                    .fetch_add(1, Ordering::Relaxed);
 
-    config.create().expect("Producer creation failed")
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, retry.max_backoff());
+            }
+            Err((e, _)) => return Err(e),
+        }
+    }
+}
+
+fn handle_message_delivery_failure(
+    message: &mut SentMessage,
+    bytes_unsent: usize,
+    reason: DeliveryFailureReason,
+) {
+    message.delivery_failures += 1;
+    message.total_sent_bytes -= bytes_unsent;
+    *message.delivery_failure_reasons.entry(reason).or_default() += 1;
+}
+
+fn handle_job_delivery_result(
+    status: &mut JobStatus,
+    result: Result<Result<Delivery, DeliveryFailureReason>, tokio::task::JoinError>,
+) {
+    let reason = match result {
+        Ok(Ok(_)) => {
+            status.delivered_messages += 1;
+            return;
+        }
+        Ok(Err(reason)) => reason,
+        Err(e) => {
+            tracing::debug!("INTERNAL ERROR FOR JOB {}: {:?}", status.job_uuid, e);
+            DeliveryFailureReason::Internal
+        }
+    };
+
+    status.delivery_failures += 1;
+    *status.delivery_failure_reasons.entry(reason).or_default() += 1;
 }
 
 fn random_string(body_size: u128) -> Arc<String> {
@@ -54,69 +200,277 @@ fn random_string(body_size: u128) -> Arc<String> {
     )
 }
 
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |rendered, (placeholder, value)| {
+            rendered.replace(placeholder, value)
+        })
+}
+
+/// Generates payloads of the sent messages. All of them have the same length, as the uuids
+/// have the fixed length
+struct PayloadGenerator {
+    body: Arc<String>,
+    template: Option<String>,
+}
+
+impl PayloadGenerator {
+    fn new(payload: &Payload, body_size: u128) -> Self {
+        Self {
+            body: random_string(body_size),
+            template: match payload {
+                Payload::Random => None,
+                Payload::JsonTemplate { template } => Some(template.to_string()),
+            },
+        }
+    }
+
+    fn generate(&self, message_uuid: uuid::Uuid, experiment_uuid: uuid::Uuid) -> Arc<String> {
+        match &self.template {
+            None => self.body.clone(),
+            Some(template) => Arc::new(render_template(
+                template,
+                &[
+                    ("{message_uuid}", &message_uuid.to_string()),
+                    ("{experiment_uuid}", &experiment_uuid.to_string()),
+                    ("{body}", &self.body),
+                ],
+            )),
+        }
+    }
+
+    fn message_len(&self) -> usize {
+        self.generate(uuid::Uuid::nil(), uuid::Uuid::nil()).len()
+    }
+}
+
 async fn sender(
     idx: usize,
+    message_uuid: uuid::Uuid,
     params: SendMessage,
-    messages_state: Arc<Mutex<MessagesState>>,
+    messages_state: MessagesState,
     payload: Arc<String>,
-    producer: FutureProducer,
-    async_mode: bool,
-) -> Result<
-    rdkafka::producer::future_producer::Delivery,
-    Option<(rdkafka::error::KafkaError, rdkafka::message::OwnedMessage)>,
-> {
-    let i = idx;
-    let message_uuid = uuid::Uuid::new_v4();
+    producer: Arc<ProducerLease>,
+    backpressure: Arc<Backpressure>,
+) -> Result<Delivery, DeliveryFailureReason> {
+    // Jobs keep running after the limit has been reached, their messages are just not produced
+    if messages_state
+        .get(&params.experiment_uuid)
+        .is_some_and(|x| x.rejects_sends())
+    {
+        return Err(DeliveryFailureReason::LimitReached);
+    }
+
+    let key = render_template(
+        &params.key_template,
+        &[
+            ("{index}", &idx.to_string()),
+            ("{message_uuid}", &message_uuid.to_string()),
+            ("{experiment_uuid}", &params.experiment_uuid.to_string()),
+        ],
+    );
+    let default_headers = CorrelationHeaders::default();
+    let headers = params.headers.as_ref().unwrap_or(&default_headers);
+    let (message_uuid_value, experiment_uuid_value) = if headers.binary {
+        (
+            message_uuid.as_bytes().to_vec(),
+            params.experiment_uuid.as_bytes().to_vec(),
+        )
+    } else {
+        (
+            message_uuid.to_string().into_bytes(),
+            params.experiment_uuid.to_string().into_bytes(),
+        )
+    };
+
     // The send operation on the topic returns a future, which will be
     // completed once the result or failure from Kafka is received.
-    let delivery_status = producer
-        .send(
-            FutureRecord::to(&params.topic)
-                .payload(payload.as_str())
-                .key(&format!("msg-{}", i))
-                .headers(
-                    OwnedHeaders::new()
-                        .insert(Header {
-                            key: MESSAGE_UUID_HEADER,
-                            value: Some(&message_uuid.to_string()),
-                        })
-                        .insert(Header {
-                            key: EXPERIMENT_UUID_HEADER,
-                            value: Some(&params.experiment_uuid.to_string()),
-                        }),
-                ),
-            params.message_timeout.0,
-        )
-        .await;
+    let delivery_status = send_with_backpressure(
+        &producer.producer,
+        FutureRecord::to(&params.topic)
+            .payload(payload.as_str())
+            .key(&key)
+            .headers(
+                OwnedHeaders::new()
+                    .insert(Header {
+                        key: &headers.message_uuid,
+                        value: Some(&message_uuid_value),
+                    })
+                    .insert(Header {
+                        key: &headers.experiment_uuid,
+                        value: Some(&experiment_uuid_value),
+                    }),
+            ),
+        params.message_timeout.0,
+        &backpressure,
+    )
+    .await
+    .map_err(|e| {
+        tracing::debug!("Message {} delivery error: {}", message_uuid, e);
+        delivery_failure_reason(&e)
+    });
     let now = get_now_millis();
 
-    let mut state = messages_state.lock().await;
-
-    state.insert_message(
-        Message {
-            uuid: message_uuid,
-            bytes_size: bytesize::ByteSize::b(payload.len() as u64).into(),
-        },
-        params.experiment_uuid,
-        async_mode,
-    );
-
-    let events = state.events.get_mut(&params.experiment_uuid);
-    if events.is_none() {
-        return Err(None);
-    }
+    let Some(experiment) = messages_state.get(&params.experiment_uuid) else {
+        if params.async_mode {
+            tracing::debug!("Unknown experiment: {}", params.experiment_uuid);
+        } else {
+            tracing::warn!("Unknown experiment: {}", params.experiment_uuid);
+        }
+        return Err(DeliveryFailureReason::ExperimentNotFound);
+    };
 
-    let events = events.unwrap();
+    experiment.record(Record::Sent(Message {
+        uuid: message_uuid,
+        bytes_size: bytesize::ByteSize::b(payload.len() as u64).into(),
+    }));
 
-    events.push(MessageEvent {
+    experiment.record(Record::Event(MessageEvent {
         message_uuid,
         timestamp_millis: now,
         topic: params.topic.clone(),
         brokers: params.brokers.clone(),
-        event_type: EventType::Sent,
-    });
+        event_type: match &delivery_status {
+            Ok(_) => EventType::Sent,
+            Err(reason) => EventType::SendFailed { reason: *reason },
+        },
+    }));
 
-    delivery_status.map_err(|x| Some(x))
+    delivery_status
+}
+
+async fn transaction_operation(
+    producer: &FutureProducer,
+    operation: impl FnOnce(&FutureProducer) -> KafkaResult<()> + Send + 'static,
+) -> KafkaResult<()> {
+    let producer = producer.clone();
+    tokio::task::spawn_blocking(move || operation(&producer))
+        .await
+        .unwrap_or(Err(KafkaError::Canceled))
+}
+
+/// Sends the messages in transactions of the configured size. Part of the transactions is
+/// aborted on purpose. Outcome of the transaction is recorded for every delivered message
+async fn send_transactional_batch<F>(
+    messages: Vec<(uuid::Uuid, F)>,
+    producer: &ProducerLease,
+    transaction: &TransactionCfg,
+    backpressure: &Backpressure,
+    messages_state: &MessagesState,
+    params: &SendMessage,
+) -> Vec<Result<Result<Delivery, DeliveryFailureReason>, tokio::task::JoinError>>
+where
+    F: Future<Output = Result<Delivery, DeliveryFailureReason>> + Send + 'static,
+{
+    let timeout = params.message_timeout.0;
+    let messages_number = messages.len();
+    let mut results = Vec::with_capacity(messages_number);
+
+    let mut initialized = producer.transactions.lock().await;
+
+    if !*initialized {
+        match transaction_operation(&producer.producer, move |p| p.init_transactions(timeout)).await
+        {
+            Ok(_) => *initialized = true,
+            Err(e) => {
+                tracing::warn!("Could not initialize transactions: {}", e);
+                producer.discard();
+                results.resize_with(messages_number, || {
+                    Ok(Err(DeliveryFailureReason::TransactionFailed))
+                });
+                return results;
+            }
+        }
+    }
+
+    let mut messages = messages.into_iter().peekable();
+
+    while messages.peek().is_some() {
+        let chunk: Vec<_> = messages
+            .by_ref()
+            .take(transaction.messages_per_transaction.max(1))
+            .collect();
+
+        if let Err(e) = transaction_operation(&producer.producer, |p| p.begin_transaction()).await {
+            tracing::warn!("Could not begin transaction: {}", e);
+            producer.discard();
+            results.extend(
+                chunk
+                    .iter()
+                    .map(|_| Ok(Err(DeliveryFailureReason::TransactionFailed))),
+            );
+            continue;
+        }
+
+        let mut delivered = Vec::new();
+        if params.blocking {
+            for (message_uuid, future) in chunk {
+                let delivery = future.await;
+                if delivery.is_ok() {
+                    delivered.push(message_uuid);
+                }
+                results.push(Ok(delivery));
+            }
+        } else {
+            let mut join_set = tokio::task::JoinSet::new();
+            for (message_uuid, future) in chunk {
+                backpressure
+                    .spawn(&mut join_set, async move { (message_uuid, future.await) })
+                    .await;
+            }
+
+            while let Some(res) = join_set.join_next().await {
+                results.push(res.map(|(message_uuid, delivery)| {
+                    if delivery.is_ok() {
+                        delivered.push(message_uuid);
+                    }
+                    delivery
+                }));
+            }
+        }
+
+        let mut committed = !rand::rng().random_bool(transaction.abort_ratio.clamp(0.0, 1.0));
+
+        if committed
+            && let Err(e) =
+                transaction_operation(&producer.producer, move |p| p.commit_transaction(timeout))
+                    .await
+        {
+            tracing::warn!("Could not commit transaction: {}", e);
+            producer.discard();
+            committed = false;
+        }
+
+        if !committed
+            && let Err(e) =
+                transaction_operation(&producer.producer, move |p| p.abort_transaction(timeout))
+                    .await
+        {
+            tracing::warn!("Could not abort transaction: {}", e);
+            producer.discard();
+        }
+
+        let now = get_now_millis();
+
+        if let Some(experiment) = messages_state.get(&params.experiment_uuid) {
+            for message_uuid in delivered {
+                experiment.record(Record::Event(MessageEvent {
+                    message_uuid,
+                    timestamp_millis: now,
+                    topic: params.topic.clone(),
+                    brokers: params.brokers.clone(),
+                    event_type: if committed {
+                        EventType::TransactionCommitted
+                    } else {
+                        EventType::TransactionAborted
+                    },
+                }));
+            }
+        }
+    }
+
+    results
 }
 
 #[derive(thiserror::Error, Debug, Clone)]
@@ -126,11 +480,23 @@ pub enum ResponseError {
 
     #[error("Could not find experiment with the provided uuid")]
     ExperimentNotFound,
+
+    #[error("Experiment has reached its limit and rejects new messages")]
+    ExperimentLimitReached,
+
+    #[error("Experiment has been stopped")]
+    ExperimentStopped,
+
+    #[error("Could not create producer: {0}")]
+    ProducerCreation(#[from] KafkaError),
 }
 impl actix_web::error::ResponseError for ResponseError {
     fn status_code(&self) -> StatusCode {
         match *self {
+            Self::ProducerCreation(_) => StatusCode::BAD_REQUEST,
             Self::ExperimentNotFound => StatusCode::NOT_FOUND,
+            Self::ExperimentLimitReached => StatusCode::INSUFFICIENT_STORAGE,
+            Self::ExperimentStopped => StatusCode::CONFLICT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -140,9 +506,12 @@ impl actix_web::error::ResponseError for ResponseError {
     tag = "messages",
     responses(
         (status = 200, description = "Sent new messages", body = SentMessage),
+        (status = 400, description = "Producer could not be created with the provided config"),
         (status = 404, description = "Experiment not found"),
+        (status = 409, description = "Experiment has been stopped"),
         (status = 207, description = "Some messages were ok, some failed", body = SentMessage),
-        (status = 500 , description = "No message has been sent properly")
+        (status = 500 , description = "No message has been sent properly"),
+        (status = 507, description = "Experiment has reached its limit and rejects new messages")
     )
 )]
 #[post("/")]
@@ -151,47 +520,123 @@ async fn send(
     params: web::Json<SendMessage>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<impl Responder, ResponseError> {
-    let params = params.into_inner();
-    let producer = create_producer(&params);
-    let payload = random_string(params.body_size.as_bytes());
+    let mut params = params.into_inner();
+    let payload = PayloadGenerator::new(&params.payload, params.body_size.as_bytes());
 
     {
-        let state = data.app_state.lock().await.messages_state.clone();
-        let state = state.lock().await;
-
-        if !state.experiments.contains_key(&params.experiment_uuid) {
+        let Some(experiment) = data.messages_state.get(&params.experiment_uuid) else {
             return Err(ResponseError::ExperimentNotFound);
+        };
+
+        if experiment.rejects_sends() {
+            return Err(ResponseError::ExperimentLimitReached);
         }
+
+        let experiment = &experiment.lock().experiment;
+        if experiment.lifecycle != ExperimentLifecycle::Running {
+            return Err(ResponseError::ExperimentStopped);
+        }
+
+        params
+            .headers
+            .get_or_insert_with(|| experiment.headers.clone());
     }
 
     // This loop is non blocking: all messages will be sent one after the other, without waiting
     // for the results.
-    let messages_state = data.app_state.lock().await.messages_state.clone();
-    let async_mode = params.async_mode;
+    let messages_state = data.messages_state.clone();
+    let producer = Arc::new(
+        data.app_state
+            .lock()
+            .await
+            .producers
+            .acquire(&ProducerCfg::from(&params))?,
+    );
+    let backpressure = Backpressure::new(params.max_in_flight, params.queue_full_retry.clone());
     let futures = (0..params.messages_number)
         .map(|i| {
-            sender(
+            let message_uuid = uuid::Uuid::new_v4();
+            let future = sender(
                 i,
+                message_uuid,
                 params.clone(),
                 messages_state.clone(),
-                payload.clone(),
+                payload.generate(message_uuid, params.experiment_uuid),
                 producer.clone(),
-                async_mode,
-            )
+                backpressure.clone(),
+            );
+            (message_uuid, future)
         })
         .collect::<Vec<_>>();
 
-    let total_bytes = payload.len() * params.messages_number;
+    let payload_len = payload.message_len();
+    let total_bytes = payload_len * params.messages_number;
 
     let mut message = SentMessage {
         experiment_uuid: params.experiment_uuid,
-        bytes_size: payload.len(),
+        bytes_size: payload_len,
         message_number: params.messages_number,
         total_sent_bytes: total_bytes,
         total_sent_bytes_human_readable: bytesize::ByteSize::b(total_bytes as u64).into(),
         delivery_failures: 0,
+        delivery_failure_reasons: Default::default(),
+        backpressure: Default::default(),
     };
 
+    if let Some(transaction) = params.transaction.clone() {
+        let async_mode = params.async_mode;
+        let send_transactions = {
+            let backpressure = backpressure.clone();
+            async move {
+                send_transactional_batch(
+                    futures,
+                    &producer,
+                    &transaction,
+                    &backpressure,
+                    &messages_state,
+                    &params,
+                )
+                .await
+            }
+        };
+
+        if async_mode {
+            let experiment_uuid = message.experiment_uuid;
+            tokio::spawn(async move {
+                for res in send_transactions.await {
+                    if let Ok(Err(e)) = res {
+                        tracing::debug!(
+                            "Failed to deliver message (async) for {:?}. Reason: {:?}",
+                            experiment_uuid,
+                            e
+                        );
+                    }
+                }
+            });
+
+            return Ok(web::Json(message).customize());
+        }
+
+        for res in send_transactions.await {
+            if let Err(e) = res {
+                tracing::warn!("INTERNAL ERROR FOR MESSAGE{:?}: {:?}", &message, e);
+                handle_message_delivery_failure(
+                    &mut message,
+                    payload_len,
+                    DeliveryFailureReason::Internal,
+                );
+            } else if let Ok(Err(e)) = res {
+                tracing::warn!("Failed to deliver message {:?}. Reason: {:?}", &message, e);
+                handle_message_delivery_failure(&mut message, payload_len, e);
+            }
+        }
+
+        message.backpressure = backpressure.stats();
+        return sent_message_response(message);
+    }
+
+    let futures = futures.into_iter().map(|(_, future)| future);
+
     if params.async_mode {
         let experiment_uuid = params.experiment_uuid;
         if params.blocking {
@@ -210,7 +655,7 @@ async fn send(
             tokio::spawn(async move {
                 let mut join_set = tokio::task::JoinSet::new();
                 for future in futures {
-                    join_set.spawn(future);
+                    backpressure.spawn(&mut join_set, future).await;
                 }
 
                 while let Some(res) = join_set.join_next().await {
@@ -237,36 +682,47 @@ async fn send(
             for future in futures {
                 if let Err(e) = future.await {
                     tracing::warn!("Failed to deliver message {:?}. Reason: {:?}", &message, e);
-                    handle_message_delivery_failure(&mut message, payload.len());
+                    handle_message_delivery_failure(&mut message, payload_len, e);
                 }
             }
         } else {
             let mut join_set = tokio::task::JoinSet::new();
             for future in futures {
-                join_set.spawn(future);
+                backpressure.spawn(&mut join_set, future).await;
             }
 
             while let Some(res) = join_set.join_next().await {
                 if let Err(e) = res {
                     tracing::warn!("INTERNAL ERROR FOR MESSAGE{:?}: {:?}", &message, e);
-                    handle_message_delivery_failure(&mut message, payload.len());
+                    handle_message_delivery_failure(
+                        &mut message,
+                        payload_len,
+                        DeliveryFailureReason::Internal,
+                    );
                 } else if let Ok(Err(e)) = res {
                     tracing::warn!("Failed to deliver message {:?}. Reason: {:?}", &message, e);
-                    handle_message_delivery_failure(&mut message, payload.len());
+                    handle_message_delivery_failure(&mut message, payload_len, e);
                 }
             }
         }
 
-        message.total_sent_bytes_human_readable =
-            bytesize::ByteSize::b(message.total_sent_bytes as u64).into();
+        message.backpressure = backpressure.stats();
+        sent_message_response(message)
+    }
+}
+
+fn sent_message_response(
+    mut message: SentMessage,
+) -> Result<CustomizeResponder<web::Json<SentMessage>>, ResponseError> {
+    message.total_sent_bytes_human_readable =
+        bytesize::ByteSize::b(message.total_sent_bytes as u64).into();
 
-        match message.delivery_failures {
-            0 => Ok(web::Json(message).customize()),
-            x if x == message.message_number => Err(ResponseError::NoMessagesDelivered),
-            _ => Ok(web::Json(message)
-                .customize()
-                .with_status(StatusCode::MULTI_STATUS)),
-        }
+    match message.delivery_failures {
+        0 => Ok(web::Json(message).customize()),
+        x if x == message.message_number => Err(ResponseError::NoMessagesDelivered),
+        _ => Ok(web::Json(message)
+            .customize()
+            .with_status(StatusCode::MULTI_STATUS)),
     }
 }
 
@@ -274,7 +730,11 @@ async fn send(
 #[utoipa::path(
     tag = "messages",
     responses(
-        (status = 200, description = "New job scheduled", body = SentMessage),
+        (status = 200, description = "New job scheduled", body = JobScheduled),
+        (status = 400, description = "Producer could not be created with the provided config"),
+        (status = 404, description = "Experiment not found"),
+        (status = 409, description = "Experiment has been stopped"),
+        (status = 507, description = "Experiment has reached its limit and rejects new messages"),
     )
 )]
 #[post("/job")]
@@ -282,38 +742,76 @@ async fn send_job(
     params: web::Json<SendMessageTask>,
     data: web::Data<AppData>,
 ) -> actix_web::Result<impl Responder, ResponseError> {
-    let params = params.into_inner();
+    let mut params = params.into_inner();
 
     {
-        let state = data.app_state.lock().await.messages_state.clone();
-        let state = state.lock().await;
-
-        if !state.experiments.contains_key(&params.experiment_uuid) {
+        let Some(experiment) = data.messages_state.get(&params.experiment_uuid) else {
             return Err(ResponseError::ExperimentNotFound);
+        };
+
+        if experiment.rejects_sends() {
+            return Err(ResponseError::ExperimentLimitReached);
         }
+
+        let experiment = &experiment.lock().experiment;
+        if experiment.lifecycle != ExperimentLifecycle::Running {
+            return Err(ResponseError::ExperimentStopped);
+        }
+
+        params
+            .headers
+            .get_or_insert_with(|| experiment.headers.clone());
     }
 
-    tokio::spawn(async move {
-        let send_message_task_base = SendMessage {
-            buffering_ms: params.buffering_ms,
-            brokers: params.brokers,
-            topic: params.topic,
-            ssl: params.ssl,
-            message_timeout: params.message_timeout,
-            body_size: params.body_size,
-            messages_number: params.messages_number,
-            experiment_uuid: params.experiment_uuid,
-            blocking: false,
-            async_mode: true,
-        };
+    let send_message_task_base = SendMessage {
+        buffering_ms: params.buffering_ms,
+        brokers: params.brokers,
+        topic: params.topic,
+        ssl: params.ssl,
+        message_timeout: params.message_timeout,
+        body_size: params.body_size,
+        messages_number: params.messages_number,
+        experiment_uuid: params.experiment_uuid,
+        blocking: false,
+        async_mode: true,
+        max_in_flight: params.max_in_flight,
+        queue_full_retry: params.queue_full_retry,
+        transaction: params.transaction,
+        payload: params.payload,
+        key_template: params.key_template,
+        headers: params.headers,
+    };
+
+    let producer = Arc::new(
+        data.app_state
+            .lock()
+            .await
+            .producers
+            .acquire(&ProducerCfg::from(&send_message_task_base))?,
+    );
 
-        let producer = create_producer(&send_message_task_base);
-        let payload = random_string(send_message_task_base.body_size.as_bytes());
+    let job_uuid = uuid::Uuid::new_v4();
+    let experiment_uuid = params.experiment_uuid;
+    let jobs_state = data.app_state.lock().await.jobs_state.clone();
+
+    jobs_state.lock().await.jobs.insert(
+        job_uuid,
+        JobStatus::new(job_uuid, experiment_uuid, params.messages_number),
+    );
+
+    tokio::spawn(async move {
+        let payload = PayloadGenerator::new(
+            &send_message_task_base.payload,
+            send_message_task_base.body_size.as_bytes(),
+        );
 
         // This loop is non blocking: all messages will be sent one after the other, without waiting
         // for the results.
-        let messages_state = data.app_state.lock().await.messages_state.clone();
-        let async_mode = send_message_task_base.async_mode;
+        let messages_state = data.messages_state.clone();
+        let backpressure = Backpressure::new(
+            send_message_task_base.max_in_flight,
+            send_message_task_base.queue_full_retry.clone(),
+        );
 
         let iterations = (params.messages_number / params.message_rate.messages) + 1;
         let mut total_messages = 0;
@@ -321,6 +819,20 @@ async fn send_job(
         let mut join_set = tokio::task::JoinSet::new();
 
         for _ in 0..iterations {
+            // Job is canceled once its experiment is stopped or deleted
+            let running = messages_state
+                .get(&experiment_uuid)
+                .is_some_and(|x| x.lock().experiment.lifecycle == ExperimentLifecycle::Running);
+
+            if !running {
+                tracing::info!("Canceling job {} of stopped experiment", job_uuid);
+
+                if let Some(status) = jobs_state.lock().await.jobs.get_mut(&job_uuid) {
+                    status.canceled = true;
+                }
+                break;
+            }
+
             let desired_messages_batch = std::cmp::min(
                 params.messages_number - total_messages,
                 params.message_rate.messages,
@@ -328,27 +840,288 @@ async fn send_job(
 
             let futures = (0..desired_messages_batch)
                 .map(|i| {
-                    sender(
+                    let message_uuid = uuid::Uuid::new_v4();
+                    let future = sender(
                         i,
+                        message_uuid,
                         send_message_task_base.clone(),
                         messages_state.clone(),
-                        payload.clone(),
+                        payload.generate(message_uuid, send_message_task_base.experiment_uuid),
                         producer.clone(),
-                        async_mode,
-                    )
+                        backpressure.clone(),
+                    );
+                    (message_uuid, future)
                 })
                 .collect::<Vec<_>>();
 
-            for future in futures {
-                join_set.spawn(future);
+            if let Some(transaction) = &send_message_task_base.transaction {
+                let results = send_transactional_batch(
+                    futures,
+                    &producer,
+                    transaction,
+                    &backpressure,
+                    &messages_state,
+                    &send_message_task_base,
+                )
+                .await;
+
+                if let Some(status) = jobs_state.lock().await.jobs.get_mut(&job_uuid) {
+                    for res in results {
+                        handle_job_delivery_result(status, res);
+                    }
+                }
+            } else {
+                for (_, future) in futures {
+                    backpressure.spawn(&mut join_set, future).await;
+                }
             }
 
             total_messages += desired_messages_batch;
+
+            update_job_status(
+                &jobs_state,
+                &job_uuid,
+                &mut join_set,
+                &backpressure,
+                total_messages,
+                false,
+            )
+            .await;
             tokio::time::sleep(params.message_rate.per.0).await;
         }
 
-        while join_set.join_next().await.is_some() {}
+        update_job_status(
+            &jobs_state,
+            &job_uuid,
+            &mut join_set,
+            &backpressure,
+            total_messages,
+            true,
+        )
+        .await;
     });
 
-    Ok("Scheduled a new job for emitting message".to_string())
+    Ok(web::Json(JobScheduled {
+        job_uuid,
+        experiment_uuid,
+    }))
+}
+
+/// Collect results of the sent messages. Waits for all of them when the job is finished
+async fn update_job_status(
+    jobs_state: &Mutex<JobsState>,
+    job_uuid: &uuid::Uuid,
+    join_set: &mut tokio::task::JoinSet<Result<Delivery, DeliveryFailureReason>>,
+    backpressure: &Backpressure,
+    scheduled_messages: usize,
+    finished: bool,
+) {
+    let mut results = Vec::new();
+
+    if finished {
+        while let Some(res) = join_set.join_next().await {
+            results.push(res);
+        }
+    } else {
+        while let Some(res) = join_set.try_join_next() {
+            results.push(res);
+        }
+    }
+
+    let mut jobs_state = jobs_state.lock().await;
+
+    if finished {
+        jobs_state.finish(*job_uuid);
+    }
+
+    if let Some(status) = jobs_state.jobs.get_mut(job_uuid) {
+        status.scheduled_messages = scheduled_messages;
+        status.backpressure = backpressure.stats();
+        status.finished = finished;
+
+        for res in results {
+            handle_job_delivery_result(status, res);
+        }
+    }
+}
+
+#[utoipa::path(
+    tag = "messages",
+    params(
+        JobStatusRequest
+    ),
+    responses(
+        (status = 200, description = "Status of the job", body = JobStatus),
+        (status = 404, description = "Job not found"),
+    )
+)]
+#[get("/job")]
+/// Get progress and delivery failures of the job
+async fn job_status(
+    params: web::Query<JobStatusRequest>,
+    data: web::Data<AppData>,
+) -> actix_web::Result<web::Json<JobStatus>> {
+    let jobs_state = data.app_state.lock().await.jobs_state.clone();
+    let status = jobs_state.lock().await.jobs.get(&params.job_uuid).cloned();
+
+    status
+        .map(web::Json)
+        .ok_or(actix_web::error::ErrorNotFound("Job not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::Timestamp;
+
+    fn sent_message(message_number: usize, bytes_size: usize) -> SentMessage {
+        SentMessage {
+            experiment_uuid: uuid::Uuid::new_v4(),
+            message_number,
+            bytes_size,
+            total_sent_bytes: message_number * bytes_size,
+            total_sent_bytes_human_readable: bytesize::ByteSize::b(0).into(),
+            delivery_failures: 0,
+            delivery_failure_reasons: Default::default(),
+            backpressure: Default::default(),
+        }
+    }
+
+    fn delivered() -> Delivery {
+        Delivery {
+            partition: 0,
+            offset: 0,
+            timestamp: Timestamp::NotAvailable,
+        }
+    }
+
+    #[test]
+    fn delivery_failures_are_classified_by_error_code() {
+        let reason = |code| delivery_failure_reason(&KafkaError::MessageProduction(code));
+
+        assert_eq!(
+            reason(RDKafkaErrorCode::MessageTimedOut),
+            DeliveryFailureReason::MessageTimedOut
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::QueueFull),
+            DeliveryFailureReason::QueueFull
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::UnknownTopicOrPartition),
+            DeliveryFailureReason::UnknownTopic
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::TopicAuthorizationFailed),
+            DeliveryFailureReason::AuthorizationFailed
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::SaslAuthenticationFailed),
+            DeliveryFailureReason::AuthenticationFailed
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::MessageSizeTooLarge),
+            DeliveryFailureReason::RecordTooLarge
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::AllBrokersDown),
+            DeliveryFailureReason::BrokerUnavailable
+        );
+        assert_eq!(
+            reason(RDKafkaErrorCode::InvalidRecord),
+            DeliveryFailureReason::Other
+        );
+        assert_eq!(
+            delivery_failure_reason(&KafkaError::Canceled),
+            DeliveryFailureReason::Canceled
+        );
+    }
+
+    #[test]
+    fn message_delivery_failures_are_counted_by_reason() {
+        let mut message = sent_message(3, 10);
+
+        handle_message_delivery_failure(&mut message, 10, DeliveryFailureReason::QueueFull);
+        handle_message_delivery_failure(&mut message, 10, DeliveryFailureReason::QueueFull);
+        handle_message_delivery_failure(&mut message, 10, DeliveryFailureReason::UnknownTopic);
+
+        assert_eq!(message.delivery_failures, 3);
+        assert_eq!(message.total_sent_bytes, 0);
+        assert_eq!(
+            message
+                .delivery_failure_reasons
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                (DeliveryFailureReason::QueueFull, 2),
+                (DeliveryFailureReason::UnknownTopic, 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn job_delivery_results_are_counted_by_reason() {
+        let mut status = JobStatus::new(uuid::Uuid::new_v4(), uuid::Uuid::new_v4(), 3);
+        let panicked = tokio::spawn(async { panic!("delivery task panicked") })
+            .await
+            .map(|_: ()| Ok(delivered()));
+
+        handle_job_delivery_result(&mut status, Ok(Ok(delivered())));
+        handle_job_delivery_result(&mut status, Ok(Err(DeliveryFailureReason::MessageTimedOut)));
+        handle_job_delivery_result(&mut status, panicked);
+
+        assert_eq!(status.delivered_messages, 1);
+        assert_eq!(status.delivery_failures, 2);
+        assert_eq!(
+            status
+                .delivery_failure_reasons
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                (DeliveryFailureReason::MessageTimedOut, 1),
+                (DeliveryFailureReason::Internal, 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit_waits_for_the_delivery() {
+        let backpressure = Backpressure::new(Some(1), QueueFullRetry::default());
+        let mut join_set = tokio::task::JoinSet::new();
+        let (deliver, delivered) = tokio::sync::oneshot::channel();
+
+        backpressure
+            .spawn(&mut join_set, async move { delivered.await.is_ok() })
+            .await;
+
+        let waiting = backpressure.in_flight_permit();
+        let timeout = std::time::Duration::from_millis(20);
+        assert!(tokio::time::timeout(timeout, waiting).await.is_err());
+
+        deliver.send(()).unwrap();
+        assert!(join_set.join_next().await.unwrap().unwrap());
+
+        backpressure.spawn(&mut join_set, async { true }).await;
+        assert!(join_set.join_next().await.unwrap().unwrap());
+        assert_eq!(backpressure.stats().in_flight_limit_waits, 1);
+    }
+
+    #[tokio::test]
+    async fn no_in_flight_limit_never_waits() {
+        let backpressure = Backpressure::new(None, QueueFullRetry::default());
+
+        assert!(backpressure.in_flight_permit().await.is_none());
+        assert_eq!(backpressure.stats().in_flight_limit_waits, 0);
+    }
+
+    #[test]
+    fn queue_full_backoff_is_capped() {
+        let retry = QueueFullRetry::default();
+        assert_eq!(retry.max_backoff(), retry.backoff.0 * 10);
+
+        let retry: QueueFullRetry =
+            serde_json::from_value(serde_json::json!({"backoff": "10ms", "max_backoff": "25ms"}))
+                .unwrap();
+        assert_eq!(retry.max_backoff(), std::time::Duration::from_millis(25));
+    }
 }
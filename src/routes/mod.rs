@@ -3,10 +3,13 @@ use std::sync::atomic::Ordering;
 use actix_web::{delete, get, web, Responder};
 
 use crate::AppData;
+use crate::memory::AllocatorStats;
+use crate::models::{ExperimentMemory, MemoryUsage};
 
 pub mod experiment;
 pub mod measurements;
 pub mod messages;
+pub mod producers;
 
 #[utoipa::path(
     tag = "internal",
@@ -57,3 +60,45 @@ async fn stop_runtime(data: web::Data<AppData>) -> impl Responder {
     data.stop_handle.stop(true);
     "Stopping runtime..."
 }
+
+#[utoipa::path(
+    tag = "internal",
+    responses(
+        (status = 200, description = "Allocator statistics and size of the experiments", body = MemoryUsage),
+        (status = 500, description = "Allocator statistics are not available")
+    )
+)]
+/// Memory usage compared with the budget set by the MEMORY_BUDGET env
+#[get("/memory")]
+async fn memory_usage(data: web::Data<AppData>) -> actix_web::Result<web::Json<MemoryUsage>> {
+    let stats = AllocatorStats::read().ok_or(actix_web::error::ErrorInternalServerError(
+        "Allocator statistics are not available",
+    ))?;
+    let budget = data.messages_state.budget();
+
+    let experiments = data
+        .messages_state
+        .shards()
+        .into_iter()
+        .map(|(experiment_uuid, shard)| {
+            let limit_reached = shard.limit_reached();
+            let state = shard.lock();
+
+            ExperimentMemory {
+                experiment_uuid,
+                messages: state.messages.len(),
+                events: state.events.len(),
+                limit_reached,
+            }
+        })
+        .collect();
+
+    Ok(web::Json(MemoryUsage {
+        allocated_bytes: stats.allocated,
+        active_bytes: stats.active,
+        resident_bytes: stats.resident,
+        budget_bytes: budget.bytes,
+        budget_exceeded: budget.exceeded(),
+        experiments,
+    }))
+}
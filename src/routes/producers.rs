@@ -0,0 +1,17 @@
+use crate::AppData;
+use crate::models::ProducerPoolEntry;
+use actix_web::{Responder, get, web};
+
+#[utoipa::path(
+    tag = "producers",
+    responses(
+        (status = 200, description = "Producers kept in the pool", body = Vec<ProducerPoolEntry>)
+    )
+)]
+#[get("/")]
+/// Get state of the producers pool
+async fn list_producers(data: web::Data<AppData>) -> impl Responder {
+    let producers = data.app_state.lock().await.producers.clone();
+
+    web::Json(producers.evict_idle().entries())
+}
@@ -1,151 +1,1238 @@
-use crate::consumers::Consumers;
-use crate::models::{Experiment, KafkaBrokerCfg, Message, MessageEvent};
-use std::collections::HashMap;
+use crate::aggregates::Aggregator;
+use crate::consumers::{Consumers, ListenerRestore, RestorePosition};
+use crate::events::EventStore;
+use crate::get_now_millis;
+use crate::memory::MemoryBudget;
+use crate::models::measurements::ExperimentRoute;
+use crate::models::{
+    ChurnEvent, CorrelationHeaders, Experiment, ExperimentLifecycle, ExperimentLimits, JobStatus,
+    KafkaBrokerCfg, LimitPolicy, ListenerStatus, Message, MessageEvent, RebalanceEvent,
+    RestoreFrom, StorageMode,
+};
+use crate::persistence::{Persistence, PersistenceError};
+use crate::producers::Producers;
+use rdkafka::error::KafkaError;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Mutex, mpsc, oneshot};
 use tracing::info;
 use uuid::Uuid;
 
-#[derive(Default, Debug, Clone)]
-pub struct ExperimentToMessagesIdx(pub HashMap<Uuid, Vec<Uuid>>);
+#[derive(thiserror::Error, Debug)]
+pub enum ExperimentError {
+    #[error("Could not create experiment listener: {0}")]
+    ListenerCreation(#[from] KafkaError),
 
-#[derive(Default, Debug, Clone)]
-pub struct MessageToExperimentIdx(pub HashMap<Uuid, Uuid>);
+    #[error("Experiment start is unknown. Restore from the explicit timestamp instead")]
+    UnknownExperimentStart,
+
+    #[error("Invalid correlation key pattern: {0}")]
+    InvalidCorrelation(#[from] regex::Error),
+
+    #[error("Invalid topic pattern: {0}")]
+    InvalidTopicPattern(regex::Error),
+
+    #[error("Invalid listener churn: {0}")]
+    InvalidChurn(&'static str),
+
+    #[error("Invalid experiment limits: {0}")]
+    InvalidLimits(&'static str),
+
+    #[error("Topic pattern uses {0}, which is not supported by POSIX regex. Use e.g. [0-9]")]
+    UnsupportedTopicPattern(String),
+
+    #[error("Experiment not found")]
+    ExperimentNotFound,
+
+    #[error("Experiment with the same uuid already exists")]
+    ExperimentAlreadyExists,
+
+    #[error("Experiment already has listener for the topic and consumer group")]
+    ListenerAlreadyExists,
+
+    #[error("Experiment has no listener for the topic and consumer group")]
+    ListenerNotFound,
+
+    #[error("Experiment already has route with the same name")]
+    RouteAlreadyExists,
+
+    #[error("Consumer group is used by another experiment listener with a different config")]
+    ListenerConfigConflict,
+
+    #[error("Experiment has been stopped")]
+    ExperimentStopped,
+
+    #[error("Experiments can be archived only when the state directory is set")]
+    PersistenceDisabled,
+
+    #[error(transparent)]
+    Persistence(#[from] PersistenceError),
+}
 
 #[derive(Debug, Clone)]
 pub struct State {
     /// Used to spawn, manage and destroy kafka consumers (receivers)
     pub consumers: Consumers,
-    pub messages_state: Arc<Mutex<MessagesState>>,
+    /// Pool of kafka producers shared by the requests and jobs
+    pub producers: Producers,
+    pub messages_state: MessagesState,
+    pub jobs_state: Arc<Mutex<JobsState>>,
+    /// Storage of the archived experiments. Not set when the state directory is not
+    pub persistence: Option<Persistence>,
 }
 
-/// Message uuid to message
+/// Statuses of that many finished jobs are kept at most
+pub const MAX_FINISHED_JOBS: usize = 1000;
+
+/// Job uuid to job status
 #[derive(Debug, Clone, Default)]
-pub struct MessageMapping(pub HashMap<Uuid, Message>);
+pub struct JobsState {
+    pub jobs: HashMap<Uuid, JobStatus>,
+    /// Finished jobs from the oldest, so their statuses are evicted first
+    finished: VecDeque<Uuid>,
+}
+
+impl JobsState {
+    /// Evicts the oldest finished jobs over the limit
+    pub fn finish(&mut self, job_uuid: Uuid) {
+        self.finished.push_back(job_uuid);
+
+        while self.finished.len() > MAX_FINISHED_JOBS {
+            if let Some(evicted) = self.finished.pop_front() {
+                self.jobs.remove(&evicted);
+            }
+        }
+    }
+}
 
+/// Messages are stored in chunks of that many, so the snapshot shares all the chunks but the
+/// last one being filled
+const MESSAGE_CHUNK_LEN: usize = 4096;
+
+/// Messages of the experiment in the order they have been recorded
 #[derive(Debug, Clone, Default)]
-pub struct MessagesState {
-    pub experiments: HashMap<Uuid, Experiment>,
+pub struct MessageStore {
+    chunks: Vec<Arc<Vec<Message>>>,
+    /// Message uuid to its position in the recording order
+    positions: HashMap<Uuid, usize>,
+    /// Positions of the messages replaced since they have been taken last, so the persisted
+    /// ones are rewritten. Holds a position at most once per message
+    replaced: Vec<usize>,
+}
+
+/// Messages of the experiment read without holding the experiment lock
+#[derive(Debug, Clone)]
+pub struct MessagesSnapshot {
+    chunks: Vec<Arc<Vec<Message>>>,
+    len: usize,
+}
+
+impl MessageStore {
+    /// Known message is replaced only if `replace` is set
+    pub fn insert(&mut self, message: Message, replace: bool) {
+        let len = self.positions.len();
+        match self.positions.entry(message.uuid) {
+            Entry::Occupied(entry) => {
+                let position = *entry.get();
+                if replace {
+                    // Copies the chunk only if it is being read
+                    let chunk = Arc::make_mut(&mut self.chunks[position / MESSAGE_CHUNK_LEN]);
+                    chunk[position % MESSAGE_CHUNK_LEN] = message;
+                    self.replaced.push(position);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(len);
+                if self
+                    .chunks
+                    .last()
+                    .is_none_or(|x| x.len() >= MESSAGE_CHUNK_LEN)
+                {
+                    self.chunks
+                        .push(Arc::new(Vec::with_capacity(MESSAGE_CHUNK_LEN)));
+                }
+                if let Some(chunk) = self.chunks.last_mut() {
+                    Arc::make_mut(chunk).push(message);
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    pub fn contains(&self, message_uuid: &Uuid) -> bool {
+        self.positions.contains_key(message_uuid)
+    }
+
+    pub fn uuids(&self) -> impl Iterator<Item = &Uuid> {
+        self.positions.keys()
+    }
+
+    /// Positions of the messages replaced since the previous call
+    pub fn take_replaced(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.replaced)
+    }
+
+    pub fn snapshot(&self) -> MessagesSnapshot {
+        MessagesSnapshot {
+            chunks: self.chunks.clone(),
+            len: self.len(),
+        }
+    }
+}
+
+impl MessagesSnapshot {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Message> {
+        self.iter_from(0)
+    }
+
+    /// Message at the position in the recording order
+    pub fn get(&self, position: usize) -> Option<&Message> {
+        if position >= self.len {
+            return None;
+        }
+
+        self.chunks
+            .get(position / MESSAGE_CHUNK_LEN)
+            .and_then(|x| x.get(position % MESSAGE_CHUNK_LEN))
+    }
+
+    /// Messages from the position in the recording order
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = &Message> {
+        self.chunks
+            .iter()
+            .skip(start / MESSAGE_CHUNK_LEN)
+            .flat_map(|x| x.iter())
+            .skip(start % MESSAGE_CHUNK_LEN)
+    }
+}
+
+/// Change of the experiment state sent to its ingestion task
+#[derive(Debug)]
+pub enum Record {
+    /// Message sent by the emitter. Replaces the message received before the delivery report
+    Sent(Message),
+    /// Message received by the listener. Kept only if the emitter has not recorded it yet
+    Received(Message),
+    Event(MessageEvent),
+    Rebalance(RebalanceEvent),
+    Churn(ChurnEvent),
+}
+
+impl Record {
+    fn message_uuid(&self) -> Option<Uuid> {
+        match self {
+            Record::Sent(message) | Record::Received(message) => Some(message.uuid),
+            Record::Event(event) => Some(event.message_uuid),
+            Record::Rebalance(_) | Record::Churn(_) => None,
+        }
+    }
+}
+
+/// Everything recorded for the single experiment
+#[derive(Debug, Clone)]
+pub struct ExperimentState {
+    pub experiment: Experiment,
+
+    pub messages: MessageStore,
+
+    pub events: EventStore,
+
+    /// Rebalances of the experiment listeners
+    pub rebalances: Vec<RebalanceEvent>,
+
+    /// Churns injected into the experiment listeners
+    pub churns: Vec<ChurnEvent>,
+
+    /// Statistics of the experiment with the aggregated storage. Its messages and events are
+    /// not kept
+    pub aggregator: Option<Aggregator>,
+}
+
+impl ExperimentState {
+    pub fn new(experiment: Experiment) -> Self {
+        let aggregator = match experiment.storage {
+            StorageMode::Events => None,
+            StorageMode::Aggregated { pending_window } => Some(Aggregator::new(pending_window)),
+        };
+
+        Self {
+            experiment,
+            messages: Default::default(),
+            events: Default::default(),
+            rebalances: Vec::new(),
+            churns: Vec::new(),
+            aggregator,
+        }
+    }
+
+    pub fn apply(&mut self, record: Record) {
+        match record {
+            Record::Sent(message) => self.insert_message(message, true),
+            Record::Received(message) => self.insert_message(message, false),
+            Record::Event(event) => match &mut self.aggregator {
+                Some(aggregator) => aggregator.record(event, &self.experiment.consumers),
+                None => self.events.push(event),
+            },
+            Record::Rebalance(rebalance) => self.rebalances.push(rebalance),
+            Record::Churn(churn) => self.churns.push(churn),
+        }
+    }
+
+    pub fn insert_message(&mut self, message: Message, replace: bool) {
+        if let Some(aggregator) = &mut self.aggregator {
+            // Received messages are counted by their send
+            if replace {
+                aggregator.message_size(message.bytes_size.as_bytes() as u64);
+            }
+            return;
+        }
+
+        self.messages.insert(message, replace);
+    }
+
+    fn is_capped(&self) -> bool {
+        self.experiment
+            .limits
+            .max_events
+            .is_some_and(|max| self.events.len() >= max)
+    }
+
+    /// Messages of the aggregated experiments are known only while awaiting receipt
+    pub fn contains_message(&self, message_uuid: &Uuid) -> bool {
+        match &self.aggregator {
+            Some(aggregator) => aggregator.is_pending(message_uuid),
+            None => self.messages.contains(message_uuid),
+        }
+    }
+
+    /// Messages of the aggregated experiment that have left the pending window since the
+    /// previous call
+    fn take_evicted(&mut self) -> Vec<Uuid> {
+        self.aggregator
+            .as_mut()
+            .map(Aggregator::take_evicted)
+            .unwrap_or_default()
+    }
+}
+
+/// State of the experiment locked independently of the other experiments. Records are sent to
+/// the ingestion task of the experiment, so the senders and listeners never wait for the lock
+#[derive(Debug, Clone)]
+pub struct ExperimentShard {
+    state: Arc<parking_lot::Mutex<ExperimentState>>,
+    records: mpsc::UnboundedSender<Queued>,
+    limits: ExperimentLimits,
+    /// Set by the ingestion task once the experiment has the max events
+    capped: Arc<AtomicBool>,
+    budget: Arc<MemoryBudget>,
+}
+
+/// Entry of the ingestion queue
+#[derive(Debug)]
+enum Queued {
+    Record(Record),
+    /// Answered once every record queued before it is applied
+    Drained(oneshot::Sender<()>),
+}
+
+/// Message uuid to the uuid of its experiment
+type MessageIndex = parking_lot::RwLock<HashMap<Uuid, Uuid>>;
+
+/// Ingestion task applies that many queued records at most under a single lock
+const INGEST_BATCH: usize = 1024;
+
+impl ExperimentShard {
+    /// Spawns the ingestion task, which ends once every handle of the shard is dropped
+    pub fn new(state: ExperimentState, messages_state: MessagesState) -> Self {
+        let limits = state.experiment.limits.clone();
+        let capped = Arc::new(AtomicBool::new(state.is_capped()));
+        let budget = messages_state.budget();
+        let experiment_uuid = state.experiment.uuid;
+        let state = Arc::new(parking_lot::Mutex::new(state));
+        let (records, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(ingest_loop(
+            Arc::downgrade(&state),
+            receiver,
+            capped.clone(),
+            (experiment_uuid, messages_state),
+        ));
+
+        Self {
+            state,
+            records,
+            limits,
+            capped,
+            budget,
+        }
+    }
+
+    pub fn lock(&self) -> parking_lot::MutexGuard<'_, ExperimentState> {
+        self.state.lock()
+    }
+
+    /// Queues the record. Records are applied in the order they have been queued. Records
+    /// past the limit are dropped according to the limit policy
+    pub fn record(&self, record: Record) {
+        if self.limit_reached() && !self.keeps(&record) {
+            return;
+        }
+
+        // Ingestion task lives as long as the shard
+        let _ = self.records.send(Queued::Record(record));
+    }
+
+    /// Waits until the records queued so far are applied
+    pub async fn drained(&self) {
+        let (done, drained) = oneshot::channel();
+        if self.records.send(Queued::Drained(done)).is_ok() {
+            let _ = drained.await;
+        }
+    }
 
-    /// Experiment uuid to message mapping
-    pub messages: HashMap<Uuid, MessageMapping>,
+    /// Experiment has the max events or the memory budget is exceeded
+    pub fn limit_reached(&self) -> bool {
+        self.capped.load(Ordering::Relaxed) || self.budget.exceeded()
+    }
+
+    pub fn rejects_sends(&self) -> bool {
+        matches!(self.limits.policy, LimitPolicy::RejectSends) && self.limit_reached()
+    }
+
+    fn keeps(&self, record: &Record) -> bool {
+        let Some(message_uuid) = record.message_uuid() else {
+            return true;
+        };
+
+        match self.limits.policy {
+            LimitPolicy::RejectSends => true,
+            LimitPolicy::StopRecording => false,
+            // Same messages are sampled for every event, so their measurements stay complete
+            LimitPolicy::Sample { ratio } => {
+                (message_uuid.as_u64_pair().0 as f64) < ratio * u64::MAX as f64
+            }
+            // Budget is freed by the eviction
+            LimitPolicy::EvictFinished => !self.capped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+async fn ingest_loop(
+    state: std::sync::Weak<parking_lot::Mutex<ExperimentState>>,
+    mut records: mpsc::UnboundedReceiver<Queued>,
+    capped: Arc<AtomicBool>,
+    (experiment_uuid, messages_state): (Uuid, MessagesState),
+) {
+    let mut batch = Vec::with_capacity(INGEST_BATCH);
+    let mut recorded = Vec::with_capacity(INGEST_BATCH);
+    let mut drained = Vec::new();
 
-    /// Experiment uuid to message events map
-    pub events: HashMap<Uuid, Vec<MessageEvent>>,
+    while records.recv_many(&mut batch, INGEST_BATCH).await > 0 {
+        let Some(state) = state.upgrade() else {
+            return;
+        };
 
-    pub idx_experiment_to_messages: ExperimentToMessagesIdx,
-    pub idx_message_to_experiment: MessageToExperimentIdx,
+        let evicted = {
+            let mut state = state.lock();
+            for queued in batch.drain(..) {
+                match queued {
+                    Queued::Record(record) => {
+                        recorded.extend(record.message_uuid());
+                        state.apply(record);
+                    }
+                    Queued::Drained(done) => drained.push(done),
+                }
+            }
+            recorded.retain(|x| state.contains_message(x));
+
+            // Records queued before the cap has been noticed are still applied
+            capped.store(state.is_capped(), Ordering::Relaxed);
+            state.take_evicted()
+        };
+
+        messages_state.index(experiment_uuid, recorded.drain(..), evicted);
+
+        for done in drained.drain(..) {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Experiments by their uuid. The map is write locked only to add or remove the experiment
+#[derive(Debug, Clone, Default)]
+pub struct MessagesState {
+    experiments: Arc<parking_lot::RwLock<HashMap<Uuid, ExperimentShard>>>,
+    /// Experiments of the known messages, so the messages without the experiment header are
+    /// matched without locking every experiment
+    message_experiments: Arc<MessageIndex>,
+    budget: Arc<MemoryBudget>,
 }
 
 impl MessagesState {
-    pub fn insert_message(&mut self, message: Message, experiment_uuid: Uuid, async_mode: bool) {
-        if self.experiments.contains_key(&experiment_uuid) {
-            self.idx_message_to_experiment
-                .0
-                .insert(message.uuid, experiment_uuid);
+    pub fn new(budget: MemoryBudget) -> Self {
+        Self {
+            experiments: Default::default(),
+            message_experiments: Default::default(),
+            budget: Arc::new(budget),
+        }
+    }
+
+    pub fn budget(&self) -> Arc<MemoryBudget> {
+        self.budget.clone()
+    }
+
+    pub fn get(&self, experiment_uuid: &Uuid) -> Option<ExperimentShard> {
+        self.experiments.read().get(experiment_uuid).cloned()
+    }
+
+    pub fn contains(&self, experiment_uuid: &Uuid) -> bool {
+        self.experiments.read().contains_key(experiment_uuid)
+    }
 
-            self.idx_experiment_to_messages
-                .0
-                .entry(experiment_uuid)
-                .or_default()
-                .push(message.uuid);
+    pub fn uuids(&self) -> Vec<Uuid> {
+        self.experiments.read().keys().copied().collect()
+    }
+
+    pub fn shards(&self) -> Vec<(Uuid, ExperimentShard)> {
+        self.experiments
+            .read()
+            .iter()
+            .map(|(uuid, shard)| (*uuid, shard.clone()))
+            .collect()
+    }
+
+    pub fn insert(&self, state: ExperimentState) -> ExperimentShard {
+        let uuid = state.experiment.uuid;
+        let shard = ExperimentShard::new(state, self.clone());
+        self.insert_shard(uuid, shard.clone());
+        shard
+    }
 
-            self.messages
-                .entry(experiment_uuid)
-                .or_default()
-                .0
-                .insert(message.uuid, message);
+    /// Adds the shard, also the one removed from the state before
+    pub fn insert_shard(&self, experiment_uuid: Uuid, shard: ExperimentShard) {
+        let messages: Vec<_> = shard.lock().messages.uuids().copied().collect();
+        self.experiments.write().insert(experiment_uuid, shard);
+        self.index(experiment_uuid, messages.into_iter(), Vec::new());
+    }
+
+    pub fn remove(&self, experiment_uuid: &Uuid) -> Option<ExperimentShard> {
+        let shard = self.experiments.write().remove(experiment_uuid);
+        self.message_experiments
+            .write()
+            .retain(|_, uuid| uuid != experiment_uuid);
+        shard
+    }
+
+    pub fn clear(&self) {
+        self.experiments.write().clear();
+        self.message_experiments.write().clear();
+    }
 
-            self.events.entry(experiment_uuid).or_default();
-        } else {
-            if async_mode {
-                tracing::debug!("Unknown experiment: {experiment_uuid}");
-            } else {
-                tracing::warn!("Unknown experiment: {experiment_uuid}");
+    /// Experiment of the message, for the messages without the experiment header
+    pub fn message_experiment(&self, message_uuid: &Uuid) -> Option<Uuid> {
+        self.message_experiments.read().get(message_uuid).copied()
+    }
+
+    /// Indexes the messages recorded by the experiment and forgets the evicted ones. Messages
+    /// of the removed experiment are not indexed anymore
+    fn index(
+        &self,
+        experiment_uuid: Uuid,
+        recorded: impl ExactSizeIterator<Item = Uuid>,
+        evicted: Vec<Uuid>,
+    ) {
+        if recorded.len() == 0 && evicted.is_empty() {
+            return;
+        }
+
+        let mut index = self.message_experiments.write();
+        for message_uuid in evicted {
+            if index.get(&message_uuid) == Some(&experiment_uuid) {
+                index.remove(&message_uuid);
             }
         }
+
+        // Checked under the index lock, so the removal does not miss the messages
+        if self.contains(&experiment_uuid) {
+            index.extend(recorded.map(|message_uuid| (message_uuid, experiment_uuid)));
+        }
     }
 }
 
 impl Default for State {
     fn default() -> Self {
-        Self::new()
+        Self::new(DEFAULT_PRODUCER_IDLE_TIMEOUT, MemoryBudget::default())
     }
 }
 
+/// Producers unused for that long are removed from the pool
+pub const DEFAULT_PRODUCER_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
 impl State {
-    pub fn new() -> Self {
-        let messages_state = Arc::new(Mutex::new(Default::default()));
+    pub fn new(producer_idle_timeout: std::time::Duration, budget: MemoryBudget) -> Self {
+        let messages_state = MessagesState::new(budget);
         Self {
             consumers: Consumers::new(messages_state.clone()),
+            producers: Producers::new(producer_idle_timeout),
             messages_state,
+            jobs_state: Default::default(),
+            persistence: None,
+        }
+    }
+
+    /// Replaces the state with the persisted one and starts listeners of the running
+    /// experiments. Listeners continue from the committed offsets of their consumer groups
+    pub async fn resume(&mut self, persisted: Vec<ExperimentState>) -> &mut Self {
+        let running: Vec<_> = persisted
+            .iter()
+            .map(|x| &x.experiment)
+            .filter(|x| x.lifecycle == ExperimentLifecycle::Running)
+            .map(|x| (x.uuid, x.consumers.clone(), x.headers.clone()))
+            .collect();
+
+        self.messages_state.clear();
+        for experiment in persisted {
+            self.messages_state.insert(experiment);
+        }
+
+        for (uuid, consumers, headers) in running {
+            info!("Resuming experiment with uuid {}", uuid);
+
+            for cfg in consumers {
+                match self.consumers.attachment(&cfg, None, &headers) {
+                    Ok(listener) => {
+                        self.consumers.start(uuid, listener).await;
+                    }
+                    Err(e) => tracing::warn!(
+                        "Could not resume listener for {} of experiment {}: {}",
+                        cfg.topic,
+                        uuid,
+                        e
+                    ),
+                }
+            }
         }
+
+        self
     }
 
     pub async fn new_experiment(
         &mut self,
         uuid: Uuid,
         consumers: Vec<KafkaBrokerCfg>,
-    ) -> &mut Self {
+        headers: CorrelationHeaders,
+        routes: Vec<ExperimentRoute>,
+        storage: StorageMode,
+        limits: ExperimentLimits,
+    ) -> Result<&mut Self, ExperimentError> {
         info!("Starting new experiment with uuid {}", uuid);
 
+        // Shard of the existing experiment would be replaced along with its data
+        if self.messages_state.contains(&uuid) {
+            return Err(ExperimentError::ExperimentAlreadyExists);
+        }
+
+        limits.validate().map_err(ExperimentError::InvalidLimits)?;
+
+        for (idx, route) in routes.iter().enumerate() {
+            if routes[..idx].iter().any(|x| x.name == route.name) {
+                return Err(ExperimentError::RouteAlreadyExists);
+            }
+        }
+
+        let listeners = consumers
+            .iter()
+            .map(|cfg| self.consumers.attachment(cfg, None, &headers))
+            .collect::<Result<Vec<_>, _>>()?;
+
         {
-            self.messages_state
-                .lock()
-                .await
-                .experiments
-                .insert(uuid, Experiment::new(uuid, consumers.clone()));
+            let mut experiment = Experiment::new(uuid, consumers, headers);
+            experiment.routes = routes;
+            experiment.storage = storage;
+            experiment.limits = limits;
+
+            self.messages_state.insert(ExperimentState::new(experiment));
         }
 
-        for consumer in consumers {
-            self.consumers.start(uuid, consumer, false).await;
+        for listener in listeners {
+            self.consumers.start(uuid, listener).await;
         }
 
-        self
+        Ok(self)
     }
 
+    /// Starts the listeners consuming experiment messages again. Data of the experiment known
+    /// to the emitter is kept and the stopped experiment is running again. Archived experiment
+    /// is loaded back from the disk first
     pub async fn restore_experiment(
         &mut self,
         uuid: Uuid,
         consumers: Vec<KafkaBrokerCfg>,
-    ) -> &mut Self {
-        info!("Starting new experiment with uuid {}", uuid);
+        from: RestoreFrom,
+        ephemeral_group: bool,
+    ) -> Result<&mut Self, ExperimentError> {
+        info!("Restoring experiment with uuid {}", uuid);
 
-        {
-            self.messages_state
-                .lock()
-                .await
-                .experiments
-                .insert(uuid, Experiment::new(uuid, consumers.clone()));
+        // Archived experiment is read back, so its data is kept rather than overwritten
+        let archived = match (&self.persistence, self.messages_state.get(&uuid)) {
+            (Some(persistence), None) => persistence.read_archived(uuid).await?,
+            _ => None,
+        };
+
+        let (experiment_start, headers) = match &archived {
+            Some(state) => (
+                Some(state.experiment.experiment_start_timestamp_millis),
+                state.experiment.headers.clone(),
+            ),
+            None => self
+                .messages_state
+                .get(&uuid)
+                .map(|x| {
+                    let experiment = &x.lock().experiment;
+                    (
+                        Some(experiment.experiment_start_timestamp_millis),
+                        experiment.headers.clone(),
+                    )
+                })
+                .unwrap_or_default(),
+        };
+
+        let timestamp = match from {
+            RestoreFrom::ExperimentStart => {
+                Some(experiment_start.ok_or(ExperimentError::UnknownExperimentStart)? as i64)
+            }
+            RestoreFrom::Timestamp { timestamp_millis } => Some(timestamp_millis),
+            RestoreFrom::Earliest | RestoreFrom::Offsets { .. } => None,
+        };
+
+        let mut listeners = Vec::new();
+        for cfg in &consumers {
+            let position = match (&from, timestamp) {
+                (_, Some(timestamp_millis)) => {
+                    RestorePosition::timestamp(cfg, timestamp_millis).await?
+                }
+                (RestoreFrom::Offsets { offsets }, None) => RestorePosition::offsets(offsets),
+                _ => RestorePosition::Earliest,
+            };
+
+            let restore = ListenerRestore {
+                experiment_uuid: uuid,
+                position,
+                ephemeral_group,
+            };
+            listeners.push(self.consumers.attachment(cfg, Some(&restore), &headers)?);
         }
 
-        for consumer in consumers {
-            self.consumers.start(uuid, consumer, true).await;
+        // Listeners of the running experiment would consume the same messages twice
+        self.consumers.stop(&uuid);
+
+        if let (Some(persistence), Some(state)) = (&self.persistence, archived) {
+            persistence.track(&state).await;
+            self.messages_state.insert(state);
         }
 
-        self
+        match self.messages_state.get(&uuid) {
+            Some(shard) => {
+                let experiment = &mut shard.lock().experiment;
+                experiment.consumers = consumers;
+                experiment.lifecycle = ExperimentLifecycle::Running;
+                experiment.experiment_end_timestamp_millis = None;
+            }
+            None => {
+                self.messages_state
+                    .insert(ExperimentState::new(Experiment::new(
+                        uuid, consumers, headers,
+                    )));
+            }
+        }
+
+        for listener in listeners {
+            self.consumers.start(uuid, listener).await;
+        }
+
+        Ok(self)
+    }
+
+    /// Attaches new listener to the running experiment. Recorded events are kept
+    pub async fn add_listener(
+        &mut self,
+        uuid: Uuid,
+        cfg: KafkaBrokerCfg,
+    ) -> Result<&mut Self, ExperimentError> {
+        info!("Adding listener for {} to experiment {}", cfg.topic, uuid);
+
+        let shard = self
+            .messages_state
+            .get(&uuid)
+            .ok_or(ExperimentError::ExperimentNotFound)?;
+
+        let headers = {
+            let experiment = &shard.lock().experiment;
+
+            if experiment.lifecycle != ExperimentLifecycle::Running {
+                return Err(ExperimentError::ExperimentStopped);
+            }
+
+            if experiment.consumers.iter().any(|x| {
+                x.brokers == cfg.brokers
+                    && x.topic == cfg.topic
+                    && x.consumer_group_id == cfg.consumer_group_id
+            }) {
+                return Err(ExperimentError::ListenerAlreadyExists);
+            }
+
+            experiment.headers.clone()
+        };
+
+        let listener = self.consumers.attachment(&cfg, None, &headers)?;
+
+        shard.lock().experiment.consumers.push(cfg);
+
+        self.consumers.start(uuid, listener).await;
+
+        Ok(self)
+    }
+
+    pub async fn add_route(
+        &mut self,
+        uuid: Uuid,
+        route: ExperimentRoute,
+    ) -> Result<&mut Self, ExperimentError> {
+        info!("Adding route {} to experiment {}", route.name, uuid);
+
+        {
+            let shard = self
+                .messages_state
+                .get(&uuid)
+                .ok_or(ExperimentError::ExperimentNotFound)?;
+            let experiment = &mut shard.lock().experiment;
+
+            if experiment.routes.iter().any(|x| x.name == route.name) {
+                return Err(ExperimentError::RouteAlreadyExists);
+            }
+
+            experiment.routes.push(route);
+        }
+
+        Ok(self)
     }
 
-    pub async fn end_experiment(&mut self, uuid: Uuid) -> &mut Self {
+    /// Stops and detaches the experiment listener. Events it has recorded are kept
+    pub async fn remove_listener(
+        &mut self,
+        uuid: Uuid,
+        brokers: &str,
+        topic: &str,
+        consumer_group_id: &str,
+    ) -> Result<ListenerStatus, ExperimentError> {
+        info!("Removing listener for {} from experiment {}", topic, uuid);
+
+        let shard = self
+            .messages_state
+            .get(&uuid)
+            .ok_or(ExperimentError::ExperimentNotFound)?;
+
+        let status = self
+            .consumers
+            .stop_listener(&uuid, brokers, topic, consumer_group_id)
+            .ok_or(ExperimentError::ListenerNotFound)?;
+
+        shard.lock().experiment.consumers.retain(|x| {
+            !(x.brokers == brokers && x.topic == topic && x.consumer_group_id == consumer_group_id)
+        });
+
+        Ok(status)
+    }
+
+    /// Stops the listeners and sets the end of the experiment. Running jobs stop scheduling
+    /// messages. Data is kept for the analysis
+    pub async fn stop_experiment(&mut self, uuid: Uuid) -> Result<Experiment, ExperimentError> {
         info!("Stopping experiment {}", uuid);
 
+        let shard = self
+            .messages_state
+            .get(&uuid)
+            .ok_or(ExperimentError::ExperimentNotFound)?;
+
         self.consumers.stop(&uuid);
 
-        {
-            let mut state = self.messages_state.lock().await;
-            state.experiments.remove(&uuid);
-            state.events.remove(&uuid);
-            if let Some(messages) = state.idx_experiment_to_messages.0.remove(&uuid) {
-                for message in messages {
-                    state.idx_message_to_experiment.0.remove(&message);
-                    state.messages.remove(&message);
-                }
+        let experiment = &mut shard.lock().experiment;
+        if experiment.lifecycle == ExperimentLifecycle::Running {
+            experiment.lifecycle = ExperimentLifecycle::Stopped;
+            experiment.experiment_end_timestamp_millis = Some(get_now_millis());
+        }
+
+        Ok(experiment.clone())
+    }
+
+    /// Stops the experiment and moves its data from the memory to the persistent storage
+    pub async fn archive_experiment(&mut self, uuid: Uuid) -> Result<Experiment, ExperimentError> {
+        let persistence = self
+            .persistence
+            .clone()
+            .ok_or(ExperimentError::PersistenceDisabled)?;
+
+        let experiment = {
+            self.stop_experiment(uuid).await?;
+            info!("Archiving experiment {}", uuid);
+
+            let shard = self
+                .messages_state
+                .get(&uuid)
+                .ok_or(ExperimentError::ExperimentNotFound)?;
+            let experiment = &mut shard.lock().experiment;
+            experiment.lifecycle = ExperimentLifecycle::Archived;
+            experiment.clone()
+        };
+
+        if let Err(e) = persistence.archive(uuid, &self.messages_state).await {
+            // Experiment stays in the memory, so it can be archived again
+            if let Some(shard) = self.messages_state.get(&uuid) {
+                shard.lock().experiment.lifecycle = ExperimentLifecycle::Stopped;
             }
+
+            return Err(e.into());
         }
 
-        self
+        Ok(experiment)
+    }
+
+    /// Stops the listeners and deletes the experiment with all its data, archived or not
+    pub async fn delete_experiment(&mut self, uuid: Uuid) -> Result<&mut Self, ExperimentError> {
+        info!("Deleting experiment {}", uuid);
+
+        self.consumers.stop(&uuid);
+
+        self.messages_state.remove(&uuid);
+
+        if let Some(persistence) = &self.persistence {
+            persistence.delete(uuid).await?;
+        }
+
+        Ok(self)
     }
 
     // pub async fn get_experiment_stats(&self, uuid: Uuid) -> Stats {
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(uuid: Uuid, bytes: u64) -> Message {
+        Message {
+            uuid,
+            bytes_size: bytesize::ByteSize(bytes).into(),
+        }
+    }
+
+    fn sizes(messages: &MessagesSnapshot) -> Vec<u128> {
+        messages.iter().map(|x| x.bytes_size.as_bytes()).collect()
+    }
+
+    fn event(message_uuid: Uuid) -> Record {
+        Record::Event(MessageEvent {
+            message_uuid,
+            timestamp_millis: 0,
+            brokers: "localhost:9092".into(),
+            topic: "topic".into(),
+            event_type: crate::models::EventType::Sent,
+        })
+    }
+
+    /// Experiment capped once it has a single event
+    async fn capped_experiment(state: &MessagesState, policy: LimitPolicy) -> ExperimentShard {
+        let mut experiment = Experiment::new(Uuid::new_v4(), Vec::new(), Default::default());
+        experiment.limits = ExperimentLimits {
+            max_events: Some(1),
+            policy,
+        };
+        let shard = state.insert(ExperimentState::new(experiment));
+
+        shard.record(event(Uuid::new_v4()));
+        shard.drained().await;
+        assert!(shard.limit_reached());
+        shard
+    }
+
+    fn route(name: &str) -> ExperimentRoute {
+        let broker = serde_json::json!({
+            "brokers": "localhost:9092",
+            "topic": "topic",
+            "consumer_group": "group",
+        });
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "source": broker,
+            "dest": broker,
+        }))
+        .unwrap()
+    }
+
+    async fn new_experiment(
+        state: &mut State,
+        uuid: Uuid,
+        routes: Vec<ExperimentRoute>,
+    ) -> Result<(), ExperimentError> {
+        state
+            .new_experiment(
+                uuid,
+                Vec::new(),
+                Default::default(),
+                routes,
+                Default::default(),
+                Default::default(),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn experiment_uuid_is_not_reused() {
+        let mut state = State::default();
+        let uuid = Uuid::new_v4();
+
+        new_experiment(&mut state, uuid, vec![route("first")])
+            .await
+            .unwrap();
+        let duplicate = new_experiment(&mut state, uuid, Vec::new()).await;
+
+        assert!(matches!(
+            duplicate,
+            Err(ExperimentError::ExperimentAlreadyExists)
+        ));
+        let shard = state.messages_state.get(&uuid).unwrap();
+        assert_eq!(shard.lock().experiment.routes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn route_names_are_unique() {
+        let mut state = State::default();
+        let uuid = Uuid::new_v4();
+
+        let duplicate = new_experiment(&mut state, uuid, vec![route("dc1"), route("dc1")]).await;
+        assert!(matches!(
+            duplicate,
+            Err(ExperimentError::RouteAlreadyExists)
+        ));
+
+        new_experiment(&mut state, uuid, vec![route("dc1")])
+            .await
+            .unwrap();
+        assert!(state.add_route(uuid, route("dc2")).await.is_ok());
+        assert!(matches!(
+            state.add_route(uuid, route("dc1")).await,
+            Err(ExperimentError::RouteAlreadyExists)
+        ));
+        assert!(matches!(
+            state.add_route(Uuid::new_v4(), route("dc1")).await,
+            Err(ExperimentError::ExperimentNotFound)
+        ));
+    }
+
+    #[test]
+    fn sent_message_replaces_the_receipt() {
+        let mut store = MessageStore::default();
+        let uuid = Uuid::new_v4();
+
+        store.insert(message(uuid, 1), false);
+        store.insert(message(uuid, 2), false);
+        let received = store.snapshot();
+        store.insert(message(uuid, 3), true);
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(sizes(&store.snapshot()), vec![3]);
+        // Snapshot taken before the replacement keeps its chunk
+        assert_eq!(sizes(&received), vec![1]);
+        assert_eq!(store.take_replaced(), vec![0]);
+        assert!(store.take_replaced().is_empty());
+    }
+
+    #[test]
+    fn messages_span_chunks() {
+        let mut store = MessageStore::default();
+        for idx in 0..MESSAGE_CHUNK_LEN as u64 + 2 {
+            store.insert(message(Uuid::from_u64_pair(0, idx), idx), false);
+        }
+        let messages = store.snapshot();
+
+        assert_eq!(messages.len(), MESSAGE_CHUNK_LEN + 2);
+        assert_eq!(
+            sizes(&messages)[MESSAGE_CHUNK_LEN - 1..],
+            [
+                MESSAGE_CHUNK_LEN as u128 - 1,
+                MESSAGE_CHUNK_LEN as u128,
+                MESSAGE_CHUNK_LEN as u128 + 1
+            ]
+        );
+        assert_eq!(messages.iter_from(MESSAGE_CHUNK_LEN + 1).count(), 1);
+        assert_eq!(
+            messages
+                .get(MESSAGE_CHUNK_LEN)
+                .map(|x| x.bytes_size.as_bytes()),
+            Some(MESSAGE_CHUNK_LEN as u128)
+        );
+        assert!(messages.get(MESSAGE_CHUNK_LEN + 2).is_none());
+    }
+
+    #[tokio::test]
+    async fn ingested_messages_are_indexed_until_the_experiment_is_removed() {
+        let state = MessagesState::default();
+        let experiment = Experiment::new(Uuid::new_v4(), Vec::new(), Default::default());
+        let experiment_uuid = experiment.uuid;
+        let shard = state.insert(ExperimentState::new(experiment));
+        let message_uuid = Uuid::new_v4();
+
+        shard.record(Record::Received(message(message_uuid, 1)));
+        shard.record(Record::Sent(message(message_uuid, 2)));
+        shard.drained().await;
+
+        assert_eq!(sizes(&shard.lock().messages.snapshot()), vec![2]);
+        assert_eq!(
+            state.message_experiment(&message_uuid),
+            Some(experiment_uuid)
+        );
+
+        state.remove(&experiment_uuid);
+        assert_eq!(state.message_experiment(&message_uuid), None);
+
+        // Shard removed by the archive failure is indexed again
+        state.insert_shard(experiment_uuid, shard);
+        assert_eq!(
+            state.message_experiment(&message_uuid),
+            Some(experiment_uuid)
+        );
+    }
+
+    #[tokio::test]
+    async fn sampled_experiment_keeps_every_event_of_the_sampled_messages() {
+        let state = MessagesState::default();
+        let shard = capped_experiment(&state, LimitPolicy::Sample { ratio: 0.5 }).await;
+        let sampled = Uuid::from_u64_pair(u64::MAX / 4, 0);
+        let dropped = Uuid::from_u64_pair(u64::MAX / 4 * 3, 0);
+
+        assert!(shard.keeps(&Record::Received(message(sampled, 1))));
+        assert!(shard.keeps(&event(sampled)));
+        assert!(!shard.keeps(&Record::Sent(message(dropped, 1))));
+        assert!(!shard.keeps(&event(dropped)));
+
+        shard.record(event(sampled));
+        shard.record(event(dropped));
+        shard.drained().await;
+        assert_eq!(shard.lock().events.len(), 2);
+        assert!(!shard.rejects_sends());
+    }
+
+    #[tokio::test]
+    async fn capped_experiment_follows_its_policy() {
+        let state = MessagesState::default();
+
+        let stopped = capped_experiment(&state, LimitPolicy::StopRecording).await;
+        stopped.record(event(Uuid::new_v4()));
+        stopped.drained().await;
+        assert_eq!(stopped.lock().events.len(), 1);
+        assert!(!stopped.rejects_sends());
+
+        let rejecting = capped_experiment(&state, LimitPolicy::RejectSends).await;
+        rejecting.record(event(Uuid::new_v4()));
+        rejecting.drained().await;
+        assert_eq!(rejecting.lock().events.len(), 2);
+        assert!(rejecting.rejects_sends());
+
+        let evicting = capped_experiment(&state, LimitPolicy::EvictFinished).await;
+        evicting.record(event(Uuid::new_v4()));
+        evicting.drained().await;
+        assert_eq!(evicting.lock().events.len(), 1);
+    }
+
+    #[test]
+    fn sample_ratio_is_validated() {
+        let limits = |ratio| ExperimentLimits {
+            max_events: None,
+            policy: LimitPolicy::Sample { ratio },
+        };
+
+        assert!(limits(0.1).validate().is_ok());
+        assert!(limits(1.5).validate().is_err());
+        assert!(limits(-0.1).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn stopped_experiment_keeps_its_data() {
+        let mut state = State::default();
+        let uuid = Uuid::new_v4();
+        new_experiment(&mut state, uuid, Vec::new()).await.unwrap();
+        let shard = state.messages_state.get(&uuid).unwrap();
+        shard.record(Record::Sent(message(Uuid::new_v4(), 1)));
+        shard.drained().await;
+
+        let stopped = state.stop_experiment(uuid).await.unwrap();
+        assert_eq!(stopped.lifecycle, ExperimentLifecycle::Stopped);
+        assert!(stopped.experiment_end_timestamp_millis.is_some());
+        assert_eq!(shard.lock().messages.len(), 1);
+
+        // End of the experiment is not moved by the repeated stop
+        let again = state.stop_experiment(uuid).await.unwrap();
+        assert_eq!(
+            again.experiment_end_timestamp_millis,
+            stopped.experiment_end_timestamp_millis
+        );
+
+        assert!(matches!(
+            state.archive_experiment(uuid).await,
+            Err(ExperimentError::PersistenceDisabled)
+        ));
+
+        state.delete_experiment(uuid).await.unwrap();
+        assert!(!state.messages_state.contains(&uuid));
+        assert!(matches!(
+            state.stop_experiment(uuid).await,
+            Err(ExperimentError::ExperimentNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn archived_experiment_leaves_the_memory() {
+        let directory = std::env::temp_dir().join(format!("kafka-http-emitter-{}", Uuid::new_v4()));
+        let persistence = Persistence::open(&directory, std::time::Duration::from_secs(1)).unwrap();
+        let mut state = State {
+            persistence: Some(persistence),
+            ..Default::default()
+        };
+        let uuid = Uuid::new_v4();
+        new_experiment(&mut state, uuid, Vec::new()).await.unwrap();
+
+        let archived = state.archive_experiment(uuid).await.unwrap();
+        let listed = state
+            .persistence
+            .as_ref()
+            .unwrap()
+            .archived()
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(archived.lifecycle, ExperimentLifecycle::Archived);
+        assert!(archived.experiment_end_timestamp_millis.is_some());
+        assert!(!state.messages_state.contains(&uuid));
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].uuid, uuid);
+    }
+}